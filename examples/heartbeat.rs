@@ -0,0 +1,97 @@
+//! A node driven by an injected event that carries data: a timer injects
+//! numbered [`Tick`]s, each of which is sent on to every peer as a
+//! `heartbeat`. A `read` answers with the last round heard from each peer,
+//! so a peer that went quiet stands out.
+//!
+//! Run it with `cargo run --example heartbeat`.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use gossip_glomers_rs::*;
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum HeartbeatPayload {
+        Heartbeat = "heartbeat" { round: u64 },
+        Read = "read",
+        ReadOk = "read_ok" { rounds: HashMap<String, u64> },
+    }
+}
+
+/// The node's [`Node::Injected`] type, numbered by the timer that sends it.
+struct Tick {
+    round: u64,
+}
+
+struct HeartbeatNode {
+    /// Per peer, the highest round it sent a heartbeat for.
+    rounds: HashMap<String, u64>,
+}
+
+impl Node<(), HeartbeatPayload> for HeartbeatNode {
+    type Injected = Tick;
+
+    fn from_init(
+        _state: (),
+        _init: Init,
+        inject: Sender<Event<HeartbeatPayload, Tick>>,
+    ) -> Result<Self> {
+        spawn_injector("heartbeat timer", 3, inject.clone(), move || {
+            for round in 1.. {
+                std::thread::sleep(TICK_INTERVAL);
+                if inject.send(Event::Injected(Tick { round })).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(HeartbeatNode {
+            rounds: HashMap::new(),
+        })
+    }
+
+    fn on_injected(&mut self, tick: Tick, output: &mut Outbox) -> Result<()> {
+        let peers: Vec<String> = output.peers().map(str::to_string).collect();
+        for peer in peers {
+            Message {
+                src: output.node_id().to_string(),
+                dest: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: HeartbeatPayload::Heartbeat { round: tick.round },
+                },
+            }
+            .send(output)
+            .with_context(|| format!("heartbeat to {peer}"))?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, input: Event<HeartbeatPayload, Tick>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match &input.body.payload {
+            HeartbeatPayload::Heartbeat { round } => {
+                let last = self.rounds.entry(input.src.clone()).or_default();
+                *last = (*last).max(*round);
+            }
+            HeartbeatPayload::Read => {
+                let rounds = self.rounds.clone();
+                output
+                    .reply(&input, HeartbeatPayload::ReadOk { rounds })
+                    .context("reply to read")?;
+            }
+            HeartbeatPayload::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    main_loop::<_, HeartbeatNode, _>(())
+}
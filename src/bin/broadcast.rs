@@ -0,0 +1,540 @@
+//! The `broadcast` workload: values broadcast to any node must eventually be
+//! readable from every node.
+//!
+//! New values are gossiped right away, and every node also gossips on a
+//! timer, so values whose gossip was lost reach their neighbors even if
+//! nothing new arrives.
+//!
+//! Usage: `broadcast [all|tree|random:<k>]` to pick the gossip strategy
+//! (defaults to `all`).
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use gossip_glomers_rs::gossip::{Compressible, GossipStrategy, Topology};
+use gossip_glomers_rs::*;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum BroadcastPayload {
+        Broadcast = "broadcast" { message: u64 },
+        BroadcastOk = "broadcast_ok",
+        Read = "read",
+        // Sorted, so that reads of the same set produce identical arrays.
+        ReadOk = "read_ok" { messages: BTreeSet<u64> },
+        Topology = "topology" { topology: Topology },
+        TopologyOk = "topology_ok",
+        Gossip = "gossip" { messages: Compressible<HashSet<u64>> },
+        GossipOk = "gossip_ok" { messages: Compressible<HashSet<u64>> },
+    }
+}
+
+struct GossipTick;
+
+/// Every value seen so far, shareable with threads other than the one
+/// stepping the node.
+///
+/// Readers share the lock, so concurrent reads do not wait on each other;
+/// only recording a new value takes it exclusively.
+#[derive(Clone, Default)]
+struct Values(Arc<RwLock<HashSet<u64>>>);
+
+impl Values {
+    /// Records `values` and returns the ones not seen before.
+    fn insert_new(&self, values: impl IntoIterator<Item = u64>) -> HashSet<u64> {
+        let mut seen = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        values
+            .into_iter()
+            .filter(|value| seen.insert(*value))
+            .collect()
+    }
+
+    /// The values not in `known`, or all of them without it.
+    fn missing_from(&self, known: Option<&HashSet<u64>>) -> HashSet<u64> {
+        let seen = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        match known {
+            Some(known) => seen.difference(known).copied().collect(),
+            None => seen.clone(),
+        }
+    }
+
+    fn sorted(&self) -> BTreeSet<u64> {
+        let seen = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        seen.iter().copied().collect()
+    }
+}
+
+struct BroadcastNode {
+    node: String,
+    strategy: GossipStrategy,
+    messages: Values,
+    topology: Topology,
+    /// The gossip targets, if the strategy always picks the same ones for a
+    /// topology. Computed on first use and reset when the topology changes.
+    cached_targets: Option<Vec<String>>,
+    /// Per neighbor, the values it is known to have: the ones it acked or
+    /// gossiped to us.
+    known: HashMap<String, HashSet<u64>>,
+    /// Delivers the next [`GossipTick`]; each tick schedules the one after.
+    timer: Timer<GossipTick>,
+}
+
+impl BroadcastNode {
+    /// Records `messages` and returns the ones this node had not seen yet.
+    fn learn(&self, messages: impl IntoIterator<Item = u64>) -> HashSet<u64> {
+        self.messages.insert_new(messages)
+    }
+
+    fn targets(&mut self) -> Vec<String> {
+        if !self.strategy.is_deterministic() {
+            return self.strategy.targets(&self.node, &self.topology);
+        }
+        self.cached_targets
+            .get_or_insert_with(|| self.strategy.targets(&self.node, &self.topology))
+            .clone()
+    }
+
+    /// Sends every gossip target the values it is not known to have yet.
+    ///
+    /// Values a previous gossip carried but that were never acked are sent
+    /// again, so lost gossip is repaired by the next one, at the latest on
+    /// the next tick.
+    fn gossip(&mut self, output: &mut Outbox) -> Result<()> {
+        for target in self.targets() {
+            let messages = self.messages.missing_from(self.known.get(&target));
+            if messages.is_empty() {
+                continue;
+            }
+            Message {
+                src: self.node.clone(),
+                dest: target,
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: BroadcastPayload::Gossip {
+                        messages: Compressible(messages),
+                    },
+                },
+            }
+            .send(output)
+            .context("gossip to neighbor")?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<GossipStrategy, BroadcastPayload> for BroadcastNode {
+    type Injected = GossipTick;
+
+    fn from_init(
+        strategy: GossipStrategy,
+        init: Init,
+        inject: Sender<Event<BroadcastPayload, GossipTick>>,
+    ) -> Result<Self> {
+        let timer = Timer::new(inject);
+        timer.schedule_in(GOSSIP_INTERVAL, GossipTick);
+
+        Ok(BroadcastNode {
+            node: init.node_id,
+            strategy,
+            messages: Values::default(),
+            topology: Topology::default(),
+            cached_targets: None,
+            known: HashMap::new(),
+            timer,
+        })
+    }
+
+    // Every tick gossips everything still unacked, so one makes up for many.
+    fn coalesce_injected(&self) -> bool {
+        true
+    }
+
+    fn on_injected(&mut self, tick: GossipTick, output: &mut Outbox) -> Result<()> {
+        self.timer.schedule_in(GOSSIP_INTERVAL, tick);
+        self.gossip(output)
+    }
+
+    fn step(
+        &mut self,
+        input: Event<BroadcastPayload, GossipTick>,
+        output: &mut Outbox,
+    ) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match input.body.payload {
+            BroadcastPayload::Gossip {
+                messages: Compressible(messages),
+            } => {
+                // Gossip is not a request, so it is acked with a gossip of
+                // its own rather than a reply.
+                let new = self.learn(messages.iter().copied());
+                Message {
+                    src: self.node.clone(),
+                    dest: input.src.clone(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        payload: BroadcastPayload::GossipOk {
+                            messages: Compressible(messages.clone()),
+                        },
+                    },
+                }
+                .send(output)
+                .context("ack gossip")?;
+                self.known.entry(input.src).or_default().extend(messages);
+                if !new.is_empty() {
+                    self.gossip(output)?;
+                }
+                return Ok(());
+            }
+            BroadcastPayload::GossipOk {
+                messages: Compressible(messages),
+            } => {
+                self.known.entry(input.src).or_default().extend(messages);
+                return Ok(());
+            }
+            _ => {}
+        }
+        match &input.body.payload {
+            &BroadcastPayload::Broadcast { message } => {
+                // Maelstrom re-sends a broadcast whose ack it did not get in
+                // time, so every copy is acked but a value is only gossiped
+                // the first time it is seen.
+                output
+                    .reply(&input, BroadcastPayload::BroadcastOk)
+                    .context("reply to broadcast")?;
+                if !self.learn([message]).is_empty() {
+                    self.gossip(output)?;
+                }
+            }
+            BroadcastPayload::Read => {
+                let messages = self.messages.sorted();
+                output
+                    .reply(&input, BroadcastPayload::ReadOk { messages })
+                    .context("reply to read")?;
+            }
+            BroadcastPayload::Topology { topology } => {
+                self.topology = topology.clone();
+                self.cached_targets = None;
+                output
+                    .reply(&input, BroadcastPayload::TopologyOk)
+                    .context("reply to topology")?;
+            }
+            BroadcastPayload::BroadcastOk
+            | BroadcastPayload::ReadOk { .. }
+            | BroadcastPayload::TopologyOk
+            | BroadcastPayload::Gossip { .. }
+            | BroadcastPayload::GossipOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let strategy = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => GossipStrategy::default(),
+    };
+    main_loop::<_, BroadcastNode, _>(strategy)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers_rs::json::{FromJson, ToJson};
+    use gossip_glomers_rs::test_support::{init_msg, request, Cluster, TestNode};
+
+    use super::*;
+
+    type Broadcaster = TestNode<GossipStrategy, BroadcastNode, BroadcastPayload>;
+
+    /// Sends `payload` from a client to `node`, returning the reply.
+    fn call(node: &mut Broadcaster, payload: BroadcastPayload, id: usize) -> BroadcastPayload {
+        node.step(Event::Message(request("c1", "n1", payload, id)))
+            .unwrap();
+        let output = node.take_output().unwrap();
+        let reply = output
+            .iter()
+            .find(|message| message.dest == "c1")
+            .expect("a reply");
+        BroadcastPayload::from_json(&reply.body.payload).unwrap()
+    }
+
+    fn read(node: &mut Broadcaster) -> Vec<u64> {
+        match call(node, BroadcastPayload::Read, 0) {
+            BroadcastPayload::ReadOk { messages } => messages.into_iter().collect(),
+            other => panic!("not a read_ok: {:?}", other.to_json()),
+        }
+    }
+
+    #[test]
+    fn reads_see_every_value_once() {
+        let mut node: Broadcaster =
+            TestNode::start(GossipStrategy::All, &init_msg("n1", &["n1"])).unwrap();
+        assert_eq!(read(&mut node), Vec::<u64>::new());
+        for (id, message) in [3, 1, 3, 2].into_iter().enumerate() {
+            let reply = call(&mut node, BroadcastPayload::Broadcast { message }, id + 1);
+            assert!(matches!(reply, BroadcastPayload::BroadcastOk));
+            assert!(read(&mut node).contains(&message));
+        }
+        let gossip = BroadcastPayload::Gossip {
+            messages: Compressible(HashSet::from([2, 4])),
+        };
+        node.step(Event::Message(request("n2", "n1", gossip, 9)))
+            .unwrap();
+        node.take_output().unwrap();
+        assert_eq!(read(&mut node), [1, 2, 3, 4]);
+        assert_eq!(node.node().messages.sorted().len(), 4);
+    }
+
+    #[test]
+    fn values_can_be_read_while_broadcasts_are_handled() {
+        let mut node: Broadcaster =
+            TestNode::start(GossipStrategy::All, &init_msg("n1", &["n1"])).unwrap();
+        let values = node.node().messages.clone();
+        let broadcasts = 500;
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let values = values.clone();
+                std::thread::spawn(move || {
+                    let mut last = BTreeSet::new();
+                    while last.len() < 2 * broadcasts {
+                        let now = values.sorted();
+                        assert!(now.is_superset(&last), "values were lost");
+                        last = now;
+                    }
+                })
+            })
+            .collect();
+        // Values recorded off the stepping thread too, each by two writers,
+        // so that every one must be reported new exactly once.
+        let writers: Vec<_> = (0..4u64)
+            .map(|writer| {
+                let values = values.clone();
+                std::thread::spawn(move || {
+                    (broadcasts as u64..2 * broadcasts as u64)
+                        .filter(|message| message % 2 == writer % 2)
+                        .map(|message| values.insert_new([message]).len())
+                        .sum::<usize>()
+                })
+            })
+            .collect();
+        for message in 0..broadcasts as u64 {
+            let reply = call(&mut node, BroadcastPayload::Broadcast { message }, 1);
+            assert!(matches!(reply, BroadcastPayload::BroadcastOk));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let recorded_by_writers: usize = writers.into_iter().map(|w| w.join().unwrap()).sum();
+        assert_eq!(recorded_by_writers, broadcasts);
+        assert_eq!(
+            read(&mut node),
+            (0..2 * broadcasts as u64).collect::<Vec<_>>()
+        );
+    }
+
+    /// n1 with neighbors n2 and n3, under the `All` strategy.
+    fn with_two_neighbors() -> Broadcaster {
+        let mut node: Broadcaster =
+            TestNode::start(GossipStrategy::All, &init_msg("n1", &["n1", "n2", "n3"])).unwrap();
+        let topology = Topology::from_map(HashMap::from([(
+            "n1".to_string(),
+            vec!["n2".to_string(), "n3".to_string()],
+        )]));
+        call(&mut node, BroadcastPayload::Topology { topology }, 1);
+        node
+    }
+
+    /// Steps `payload` from `src` and returns what the node sent, as
+    /// `(type, dest, values)` with the values of gossip and acks.
+    fn deliver(
+        node: &mut Broadcaster,
+        src: &str,
+        payload: BroadcastPayload,
+    ) -> Vec<(String, String, Vec<u64>)> {
+        node.step(Event::Message(request(src, "n1", payload, 50)))
+            .unwrap();
+        let mut sent: Vec<_> = node
+            .take_output()
+            .unwrap()
+            .into_iter()
+            .map(|message| {
+                let payload = BroadcastPayload::from_json(&message.body.payload).unwrap();
+                let values = match payload {
+                    BroadcastPayload::Gossip { messages }
+                    | BroadcastPayload::GossipOk { messages } => messages
+                        .0
+                        .into_iter()
+                        .collect::<BTreeSet<_>>()
+                        .into_iter()
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                (
+                    message.body.payload.kind().to_string(),
+                    message.dest,
+                    values,
+                )
+            })
+            .collect();
+        sent.sort();
+        sent
+    }
+
+    fn sent(kind: &str, dest: &str, values: &[u64]) -> (String, String, Vec<u64>) {
+        (kind.to_string(), dest.to_string(), values.to_vec())
+    }
+
+    fn gossip(values: &[u64]) -> BroadcastPayload {
+        BroadcastPayload::Gossip {
+            messages: Compressible(values.iter().copied().collect()),
+        }
+    }
+
+    #[test]
+    fn repeated_broadcasts_are_acked_but_gossiped_once() {
+        let mut node = with_two_neighbors();
+        let broadcast = || BroadcastPayload::Broadcast { message: 7 };
+        assert_eq!(
+            deliver(&mut node, "c1", broadcast()),
+            [
+                sent("broadcast_ok", "c1", &[]),
+                sent("gossip", "n2", &[7]),
+                sent("gossip", "n3", &[7]),
+            ]
+        );
+        assert_eq!(
+            deliver(&mut node, "c1", broadcast()),
+            [sent("broadcast_ok", "c1", &[])]
+        );
+    }
+
+    #[test]
+    fn redelivered_gossip_is_acked_but_not_forwarded_again() {
+        let mut node = with_two_neighbors();
+        assert_eq!(
+            deliver(&mut node, "n2", gossip(&[8])),
+            [sent("gossip", "n3", &[8]), sent("gossip_ok", "n2", &[8])]
+        );
+        assert_eq!(
+            deliver(&mut node, "n2", gossip(&[8])),
+            [sent("gossip_ok", "n2", &[8])]
+        );
+        assert_eq!(read(&mut node), [8]);
+    }
+
+    #[test]
+    fn acked_values_are_not_gossiped_to_that_neighbor_again() {
+        let mut node = with_two_neighbors();
+        for message in [1, 2] {
+            deliver(&mut node, "c1", BroadcastPayload::Broadcast { message });
+        }
+        let ack = BroadcastPayload::GossipOk {
+            messages: Compressible(HashSet::from([1, 2])),
+        };
+        assert!(deliver(&mut node, "n2", ack).is_empty());
+        // n3 never acked, so it gets everything again.
+        assert_eq!(
+            deliver(&mut node, "c1", BroadcastPayload::Broadcast { message: 3 }),
+            [
+                sent("broadcast_ok", "c1", &[]),
+                sent("gossip", "n2", &[3]),
+                sent("gossip", "n3", &[1, 2, 3]),
+            ]
+        );
+    }
+
+    type Broadcasters = Cluster<GossipStrategy, BroadcastNode, BroadcastPayload>;
+
+    const NODES: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+    /// A cluster of [`NODES`] gossiping with `strategy` over a full mesh.
+    fn mesh_cluster(strategy: GossipStrategy) -> Broadcasters {
+        let mut cluster = Cluster::start(&NODES, || strategy).unwrap();
+        let mesh = NODES
+            .iter()
+            .map(|node| {
+                let neighbors = NODES.iter().filter(|other| *other != node);
+                (
+                    node.to_string(),
+                    neighbors.map(|other| other.to_string()).collect(),
+                )
+            })
+            .collect();
+        let topology = Topology::from_map(mesh);
+        for (id, node) in NODES.iter().enumerate() {
+            let payload = BroadcastPayload::Topology {
+                topology: topology.clone(),
+            };
+            cluster.send(request("c1", node, payload.to_json(), id + 1));
+        }
+        cluster
+    }
+
+    #[test]
+    fn every_strategy_converges_within_a_bound() {
+        // In messages delivered, from a value's broadcast to every node
+        // having it, with the other values' gossip interleaved. The tree is
+        // a star around n1, so values broadcast elsewhere take a hop more.
+        for (strategy, bound) in [
+            (GossipStrategy::All, 30),
+            (GossipStrategy::SpanningTree, 45),
+            (GossipStrategy::RandomSubset(NODES.len() - 1), 30),
+        ] {
+            let mut cluster = mesh_cluster(strategy);
+            cluster.run_until_quiet().unwrap();
+            for (message, dest) in NODES.iter().enumerate() {
+                let payload = BroadcastPayload::Broadcast {
+                    message: message as u64,
+                };
+                cluster.send(request("c1", dest, payload.to_json(), 100 + message));
+            }
+            let convergence = cluster
+                .run_measuring_convergence(|node| node.messages.sorted().into_iter().collect())
+                .unwrap();
+            assert_eq!(convergence.per_value.len(), NODES.len(), "{strategy}");
+            let slowest = convergence.max().expect("every value reached every node");
+            assert!(slowest <= bound, "{strategy}: {convergence:?}");
+        }
+    }
+
+    #[test]
+    fn spanning_tree_gossip_sends_two_messages_per_edge() {
+        let nodes = NODES;
+        // A full mesh, of which the tree uses one edge per node but the root.
+        let mut cluster = mesh_cluster(GossipStrategy::SpanningTree);
+        let broadcasts = 10;
+        for message in 0..broadcasts {
+            let dest = nodes[message as usize % nodes.len()];
+            let payload = BroadcastPayload::Broadcast { message };
+            cluster.send(request(
+                "c1",
+                dest,
+                payload.to_json(),
+                100 + message as usize,
+            ));
+            // One at a time: gossip crossing in flight is resent.
+            cluster.run_until_quiet().unwrap();
+        }
+
+        for node in nodes {
+            let messages = cluster.node(node).unwrap().node().messages.sorted();
+            assert_eq!(messages.len(), broadcasts as usize, "{node}");
+        }
+        // Per broadcast its reply, and a gossip and its ack per tree edge;
+        // plus the topology replies.
+        let edges = nodes.len() - 1;
+        let bound = broadcasts as usize * (1 + 2 * edges) + nodes.len();
+        assert!(
+            cluster.messages_sent() <= bound,
+            "{} messages, expected at most {bound}",
+            cluster.messages_sent()
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! The `g-counter` workload: a grow-only counter shared by all nodes, kept
+//! in a single `seq-kv` key.
+//!
+//! An `add` reads the counter and installs the sum with a `cas`; if another
+//! node added in between, the `cas` fails and the add is retried with the
+//! fresh value. `seq-kv` may serve a read from a stale copy, so a `read`
+//! confirms the value it saw with a `cas` to itself before replying.
+//!
+//! Usage: `counter [consistent|available]` (defaults to `consistent`). In
+//! `available` mode, a `read` the KV does not answer in time is served from
+//! the last value this node saw, so reads keep succeeding under a partition
+//! at the cost of possibly being stale.
+
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+
+use gossip_glomers_rs::json::Value;
+use gossip_glomers_rs::kv::KvClient;
+use gossip_glomers_rs::*;
+
+/// The `seq-kv` key holding the counter.
+const COUNTER_KEY: &str = "counter";
+
+/// How many times an add or a read is attempted before giving up on contention.
+const ADD_ATTEMPTS: u32 = 20;
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum CounterPayload {
+        Add = "add" { delta: u64 },
+        AddOk = "add_ok",
+        Read = "read",
+        ReadOk = "read_ok" { value: u64 },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadMode {
+    /// Reads fail when the KV cannot be reached.
+    Consistent,
+    /// Reads the KV does not answer fall back to the last known value.
+    Available,
+}
+
+struct CounterNode {
+    kv: KvClient,
+    mode: ReadMode,
+    /// The highest value this node has read or written. The counter only
+    /// grows, so this is never ahead of the KV.
+    last_known: u64,
+}
+
+impl CounterNode {
+    fn read_counter(&mut self, output: &mut Outbox) -> Result<u64> {
+        let value = match self.kv.read(COUNTER_KEY, output)? {
+            Some(value) => value
+                .as_u64()
+                .with_context(|| format!("{COUNTER_KEY} is not a count: {value}"))?,
+            None => 0,
+        };
+        self.last_known = self.last_known.max(value);
+        Ok(value)
+    }
+
+    /// The current value, confirmed by a `cas` so that it is not stale.
+    fn read_fresh(&mut self, output: &mut Outbox) -> Result<u64> {
+        for _ in 0..ADD_ATTEMPTS {
+            let current = self.read_counter(output)?;
+            let value = Value::Int(current.into());
+            if self
+                .kv
+                .cas(COUNTER_KEY, value.clone(), value, true, output)?
+            {
+                return Ok(current);
+            }
+        }
+        bail!("could not read a settled value in {ADD_ATTEMPTS} attempts")
+    }
+
+    fn add(&mut self, delta: u64, output: &mut Outbox) -> Result<()> {
+        for _ in 0..ADD_ATTEMPTS {
+            let current = self.read_counter(output)?;
+            let from = Value::Int(current.into());
+            let to = Value::Int((current + delta).into());
+            if self.kv.cas(COUNTER_KEY, from, to, true, output)? {
+                self.last_known = self.last_known.max(current + delta);
+                return Ok(());
+            }
+        }
+        bail!("could not add {delta} in {ADD_ATTEMPTS} attempts")
+    }
+
+    /// The value to answer a `read` with.
+    fn read(&mut self, output: &mut Outbox) -> Result<u64> {
+        match self.read_fresh(output) {
+            Err(error)
+                if self.mode == ReadMode::Available
+                    && error.downcast_ref::<RecvTimeoutError>().is_some() =>
+            {
+                eprintln!(
+                    "warning: {error}; answering read with the last known, possibly stale, value {}",
+                    self.last_known
+                );
+                Ok(self.last_known)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Node<ReadMode, CounterPayload> for CounterNode {
+    type Injected = ();
+
+    fn from_init(
+        mode: ReadMode,
+        init: Init,
+        _inject: Sender<Event<CounterPayload>>,
+    ) -> Result<Self> {
+        Ok(CounterNode {
+            kv: KvClient::seq(init.node_id),
+            mode,
+            last_known: 0,
+        })
+    }
+
+    fn step(&mut self, input: Event<CounterPayload>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match input.body.payload {
+            CounterPayload::Add { delta } => match self.add(delta, output) {
+                Ok(()) => output.reply(&input, CounterPayload::AddOk),
+                Err(e) => output.reply_error(&input, error_code(&e), e.to_string()),
+            }
+            .context("reply to add")?,
+            CounterPayload::Read => match self.read(output) {
+                Ok(value) => output.reply(&input, CounterPayload::ReadOk { value }),
+                Err(e) => output.reply_error(&input, error_code(&e), e.to_string()),
+            }
+            .context("reply to read")?,
+            CounterPayload::AddOk | CounterPayload::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// The code to report a failed request with. When the KV did not answer, a
+/// `cas` may still have been applied, so the outcome is unknown.
+fn error_code(error: &Error) -> MaelstromError {
+    if error.downcast_ref::<RecvTimeoutError>().is_some() {
+        MaelstromError::Timeout
+    } else {
+        MaelstromError::TemporarilyUnavailable
+    }
+}
+
+fn main() -> Result<()> {
+    let mode = match std::env::args().nth(1).as_deref() {
+        None | Some("consistent") => ReadMode::Consistent,
+        Some("available") => ReadMode::Available,
+        Some(other) => bail!("unknown read mode {other:?}, expected consistent or available"),
+    };
+    main_loop::<_, CounterNode, _>(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers_rs::json::FromJson;
+    use gossip_glomers_rs::test_support::{init_msg, request, KvService, TestNode};
+
+    use super::*;
+
+    type Counter = TestNode<ReadMode, CounterNode, CounterPayload>;
+
+    fn counter(mode: ReadMode, seq_kv: &KvService) -> Counter {
+        let mut node = Counter::start(mode, &init_msg("n1", &["n1"])).unwrap();
+        node.serve(seq_kv.clone());
+        node
+    }
+
+    fn call(node: &mut Counter, payload: CounterPayload) -> Value {
+        node.step(Event::Message(request("c1", "n1", payload, 1)))
+            .unwrap();
+        let output = node.take_output().unwrap();
+        assert_eq!(output.len(), 1, "{output:?}");
+        output[0].body.payload.clone()
+    }
+
+    fn read(node: &mut Counter) -> u64 {
+        let reply = call(node, CounterPayload::Read);
+        match CounterPayload::from_json(&reply) {
+            Ok(CounterPayload::ReadOk { value }) => value,
+            _ => panic!("not a read_ok: {reply}"),
+        }
+    }
+
+    /// The types of the requests `seq_kv` received.
+    fn kinds(seq_kv: &KvService) -> Vec<String> {
+        seq_kv
+            .requests()
+            .iter()
+            .map(|request| request.body.payload.kind().to_string())
+            .collect()
+    }
+
+    /// `seq-kv` with the counter at 5, whose next read still sees 3.
+    fn lagging() -> KvService {
+        let seq_kv = KvService::new("seq-kv").stale_reads(1);
+        seq_kv.insert(COUNTER_KEY, Value::Int(3));
+        seq_kv.insert(COUNTER_KEY, Value::Int(5));
+        seq_kv
+    }
+
+    #[test]
+    fn a_stale_read_fails_the_cas_and_is_read_again() {
+        let seq_kv = lagging();
+        let mut node = counter(ReadMode::Consistent, &seq_kv);
+        assert_eq!(read(&mut node), 5);
+        assert_eq!(kinds(&seq_kv), ["read", "cas", "read", "cas"]);
+    }
+
+    #[test]
+    fn an_add_based_on_a_stale_read_is_retried() {
+        let seq_kv = lagging();
+        let mut node = counter(ReadMode::Consistent, &seq_kv);
+        let reply = call(&mut node, CounterPayload::Add { delta: 1 });
+        assert_eq!(reply.kind(), "add_ok");
+        assert_eq!(seq_kv.get(COUNTER_KEY), Some(Value::Int(6)));
+        assert_eq!(kinds(&seq_kv), ["read", "cas", "read", "cas"]);
+    }
+
+    #[test]
+    fn available_reads_fall_back_to_the_last_known_value() {
+        for mode in [ReadMode::Consistent, ReadMode::Available] {
+            let seq_kv = KvService::new("seq-kv");
+            let mut node = counter(mode, &seq_kv);
+            call(&mut node, CounterPayload::Add { delta: 4 });
+            seq_kv.partition();
+            let reply = call(&mut node, CounterPayload::Read);
+            match mode {
+                ReadMode::Available => assert!(
+                    matches!(
+                        CounterPayload::from_json(&reply),
+                        Ok(CounterPayload::ReadOk { value: 4 })
+                    ),
+                    "{reply}"
+                ),
+                ReadMode::Consistent => {
+                    let code = reply.get("code").and_then(Value::as_u64);
+                    assert_eq!(code, Some(MaelstromError::Timeout.code()), "{reply}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! The `echo` workload: every `echo` is answered with the same text.
+
+use std::sync::mpsc::Sender;
+
+use gossip_glomers_rs::*;
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum EchoPayload {
+        Echo = "echo" { echo: String },
+        EchoOk = "echo_ok" { echo: String },
+    }
+}
+
+struct EchoNode;
+
+impl Node<(), EchoPayload> for EchoNode {
+    type Injected = ();
+
+    fn from_init(_state: (), _init: Init, _inject: Sender<Event<EchoPayload>>) -> Result<Self> {
+        Ok(EchoNode)
+    }
+
+    fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match &input.body.payload {
+            // The text is taken from the request itself, so an empty echo
+            // comes back empty.
+            EchoPayload::Echo { echo } => {
+                let payload = EchoPayload::EchoOk { echo: echo.clone() };
+                output.reply(&input, payload).context("reply to echo")?;
+            }
+            EchoPayload::EchoOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    main_loop::<_, EchoNode, _>(())
+}
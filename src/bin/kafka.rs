@@ -0,0 +1,495 @@
+//! The `kafka` workload: append-only logs per key, with polling from an
+//! offset and committed offsets per key.
+//!
+//! Each message is a key of its own in `lin-kv`, named after its log and its
+//! offset. A send claims the next offset with a `cas` that only creates the
+//! key, so two nodes never assign the same offset to a key, and a `next-`
+//! key per log keeps the offset to claim next. Sends thus write one small
+//! value each instead of rewriting the whole log. Committed offsets live in
+//! `lin-kv` as well, so every node polls and lists the same logs and offsets
+//! whichever node they were sent to.
+//!
+//! Keys are spread over the nodes with [`Partitions`], and a send to a node
+//! that does not own its key is forwarded to the owner, whose reply is
+//! relayed back. So each log has a single writer, and appends to it do not
+//! race each other's `cas`.
+//!
+//! With the `debug` feature, `debug_offsets` reports the latest and the
+//! committed offset of every key.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use gossip_glomers_rs::json::Value;
+use gossip_glomers_rs::kv::KvClient;
+use gossip_glomers_rs::partition::Partitions;
+use gossip_glomers_rs::*;
+
+/// How many times a `cas` on an offset counter or a committed offset is
+/// retried when another node changed it in between, and how many taken
+/// offsets a send skips before it gives up.
+const CAS_ATTEMPTS: u32 = 10;
+
+/// How many messages of each key a poll returns at most.
+const POLL_MESSAGES: u64 = 10;
+
+/// How many partitions the keys are spread over.
+const PARTITIONS: usize = 64;
+
+/// How long a forwarded send waits for the owner's reply. The client has
+/// given up on it by then.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum KafkaPayload {
+        Send = "send" { key: String, msg: u64 },
+        SendOk = "send_ok" { offset: u64 },
+        Poll = "poll" { offsets: HashMap<String, u64> },
+        PollOk = "poll_ok" { msgs: HashMap<String, Vec<(u64, u64)>> },
+        CommitOffsets = "commit_offsets" { offsets: HashMap<String, u64> },
+        CommitOffsetsOk = "commit_offsets_ok",
+        ListCommittedOffsets = "list_committed_offsets" { keys: Vec<String> },
+        ListCommittedOffsetsOk = "list_committed_offsets_ok" { offsets: HashMap<String, u64> },
+        DebugOffsets = "debug_offsets",
+        DebugOffsetsOk = "debug_offsets_ok" {
+            latest: HashMap<String, u64>,
+            committed: HashMap<String, u64>,
+        },
+        Error = "error" { code: u64, text: String },
+    }
+}
+
+struct KafkaNode {
+    kv: KvClient,
+    partitions: Partitions,
+    /// Per `msg_id` of a send forwarded to its key's owner, when it was
+    /// forwarded and the client's request.
+    forwarded: HashMap<usize, (Instant, Message<KafkaPayload>)>,
+    /// Per key, the offset this node claims next, if it appended to it
+    /// before. It saves reading the key's `next-` counter.
+    next: HashMap<String, u64>,
+    /// Per key, the latest offset this node has seen, for `debug_offsets`.
+    latest: HashMap<String, u64>,
+    /// Per key, the committed offset this node has last seen.
+    committed: HashMap<String, u64>,
+}
+
+impl KafkaNode {
+    /// Hands `request`, a send of a key `owner` owns, to `owner`, to relay its
+    /// reply once that arrives.
+    fn forward(
+        &mut self,
+        request: Message<KafkaPayload>,
+        owner: String,
+        output: &mut Outbox,
+    ) -> Result<()> {
+        let now = Instant::now();
+        self.forwarded
+            .retain(|_, (sent, _)| now.duration_since(*sent) < FORWARD_TIMEOUT);
+        let id = output.next_msg_id();
+        Message {
+            src: output.node_id().to_string(),
+            dest: owner,
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload: request.body.payload.clone(),
+            },
+        }
+        .send(output)
+        .context("forward send")?;
+        self.forwarded.insert(id, (now, request));
+        Ok(())
+    }
+
+    /// Appends `msg` to the log of `key`, returning its offset.
+    fn append(&mut self, key: &str, msg: u64, output: &mut Outbox) -> Result<u64> {
+        let next_key = next_key(key);
+        let first = match self.next.get(key) {
+            Some(&offset) => offset,
+            None => self.counter(&next_key, output)?.unwrap_or(0),
+        };
+        for offset in (first..).take(CAS_ATTEMPTS as usize) {
+            // Messages are never null, so this only creates the key.
+            let to = Value::Int(msg.into());
+            if self
+                .kv
+                .cas(&offset_key(key, offset), Value::Null, to, true, output)?
+            {
+                self.next.insert(key.to_string(), offset + 1);
+                self.raise(&next_key, offset + 1, output)?;
+                self.saw_offset(key, offset);
+                return Ok(offset);
+            }
+        }
+        bail!("could not append to {key:?}: {CAS_ATTEMPTS} offsets were taken")
+    }
+
+    /// The messages of each key at and after its offset in `offsets`, up to
+    /// [`POLL_MESSAGES`] of them. Keys without messages there are left out.
+    fn poll(
+        &mut self,
+        offsets: &HashMap<String, u64>,
+        output: &mut Outbox,
+    ) -> Result<HashMap<String, Vec<(u64, u64)>>> {
+        let keys: Vec<String> = offsets
+            .iter()
+            .flat_map(|(key, &from)| {
+                (from..from.saturating_add(POLL_MESSAGES)).map(|offset| offset_key(key, offset))
+            })
+            .collect();
+        let mut values = self.kv.read_many(&keys, output)?;
+        let mut msgs = HashMap::new();
+        for (key, &from) in offsets {
+            let mut polled = Vec::new();
+            for offset in from..from.saturating_add(POLL_MESSAGES) {
+                let offset_key = offset_key(key, offset);
+                // Offsets are claimed in order, so there are none past a gap.
+                let Some(value) = values.remove(&offset_key).flatten() else {
+                    break;
+                };
+                let msg = value
+                    .as_u64()
+                    .with_context(|| format!("{offset_key} is not a message: {value}"))?;
+                polled.push((offset, msg));
+            }
+            if let Some(&(last, _)) = polled.last() {
+                self.saw_offset(key, last);
+                msgs.insert(key.clone(), polled);
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// Raises the committed offset of `key` to `offset`, leaving it alone if
+    /// it is already past it.
+    fn commit(&mut self, key: &str, offset: u64, output: &mut Outbox) -> Result<()> {
+        let committed = self.raise(&committed_key(key), offset, output)?;
+        self.saw_committed(key, committed);
+        Ok(())
+    }
+
+    /// Raises the offset stored at `kv_key` to `offset`, leaving it alone if
+    /// it is already past it, and returns the offset stored now.
+    fn raise(&self, kv_key: &str, offset: u64, output: &mut Outbox) -> Result<u64> {
+        for _ in 0..CAS_ATTEMPTS {
+            let current = self.counter(kv_key, output)?;
+            if let Some(current) = current.filter(|&current| current >= offset) {
+                return Ok(current);
+            }
+            let from = current.map_or(Value::Null, |current| Value::Int(current.into()));
+            if self
+                .kv
+                .cas(kv_key, from, Value::Int(offset.into()), true, output)?
+            {
+                return Ok(offset);
+            }
+        }
+        bail!("could not raise {kv_key:?} in {CAS_ATTEMPTS} attempts")
+    }
+
+    /// The offset stored at `kv_key`, or `None` if there is none yet.
+    fn counter(&self, kv_key: &str, output: &mut Outbox) -> Result<Option<u64>> {
+        let Some(value) = self.kv.read(kv_key, output)? else {
+            return Ok(None);
+        };
+        let offset = value
+            .as_u64()
+            .with_context(|| format!("{kv_key} is not an offset: {value}"))?;
+        Ok(Some(offset))
+    }
+
+    /// The committed offsets of `keys`. Keys that were never committed are
+    /// left out.
+    fn list_committed(
+        &mut self,
+        keys: &[String],
+        output: &mut Outbox,
+    ) -> Result<HashMap<String, u64>> {
+        let committed_keys: Vec<String> = keys.iter().map(|key| committed_key(key)).collect();
+        let mut values = self.kv.read_many(&committed_keys, output)?;
+        let mut offsets = HashMap::new();
+        for (key, committed_key) in keys.iter().zip(&committed_keys) {
+            let Some(value) = values.remove(committed_key).flatten() else {
+                continue;
+            };
+            let offset = value
+                .as_u64()
+                .with_context(|| format!("{committed_key} is not an offset: {value}"))?;
+            self.saw_committed(key, offset);
+            offsets.insert(key.clone(), offset);
+        }
+        Ok(offsets)
+    }
+
+    fn saw_offset(&mut self, key: &str, offset: u64) {
+        let latest = self.latest.entry(key.to_string()).or_default();
+        *latest = (*latest).max(offset);
+    }
+
+    fn saw_committed(&mut self, key: &str, offset: u64) {
+        let committed = self.committed.entry(key.to_string()).or_default();
+        *committed = (*committed).max(offset);
+    }
+
+    #[cfg(feature = "debug")]
+    fn debug_offsets(&self) -> Result<KafkaPayload> {
+        Ok(KafkaPayload::DebugOffsetsOk {
+            latest: self.latest.clone(),
+            committed: self.committed.clone(),
+        })
+    }
+
+    #[cfg(not(feature = "debug"))]
+    fn debug_offsets(&self) -> Result<KafkaPayload> {
+        Err(NoDebugOffsets.into())
+    }
+}
+
+impl Node<(), KafkaPayload> for KafkaNode {
+    type Injected = ();
+
+    fn from_init(_state: (), init: Init, _inject: Sender<Event<KafkaPayload>>) -> Result<Self> {
+        let partitions =
+            Partitions::new(&init.node_ids, PARTITIONS).context("init has no node_ids")?;
+        Ok(KafkaNode {
+            kv: KvClient::lin(init.node_id),
+            partitions,
+            forwarded: HashMap::new(),
+            next: HashMap::new(),
+            latest: HashMap::new(),
+            committed: HashMap::new(),
+        })
+    }
+
+    fn step(&mut self, input: Event<KafkaPayload>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        if let KafkaPayload::Send { key, .. } = &input.body.payload {
+            let owner = self.partitions.partition_owner(key);
+            if owner != output.node_id() {
+                let owner = owner.to_string();
+                return self.forward(input, owner, output);
+            }
+        }
+        let forwarded = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.forwarded.remove(&id));
+        if let Some((_, request)) = forwarded {
+            return output
+                .reply(&request, input.body.payload)
+                .context("relay reply to send");
+        }
+        let reply = match &input.body.payload {
+            KafkaPayload::Send { key, msg } => self
+                .append(key, *msg, output)
+                .map(|offset| KafkaPayload::SendOk { offset }),
+            KafkaPayload::Poll { offsets } => self
+                .poll(offsets, output)
+                .map(|msgs| KafkaPayload::PollOk { msgs }),
+            KafkaPayload::CommitOffsets { offsets } => offsets
+                .iter()
+                .try_for_each(|(key, &offset)| self.commit(key, offset, output))
+                .map(|()| KafkaPayload::CommitOffsetsOk),
+            KafkaPayload::ListCommittedOffsets { keys } => self
+                .list_committed(keys, output)
+                .map(|offsets| KafkaPayload::ListCommittedOffsetsOk { offsets }),
+            KafkaPayload::DebugOffsets => self.debug_offsets(),
+            KafkaPayload::SendOk { .. }
+            | KafkaPayload::PollOk { .. }
+            | KafkaPayload::CommitOffsetsOk
+            | KafkaPayload::ListCommittedOffsetsOk { .. }
+            | KafkaPayload::DebugOffsetsOk { .. }
+            | KafkaPayload::Error { .. } => return Ok(()),
+        };
+        let replied = match reply {
+            Ok(payload) => output.reply(&input, payload),
+            Err(e) => output.reply_error(&input, error_code(&e), e.to_string()),
+        };
+        replied.with_context(|| format!("reply to {}", input.body.payload.kind()))
+    }
+}
+
+/// Where the message at `offset` of `key`'s log is kept. Offsets have no
+/// `-`, so no two keys and offsets share one.
+fn offset_key(key: &str, offset: u64) -> String {
+    format!("log-{key}-{offset}")
+}
+
+/// Where the offset the next message of `key` is to get is kept.
+fn next_key(key: &str) -> String {
+    format!("next-{key}")
+}
+
+fn committed_key(key: &str) -> String {
+    format!("committed-{key}")
+}
+
+/// `debug_offsets` without the `debug` feature.
+#[derive(Debug)]
+struct NoDebugOffsets;
+
+impl std::fmt::Display for NoDebugOffsets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("debug_offsets needs the debug feature")
+    }
+}
+
+impl std::error::Error for NoDebugOffsets {}
+
+/// The error code to answer a failed request with. When `lin-kv` did not
+/// answer, a `cas` may still have been applied, so the outcome is unknown.
+fn error_code(error: &Error) -> MaelstromError {
+    if error.downcast_ref::<NoDebugOffsets>().is_some() {
+        MaelstromError::NotSupported
+    } else if error.downcast_ref::<RecvTimeoutError>().is_some() {
+        MaelstromError::Timeout
+    } else {
+        MaelstromError::TemporarilyUnavailable
+    }
+}
+
+fn main() -> Result<()> {
+    main_loop::<_, KafkaNode, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers_rs::json;
+    use gossip_glomers_rs::test_support::{init_msg, request, Cluster, KvService, TestNode};
+
+    use super::*;
+
+    /// A lone node, which owns every key, storing its logs in `lin_kv`.
+    fn node(lin_kv: &KvService) -> TestNode<(), KafkaNode, KafkaPayload> {
+        let mut node = TestNode::start((), &init_msg("n1", &["n1"])).unwrap();
+        node.serve(lin_kv.clone());
+        node
+    }
+
+    /// Sends `payload` from a client to `node`, returning the reply.
+    fn call(node: &mut TestNode<(), KafkaNode, KafkaPayload>, payload: KafkaPayload) -> Value {
+        node.step(Event::Message(request("c1", "n1", payload, 1)))
+            .unwrap();
+        let reply = node.take_output().unwrap().pop().expect("a reply");
+        reply.body.payload
+    }
+
+    fn send(node: &mut TestNode<(), KafkaNode, KafkaPayload>, key: &str, msg: u64) -> Value {
+        let payload = KafkaPayload::Send {
+            key: key.to_string(),
+            msg,
+        };
+        call(node, payload).get("offset").unwrap().clone()
+    }
+
+    #[test]
+    fn each_message_is_its_own_key() {
+        let lin_kv = KvService::new("lin-kv");
+        let mut node = node(&lin_kv);
+        assert_eq!(send(&mut node, "k", 7), Value::Int(0));
+        assert_eq!(send(&mut node, "k", 8), Value::Int(1));
+        assert_eq!(send(&mut node, "other", 9), Value::Int(0));
+        assert_eq!(lin_kv.get("log-k-0"), Some(Value::Int(7)));
+        assert_eq!(lin_kv.get("log-k-1"), Some(Value::Int(8)));
+        assert_eq!(lin_kv.get("log-other-0"), Some(Value::Int(9)));
+        assert_eq!(lin_kv.get("next-k"), Some(Value::Int(2)));
+    }
+
+    #[test]
+    fn nodes_sending_to_the_same_key_get_distinct_offsets() {
+        // Two nodes that each believe they own every key, as during a
+        // membership change, interleaving their appends to one log.
+        let lin_kv = KvService::new("lin-kv");
+        let mut nodes = ["n1", "n2"].map(|id| {
+            let mut node: TestNode<(), KafkaNode, KafkaPayload> =
+                TestNode::start((), &init_msg(id, &[id])).unwrap();
+            node.serve(lin_kv.clone());
+            (id, node)
+        });
+        let mut offsets = Vec::new();
+        for msg in 0..10u64 {
+            let (id, node) = &mut nodes[msg as usize % 2];
+            let payload = KafkaPayload::Send {
+                key: "k".to_string(),
+                msg,
+            };
+            node.step(Event::Message(request("c1", id, payload, 1)))
+                .unwrap();
+            let reply = node.take_output().unwrap().pop().expect("a reply");
+            offsets.push(
+                reply
+                    .body
+                    .payload
+                    .get("offset")
+                    .and_then(Value::as_u64)
+                    .unwrap(),
+            );
+        }
+        let mut sorted = offsets.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>(), "{offsets:?}");
+        for (msg, offset) in offsets.into_iter().enumerate() {
+            let stored = lin_kv.get(&format!("log-k-{offset}"));
+            assert_eq!(stored, Some(Value::Int(msg as i128)));
+        }
+    }
+
+    #[test]
+    fn sends_skip_offsets_taken_elsewhere() {
+        let lin_kv = KvService::new("lin-kv");
+        // Another node claimed offset 1 but has not raised the counter yet.
+        lin_kv.insert("log-k-0", Value::Int(7));
+        lin_kv.insert("log-k-1", Value::Int(8));
+        lin_kv.insert("next-k", Value::Int(1));
+        let mut node = node(&lin_kv);
+        assert_eq!(send(&mut node, "k", 9), Value::Int(2));
+        assert_eq!(lin_kv.get("next-k"), Some(Value::Int(3)));
+    }
+
+    #[test]
+    fn polls_read_from_the_offset_up_to_the_last_message() {
+        let lin_kv = KvService::new("lin-kv");
+        let mut node = node(&lin_kv);
+        for msg in 10..13 {
+            send(&mut node, "k", msg);
+        }
+        let offsets = HashMap::from([("k".to_string(), 1), ("none".to_string(), 0)]);
+        let reply = call(&mut node, KafkaPayload::Poll { offsets });
+        assert_eq!(
+            reply.get("msgs").unwrap().to_string(),
+            r#"{"k":[[1,11],[2,12]]}"#
+        );
+    }
+
+    #[test]
+    fn sends_are_appended_by_the_key_owner() {
+        let nodes = ["n1", "n2"].map(String::from);
+        let partitions = Partitions::new(&nodes, PARTITIONS).unwrap();
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| partitions.partition_owner(key) == "n2")
+            .unwrap();
+        let mut cluster =
+            Cluster::<(), KafkaNode, KafkaPayload>::start(&["n1", "n2"], || ()).unwrap();
+        let lin_kv = KvService::new("lin-kv");
+        cluster.serve(lin_kv.clone());
+
+        let send = json::parse(&format!(r#"{{"type":"send","key":"{key}","msg":7}}"#)).unwrap();
+        cluster.send(request("c1", "n1", send, 1));
+        cluster.run_until_quiet().unwrap();
+
+        let replies = cluster.take_outside();
+        assert_eq!(replies.len(), 1, "{replies:?}");
+        assert_eq!(replies[0].dest, "c1");
+        assert_eq!(replies[0].body.in_reply_to, Some(1));
+        assert_eq!(replies[0].body.payload.kind(), "send_ok");
+        let writers: Vec<_> = lin_kv.requests().into_iter().map(|r| r.src).collect();
+        assert!(!writers.is_empty());
+        assert!(writers.iter().all(|src| src == "n2"), "{writers:?}");
+    }
+}
@@ -0,0 +1,235 @@
+//! The `pn-counter` workload: a counter that accepts positive and negative
+//! deltas, replicated by periodically gossiping the CRDT state.
+//!
+//! Each peer is only sent the slots it has not acknowledged yet; gossip that
+//! is lost is simply sent again on the next tick.
+//!
+//! Usage: `pn_counter [<checkpoint file>]`. With a file, the counter is saved
+//! to it periodically and restored from it when the node restarts.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use gossip_glomers_rs::crdt::{GCounter, PnCounter};
+use gossip_glomers_rs::json::{self, ToJson, Value};
+use gossip_glomers_rs::*;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(300);
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum CounterPayload {
+        Add = "add" { delta: i64 },
+        AddOk = "add_ok",
+        Read = "read",
+        ReadOk = "read_ok" { value: i64 },
+        Gossip = "gossip" { increments: GCounter, decrements: GCounter },
+        GossipOk = "gossip_ok" { increments: GCounter, decrements: GCounter },
+    }
+}
+
+struct GossipTick;
+
+struct PnCounterNode {
+    counter: PnCounter,
+    /// Per peer, the part of the state it is known to have: what it acked or
+    /// gossiped to us.
+    known: HashMap<String, PnCounter>,
+}
+
+impl Node<(), CounterPayload> for PnCounterNode {
+    type Injected = GossipTick;
+
+    fn from_init(
+        _state: (),
+        _init: Init,
+        inject: Sender<Event<CounterPayload, GossipTick>>,
+    ) -> Result<Self> {
+        spawn_injector("gossip timer", 3, inject.clone(), move || loop {
+            std::thread::sleep(GOSSIP_INTERVAL);
+            if inject.send(Event::Injected(GossipTick)).is_err() {
+                break;
+            }
+        });
+
+        Ok(PnCounterNode {
+            counter: PnCounter::new(),
+            known: HashMap::new(),
+        })
+    }
+
+    // Every tick sends each peer all it is missing, so one makes up for many.
+    fn coalesce_injected(&self) -> bool {
+        true
+    }
+
+    fn on_injected(&mut self, _tick: GossipTick, output: &mut Outbox) -> Result<()> {
+        let peers: Vec<String> = output.peers().map(str::to_string).collect();
+        for peer in peers {
+            let delta = match self.known.get(&peer) {
+                Some(known) => self.counter.delta_since(known),
+                None => self.counter.clone(),
+            };
+            if delta.is_empty() {
+                continue;
+            }
+            Message {
+                src: output.node_id().to_string(),
+                dest: peer.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: CounterPayload::Gossip {
+                        increments: delta.increments,
+                        decrements: delta.decrements,
+                    },
+                },
+            }
+            .send(output)
+            .with_context(|| format!("gossip to {peer}"))?;
+        }
+        Ok(())
+    }
+
+    // Only the counter is saved: after a restart peers are gossiped the
+    // whole state once, which is safe since merging is idempotent.
+    fn snapshot(&self) -> Option<Value> {
+        let mut snapshot = json::Map::new();
+        snapshot.insert("increments".to_string(), self.counter.increments.to_json());
+        snapshot.insert("decrements".to_string(), self.counter.decrements.to_json());
+        Some(Value::Object(snapshot))
+    }
+
+    fn restore(&mut self, snapshot: Value) -> Result<()> {
+        let snapshot = snapshot.as_object().context("snapshot is not an object")?;
+        self.counter.merge(&PnCounter {
+            increments: json::field(snapshot, "increments")?,
+            decrements: json::field(snapshot, "decrements")?,
+        });
+        Ok(())
+    }
+
+    fn step(
+        &mut self,
+        input: Event<CounterPayload, GossipTick>,
+        output: &mut Outbox,
+    ) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match input.body.payload {
+            CounterPayload::Gossip {
+                increments,
+                decrements,
+            } => {
+                let delta = PnCounter {
+                    increments,
+                    decrements,
+                };
+                self.counter.merge(&delta);
+                Message {
+                    src: output.node_id().to_string(),
+                    dest: input.src.clone(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        payload: CounterPayload::GossipOk {
+                            increments: delta.increments.clone(),
+                            decrements: delta.decrements.clone(),
+                        },
+                    },
+                }
+                .send(output)
+                .context("ack gossip")?;
+                self.known.entry(input.src).or_default().merge(&delta);
+                return Ok(());
+            }
+            CounterPayload::GossipOk {
+                increments,
+                decrements,
+            } => {
+                self.known.entry(input.src).or_default().merge(&PnCounter {
+                    increments,
+                    decrements,
+                });
+                return Ok(());
+            }
+            _ => {}
+        }
+        match input.body.payload {
+            CounterPayload::Add { delta } => {
+                self.counter.add(output.node_id(), delta);
+                output
+                    .reply(&input, CounterPayload::AddOk)
+                    .context("reply to add")?;
+            }
+            CounterPayload::Read => {
+                let value = self.counter.value();
+                output
+                    .reply(&input, CounterPayload::ReadOk { value })
+                    .context("reply to read")?;
+            }
+            CounterPayload::AddOk
+            | CounterPayload::ReadOk { .. }
+            | CounterPayload::Gossip { .. }
+            | CounterPayload::GossipOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let mut config = Config::new();
+    if let Some(path) = std::env::args().nth(1) {
+        config = config.checkpoint(path, CHECKPOINT_INTERVAL);
+    }
+    main_loop_with_config::<_, PnCounterNode, _>(config, ())
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers_rs::json::FromJson;
+    use gossip_glomers_rs::test_support::{request, Cluster};
+
+    use super::*;
+
+    #[test]
+    fn nodes_converge_on_the_net_total_of_their_deltas() {
+        let nodes = ["n1", "n2"];
+        let mut cluster =
+            Cluster::<_, PnCounterNode, CounterPayload>::start(&nodes, || ()).unwrap();
+        let adds = [("n1", 5), ("n1", -3), ("n2", -4), ("n2", -1)];
+        for (id, (node, delta)) in adds.into_iter().enumerate() {
+            let add = CounterPayload::Add { delta };
+            cluster.send(request("c1", node, add.to_json(), id + 1));
+        }
+        cluster.run_until_quiet().unwrap();
+        assert_eq!(cluster.node("n1").unwrap().node().counter.value(), 2);
+        assert_eq!(cluster.node("n2").unwrap().node().counter.value(), -5);
+
+        for node in nodes {
+            let inject = cluster.node(node).unwrap().inject();
+            inject.send(Event::Injected(GossipTick)).unwrap();
+        }
+        cluster.run_until_quiet().unwrap();
+        cluster.take_outside();
+        for (id, node) in nodes.into_iter().enumerate() {
+            cluster.send(request("c1", node, CounterPayload::Read.to_json(), 10 + id));
+        }
+        cluster.run_until_quiet().unwrap();
+        let reads: Vec<_> = cluster
+            .take_outside()
+            .into_iter()
+            .map(
+                |reply| match CounterPayload::from_json(&reply.body.payload).unwrap() {
+                    CounterPayload::ReadOk { value } => (reply.src, value),
+                    other => panic!("not a read_ok: {:?}", other.to_json()),
+                },
+            )
+            .collect();
+        assert_eq!(reads, [("n1".to_string(), -3), ("n2".to_string(), -3)]);
+    }
+}
@@ -0,0 +1,175 @@
+//! The `txn-rw-register` workload.
+//!
+//! Usage: `txn [memory|lin-kv]` to pick where the registers live (defaults
+//! to `memory`).
+//!
+//! With `memory`, they live in the node's memory, and each transaction runs
+//! to completion before the next one starts, so it is trivially atomic, but
+//! only a single node sees them. With `lin-kv`, they live in `lin-kv`
+//! through a [`TxnStore`], so every node sees the same registers, and a
+//! transaction that keeps conflicting with other nodes' fails with
+//! `txn-conflict`.
+//!
+//! Either way, reads see the writes made earlier in the same transaction.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+
+use gossip_glomers_rs::kv::KvClient;
+use gossip_glomers_rs::txn::{Op, TxnConflict, TxnStore};
+use gossip_glomers_rs::*;
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum TxnPayload {
+        Txn = "txn" { txn: Vec<Op> },
+        TxnOk = "txn_ok" { txn: Vec<Op> },
+    }
+}
+
+/// Where the registers live, see the module docs.
+#[derive(Debug, Clone, Copy, Default)]
+enum Mode {
+    #[default]
+    Memory,
+    LinKv,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(Mode::Memory),
+            "lin-kv" => Ok(Mode::LinKv),
+            _ => bail!("unknown mode {s:?}, expected memory or lin-kv"),
+        }
+    }
+}
+
+enum Registers {
+    Memory(HashMap<u64, u64>),
+    LinKv(TxnStore),
+}
+
+struct TxnNode {
+    registers: Registers,
+}
+
+impl TxnNode {
+    /// Applies `txn` and returns it with the values of its reads filled in.
+    fn apply(&mut self, txn: &[Op], output: &mut Outbox) -> Result<Vec<Op>> {
+        let registers = match &mut self.registers {
+            Registers::Memory(registers) => registers,
+            Registers::LinKv(store) => return store.apply(txn, output),
+        };
+        let txn = txn
+            .iter()
+            .map(|op| match *op {
+                Op::Read { key, .. } => Op::Read {
+                    key,
+                    value: registers.get(&key).copied(),
+                },
+                Op::Write { key, value } => {
+                    registers.insert(key, value);
+                    Op::Write { key, value }
+                }
+            })
+            .collect();
+        Ok(txn)
+    }
+}
+
+impl Node<Mode, TxnPayload> for TxnNode {
+    type Injected = ();
+
+    fn from_init(mode: Mode, init: Init, _inject: Sender<Event<TxnPayload>>) -> Result<Self> {
+        let registers = match mode {
+            Mode::Memory => Registers::Memory(HashMap::new()),
+            Mode::LinKv => Registers::LinKv(TxnStore::new(KvClient::lin(init.node_id))),
+        };
+        Ok(TxnNode { registers })
+    }
+
+    fn step(&mut self, input: Event<TxnPayload>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match &input.body.payload {
+            TxnPayload::Txn { txn } => match self.apply(txn, output) {
+                Ok(txn) => output.reply(&input, TxnPayload::TxnOk { txn }),
+                Err(e) => output.reply_error(&input, error_code(&e), e.to_string()),
+            }
+            .context("reply to txn")?,
+            TxnPayload::TxnOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// The error code to answer a failed transaction with. When `lin-kv` did
+/// not answer, its `cas` may still have been applied, so the outcome is
+/// unknown.
+fn error_code(error: &Error) -> MaelstromError {
+    if let Some(conflict) = error.downcast_ref::<TxnConflict>() {
+        conflict.code()
+    } else if error.downcast_ref::<RecvTimeoutError>().is_some() {
+        MaelstromError::Timeout
+    } else {
+        MaelstromError::TemporarilyUnavailable
+    }
+}
+
+fn main() -> Result<()> {
+    let mode = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => Mode::default(),
+    };
+    main_loop::<_, TxnNode, _>(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers_rs::json::{self, FromJson, Value};
+    use gossip_glomers_rs::test_support::{init_msg, request, KvService, TestNode};
+
+    use super::*;
+
+    /// Sends `txn` from a client to `node`, returning the operations of the
+    /// `txn_ok` it answers with.
+    fn run(node: &mut TestNode<Mode, TxnNode, TxnPayload>, txn: &str) -> Value {
+        let txn = Vec::<Op>::from_json(&json::parse(txn).unwrap()).unwrap();
+        let txn = request("c1", "n1", TxnPayload::Txn { txn }, 1);
+        node.step(Event::Message(txn)).unwrap();
+        let reply = node.take_output().unwrap().pop().expect("a reply");
+        assert_eq!(reply.body.payload.kind(), "txn_ok", "{reply:?}");
+        reply.body.payload.get("txn").unwrap().clone()
+    }
+
+    #[test]
+    fn reads_see_earlier_writes_in_memory() {
+        let mut node = TestNode::start(Mode::Memory, &init_msg("n1", &["n1"])).unwrap();
+        let txn = run(&mut node, r#"[["r",1,null],["w",1,6],["r",1,null]]"#);
+        assert_eq!(txn.to_string(), r#"[["r",1,null],["w",1,6],["r",1,6]]"#);
+    }
+
+    #[test]
+    fn nodes_share_the_registers_in_lin_kv() {
+        let lin_kv = KvService::new("lin-kv");
+        let mut nodes = ["n1", "n2"].map(|id| {
+            let mut node = TestNode::start(Mode::LinKv, &init_msg(id, &["n1", "n2"])).unwrap();
+            node.serve(lin_kv.clone());
+            node
+        });
+        run(&mut nodes[0], r#"[["w",1,6],["w",2,7]]"#);
+        let txn = run(&mut nodes[1], r#"[["r",1,null],["r",2,null]]"#);
+        assert_eq!(txn.to_string(), r#"[["r",1,6],["r",2,7]]"#);
+    }
+
+    #[test]
+    fn conflicts_are_answered_with_txn_conflict() {
+        let conflict = TxnConflict { attempts: 5 }.into();
+        assert_eq!(error_code(&conflict), MaelstromError::TxnConflict);
+    }
+}
@@ -0,0 +1,88 @@
+//! The `unique-ids` workload: every `generate` is answered with an ID that no
+//! other request, on any node, gets.
+//!
+//! All nodes on a machine share the generator state in [`STATE_FILE`], which
+//! is what keeps their IDs apart. A `seed` in the init message starts the
+//! clock sequence of a fresh state file; once the file holds a state, the
+//! seed is ignored.
+
+use std::sync::mpsc::Sender;
+
+use gossip_glomers_rs::uuid::{UUIDGenerator, MAX_SEQUENCE};
+use gossip_glomers_rs::*;
+
+const STATE_FILE: &str = "state.db";
+
+/// How many IDs to reserve at init, so that the first generates do not wait
+/// on the state file.
+const WARMUP: usize = 64;
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum UniqueIdsPayload {
+        Generate = "generate",
+        GenerateOk = "generate_ok" { id: String },
+    }
+}
+
+struct UniqueIdsNode {
+    generator: UUIDGenerator,
+}
+
+impl Node<(), UniqueIdsPayload> for UniqueIdsNode {
+    type Injected = ();
+
+    fn from_init(_state: (), init: Init, _inject: Sender<Event<UniqueIdsPayload>>) -> Result<Self> {
+        let mut generator = UUIDGenerator::new(STATE_FILE)?;
+        // Not something Maelstrom sends; a harness can pass it to make runs
+        // from a fresh state file reproducible. The state in the file, if
+        // any, wins over it.
+        if let Some(seed) = init.extra.get("seed") {
+            let sequence = seed
+                .as_u64()
+                .and_then(|seed| u16::try_from(seed).ok())
+                .filter(|&seed| seed <= MAX_SEQUENCE)
+                .with_context(|| {
+                    format!("seed must be a clock sequence up to {MAX_SEQUENCE}, not {seed}")
+                })?;
+            generator = generator.with_initial_sequence(sequence);
+        }
+        // Without the reservation the generates just take the lock each.
+        if let Err(e) = generator.warm_up(WARMUP) {
+            eprintln!("warning: cannot reserve IDs at init: {e}");
+        }
+        Ok(UniqueIdsNode { generator })
+    }
+
+    fn step(&mut self, input: Event<UniqueIdsPayload>, output: &mut Outbox) -> Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        match input.body.payload {
+            UniqueIdsPayload::Generate => {
+                match self.generator.generate() {
+                    Ok(id) => {
+                        let payload = UniqueIdsPayload::GenerateOk { id: id.to_string() };
+                        output.reply(&input, payload)
+                    }
+                    // No ID was handed out, so the client can safely retry.
+                    Err(e) => {
+                        eprintln!("error: {e}");
+                        output.reply_error(
+                            &input,
+                            MaelstromError::TemporarilyUnavailable,
+                            e.to_string(),
+                        )
+                    }
+                }
+                .context("reply to generate")?;
+            }
+            UniqueIdsPayload::GenerateOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    main_loop::<_, UniqueIdsNode, _>(())
+}
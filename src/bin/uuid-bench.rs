@@ -0,0 +1,63 @@
+//! Compares the throughput of `UUIDGenerator::generate` against
+//! `UUIDGenerator::generate_batch`.
+//!
+//! Usage: `uuid-bench [count]` (defaults to 1,000,000 IDs per path).
+//!
+//! Moving the generator state behind a `Mutex`, so that `generate` takes
+//! `&self`, cost nothing measurable. Medians of 5 release runs of 1,000,000
+//! IDs each, on one core of a Linux VM:
+//!
+//! | path       | `&mut self`          | `Mutex`              |
+//! |------------|----------------------|----------------------|
+//! | `generate` | 10.12s (98.8k ids/s) | 11.23s (89.1k ids/s) |
+//! | `batch`    | 0.101s (9.9M ids/s)  | 0.083s (12.0M ids/s) |
+//!
+//! The runs spread by more than the difference between the two, from 9.8s
+//! to 12.5s either way for `generate`, whose time goes to committing the
+//! state file once per ID.
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use gossip_glomers_rs::uuid::{UUIDGenerator, UUID};
+use gossip_glomers_rs::Result;
+
+const BATCH_SIZE: usize = 1000;
+
+fn report(name: &str, count: usize, start: Instant, ids: &[UUID]) {
+    let elapsed = start.elapsed();
+    let unique: HashSet<&UUID> = ids.iter().collect();
+    assert_eq!(unique.len(), ids.len(), "{name} produced duplicate IDs");
+    println!(
+        "{name:>8}: {count} ids in {:.3}s ({:.0} ids/sec)",
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> Result<()> {
+    let count: usize = match std::env::args().nth(1) {
+        Some(arg) => arg.parse().expect("count must be a positive integer"),
+        None => 1_000_000,
+    };
+
+    let path = std::env::temp_dir().join(format!("uuid-bench-{}.db", std::process::id()));
+
+    let generator = UUIDGenerator::new(&path)?;
+    let start = Instant::now();
+    let ids: Vec<UUID> = (0..count)
+        .map(|_| generator.generate())
+        .collect::<Result<_>>()?;
+    report("generate", count, start, &ids);
+
+    let start = Instant::now();
+    let mut ids = Vec::with_capacity(count);
+    while ids.len() < count {
+        ids.extend(generator.generate_batch(BATCH_SIZE.min(count - ids.len()))?);
+    }
+    report("batch", count, start, &ids);
+
+    drop(generator);
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
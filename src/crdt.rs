@@ -0,0 +1,278 @@
+//! Conflict-free replicated data types shared by the challenge nodes.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::json::{self, FromJson, ToJson, Value};
+
+/// A value together with the write that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub timestamp: u64,
+    pub node_id: String,
+}
+
+/// A custom tie-break between an incoming and the current write.
+pub type ResolveFn<T> = Arc<dyn Fn(&Timestamped<T>, &Timestamped<T>) -> Ordering + Send + Sync>;
+
+/// Decides which of two writes with the same timestamp wins.
+#[derive(Default)]
+pub enum ConflictResolver<T> {
+    /// The write from the node with the greater id wins.
+    #[default]
+    HigherNodeId,
+    /// The greater value wins, falling back to the node id if the values are
+    /// equal too.
+    HigherValue,
+    /// `Ordering::Greater` means the first (incoming) write wins.
+    Custom(ResolveFn<T>),
+}
+
+impl<T> Clone for ConflictResolver<T> {
+    fn clone(&self) -> Self {
+        match self {
+            ConflictResolver::HigherNodeId => ConflictResolver::HigherNodeId,
+            ConflictResolver::HigherValue => ConflictResolver::HigherValue,
+            ConflictResolver::Custom(f) => ConflictResolver::Custom(Arc::clone(f)),
+        }
+    }
+}
+
+impl<T> fmt::Debug for ConflictResolver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictResolver::HigherNodeId => f.write_str("HigherNodeId"),
+            ConflictResolver::HigherValue => f.write_str("HigherValue"),
+            ConflictResolver::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl<T: Ord> ConflictResolver<T> {
+    fn wins(&self, incoming: &Timestamped<T>, current: &Timestamped<T>) -> bool {
+        let ordering = incoming
+            .timestamp
+            .cmp(&current.timestamp)
+            .then_with(|| match self {
+                ConflictResolver::HigherNodeId => incoming.node_id.cmp(&current.node_id),
+                ConflictResolver::HigherValue => incoming
+                    .value
+                    .cmp(&current.value)
+                    .then_with(|| incoming.node_id.cmp(&current.node_id)),
+                ConflictResolver::Custom(f) => f(incoming, current),
+            });
+        ordering == Ordering::Greater
+    }
+}
+
+/// A last-writer-wins register: the write with the greatest timestamp wins,
+/// ties are broken by the register's [`ConflictResolver`].
+#[derive(Debug, Clone)]
+pub struct LwwRegister<T> {
+    current: Option<Timestamped<T>>,
+    resolver: ConflictResolver<T>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister {
+            current: None,
+            resolver: ConflictResolver::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> LwwRegister<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_resolver(resolver: ConflictResolver<T>) -> Self {
+        LwwRegister {
+            current: None,
+            resolver,
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.current.as_ref().map(|current| &current.value)
+    }
+
+    pub fn entry(&self) -> Option<&Timestamped<T>> {
+        self.current.as_ref()
+    }
+
+    /// Applies a write, returning whether it became the register's value.
+    pub fn set(&mut self, value: T, timestamp: u64, node_id: impl Into<String>) -> bool {
+        self.apply(Timestamped {
+            value,
+            timestamp,
+            node_id: node_id.into(),
+        })
+    }
+
+    /// Applies a write received from another replica.
+    pub fn apply(&mut self, incoming: Timestamped<T>) -> bool {
+        let wins = match &self.current {
+            Some(current) => self.resolver.wins(&incoming, current),
+            None => true,
+        };
+        if wins {
+            self.current = Some(incoming);
+        }
+        wins
+    }
+
+    /// Merges the state of another replica into this one.
+    pub fn merge(&mut self, other: &LwwRegister<T>) {
+        if let Some(entry) = &other.current {
+            self.apply(entry.clone());
+        }
+    }
+}
+
+/// A grow-only counter: every node increments its own slot, and the value is
+/// the sum over all slots.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCounter {
+    counts: HashMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&mut self, node_id: &str, by: u64) {
+        *self.counts.entry(node_id.to_string()).or_default() += by;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Takes the per-node maximum of both counters.
+    pub fn merge(&mut self, other: &GCounter) {
+        for (node_id, &count) in &other.counts {
+            let slot = self.counts.entry(node_id.clone()).or_default();
+            *slot = (*slot).max(count);
+        }
+    }
+
+    /// The slots in which this counter is ahead of `known`: merging the
+    /// result into `known` brings it up to date with this counter.
+    pub fn delta_since(&self, known: &GCounter) -> GCounter {
+        let counts = self
+            .counts
+            .iter()
+            .filter(|(node_id, &count)| known.counts.get(*node_id).is_none_or(|&k| k < count))
+            .map(|(node_id, &count)| (node_id.clone(), count))
+            .collect();
+        GCounter { counts }
+    }
+}
+
+impl ToJson for GCounter {
+    fn to_json(&self) -> Value {
+        self.counts.to_json()
+    }
+}
+
+impl FromJson for GCounter {
+    fn from_json(value: &Value) -> Result<Self, json::Error> {
+        Ok(GCounter {
+            counts: HashMap::from_json(value)?,
+        })
+    }
+}
+
+/// A counter that can go both ways, kept as one grow-only counter for
+/// increments and one for decrements.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    pub increments: GCounter,
+    pub decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node_id, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(node_id, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    /// See [`GCounter::delta_since`].
+    pub fn delta_since(&self, known: &PnCounter) -> PnCounter {
+        PnCounter {
+            increments: self.increments.delta_since(&known.increments),
+            decrements: self.decrements.delta_since(&known.decrements),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value `resolver` keeps after n1 writes 5 and n2 writes 3 at the
+    /// same timestamp, in either order.
+    fn tie_winner(resolver: ConflictResolver<u64>) -> u64 {
+        let writes = [(5, "n1"), (3, "n2")];
+        let winners = [writes, [writes[1], writes[0]]].map(|order| {
+            let mut register = LwwRegister::with_resolver(resolver.clone());
+            for (value, node_id) in order {
+                register.set(value, 7, node_id);
+            }
+            *register.get().unwrap()
+        });
+        assert_eq!(winners[0], winners[1], "depends on the order");
+        winners[0]
+    }
+
+    #[test]
+    fn each_resolver_picks_its_winner_on_a_tie() {
+        assert_eq!(tie_winner(ConflictResolver::HigherNodeId), 3);
+        assert_eq!(tie_winner(ConflictResolver::HigherValue), 5);
+        let lower_value = ConflictResolver::Custom(Arc::new(
+            |incoming: &Timestamped<u64>, current: &Timestamped<u64>| {
+                current.value.cmp(&incoming.value)
+            },
+        ));
+        assert_eq!(tie_winner(lower_value), 3);
+    }
+
+    #[test]
+    fn a_later_timestamp_wins_over_any_resolver() {
+        let mut register = LwwRegister::with_resolver(ConflictResolver::HigherValue);
+        assert!(register.set(9, 1, "n2"));
+        assert!(register.set(1, 2, "n1"));
+        assert!(!register.set(9, 1, "n2"));
+        assert_eq!(register.get(), Some(&1));
+    }
+}
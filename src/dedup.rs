@@ -0,0 +1,93 @@
+//! Remembering the replies a node sent, so that a request Maelstrom retries
+//! is answered again without handling it twice.
+//!
+//! Replies are kept under the `(src, msg_id)` of the request they answer.
+//! The cache holds at most a fixed number of entries, evicting the least
+//! recently used one first, and entries older than its TTL are treated as
+//! gone.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::json::Value;
+use crate::Message;
+
+type Key = (String, usize);
+
+struct Entry {
+    reply: Message<Value>,
+    inserted: Instant,
+    /// Position in `ReplyCache::order`.
+    used: u64,
+}
+
+pub(crate) struct ReplyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<Key, Entry>,
+    /// Keys by when they were last used, oldest first.
+    order: BTreeMap<u64, Key>,
+    next_use: u64,
+}
+
+impl ReplyCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        ReplyCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The reply sent to the request `msg_id` from `src`, unless it was
+    /// evicted or has expired by `now`.
+    pub(crate) fn get(&mut self, src: &str, msg_id: usize, now: Instant) -> Option<Message<Value>> {
+        let key = (src.to_string(), msg_id);
+        let entry = self.entries.get_mut(&key)?;
+        if now.saturating_duration_since(entry.inserted) > self.ttl {
+            self.order.remove(&entry.used);
+            self.entries.remove(&key);
+            return None;
+        }
+        self.order.remove(&entry.used);
+        entry.used = self.next_use;
+        self.order.insert(self.next_use, key);
+        self.next_use += 1;
+        Some(entry.reply.clone())
+    }
+
+    /// Remembers `reply`, which must answer a request (have `in_reply_to`).
+    /// A request's first reply is kept, so sending it again from the cache
+    /// does not extend its TTL.
+    pub(crate) fn insert(&mut self, reply: &Message<Value>, now: Instant) {
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            return;
+        };
+        let key = (reply.dest.clone(), in_reply_to);
+        if self.entries.contains_key(&key) {
+            return;
+        }
+        self.order.insert(self.next_use, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                reply: reply.clone(),
+                inserted: now,
+                used: self.next_use,
+            },
+        );
+        self.next_use += 1;
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Requests to a service that has a backup: when the primary stops
+//! answering, the request is sent to the backup instead.
+
+use std::time::Duration;
+
+use crate::json::{ToJson, Value};
+use crate::{bail, Message, Outbox, Result};
+
+pub struct Failover {
+    primary: String,
+    backup: String,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl Failover {
+    pub fn new(primary: impl Into<String>, backup: impl Into<String>) -> Self {
+        Failover {
+            primary: primary.into(),
+            backup: backup.into(),
+            timeout: Duration::from_secs(1),
+            attempts: 3,
+        }
+    }
+
+    /// How long to wait for each reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times the primary is tried before failing over.
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Sends `payload` from `src` and waits for the reply, trying the
+    /// primary `attempts` times and then the backup once.
+    ///
+    /// The reply's `src` tells which of the two answered.
+    pub fn call<P: ToJson>(
+        &self,
+        src: &str,
+        payload: &P,
+        output: &mut Outbox,
+    ) -> Result<Message<Value>> {
+        for _ in 0..self.attempts {
+            let reply = output.request(src, &self.primary, payload)?;
+            if let Ok(reply) = reply.recv_timeout(self.timeout) {
+                return Ok(reply);
+            }
+        }
+        eprintln!(
+            "warning: {} did not answer {} requests, failing over to {}",
+            self.primary, self.attempts, self.backup
+        );
+        let reply = output.request(src, &self.backup, payload)?;
+        match reply.recv_timeout(self.timeout) {
+            Ok(reply) => Ok(reply),
+            Err(_) => bail!(
+                "neither {} nor its backup {} answered",
+                self.primary,
+                self.backup
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::json;
+    use crate::test_support::{serving_outbox, KvService, SharedBuffer};
+
+    #[test]
+    fn the_backup_answers_once_the_primary_times_out() {
+        let seq_kv = KvService::new("seq-kv");
+        seq_kv.insert("k", Value::Int(7));
+        let unanswered = SharedBuffer::new();
+        let services = Arc::new(Mutex::new(vec![seq_kv.clone()]));
+        let mut output = serving_outbox(unanswered.clone(), services);
+
+        let failover = Failover::new("lin-kv", "seq-kv")
+            .with_timeout(Duration::from_millis(10))
+            .with_attempts(2);
+        let read = json::parse(r#"{"type":"read","key":"k"}"#).unwrap();
+        let reply = failover.call("n1", &read, &mut output).unwrap();
+
+        assert_eq!(reply.src, "seq-kv");
+        assert_eq!(reply.body.payload.get("value"), Some(&Value::Int(7)));
+        let primary: Vec<_> = unanswered
+            .messages()
+            .unwrap()
+            .into_iter()
+            .map(|request| request.dest)
+            .collect();
+        assert_eq!(primary, ["lin-kv", "lin-kv"]);
+        assert_eq!(seq_kv.requests().len(), 1);
+    }
+}
@@ -0,0 +1,362 @@
+//! Choosing which peers receive a gossip message.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::json::{self, FromJson, ToJson, Value};
+use crate::rand::random_index;
+use crate::{base64, gzip};
+
+/// Serialized payload fields larger than this many bytes are compressed by
+/// [`Compressible`].
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+/// Marks a compressed field on the wire.
+const GZIP_PREFIX: &str = "gzip:";
+
+/// Which neighbors a node forwards gossip to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GossipStrategy {
+    /// Every neighbor in the topology Maelstrom sends.
+    #[default]
+    All,
+    /// Only the node's parent and children in a spanning tree of the
+    /// topology, rooted at the lowest node id. Sends the fewest messages,
+    /// at the cost of latency and fault tolerance.
+    SpanningTree,
+    /// Up to `k` neighbors picked at random for every gossip.
+    RandomSubset(usize),
+}
+
+impl GossipStrategy {
+    /// Whether [`targets`](Self::targets) always returns the same nodes for
+    /// the same topology, so its result can be reused.
+    pub fn is_deterministic(&self) -> bool {
+        !matches!(self, GossipStrategy::RandomSubset(_))
+    }
+
+    /// The nodes `me` should gossip to under `topology`.
+    pub fn targets(&self, me: &str, topology: &Topology) -> Vec<String> {
+        match self {
+            GossipStrategy::All => topology.neighbors(me).to_vec(),
+            GossipStrategy::SpanningTree => {
+                let Some(root) = topology.nodes().min() else {
+                    return Vec::new();
+                };
+                topology.spanning_tree_neighbors(root, me)
+            }
+            GossipStrategy::RandomSubset(k) => {
+                let mut neighbors = topology.neighbors(me).to_vec();
+                let k = (*k).min(neighbors.len());
+                // Partial Fisher-Yates shuffle: the first k entries end up
+                // being a uniform random sample.
+                for i in 0..k {
+                    let j = i + random_index(neighbors.len() - i);
+                    neighbors.swap(i, j);
+                }
+                neighbors.truncate(k);
+                neighbors
+            }
+        }
+    }
+}
+
+impl fmt::Display for GossipStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GossipStrategy::All => f.write_str("all"),
+            GossipStrategy::SpanningTree => f.write_str("tree"),
+            GossipStrategy::RandomSubset(k) => write!(f, "random:{k}"),
+        }
+    }
+}
+
+impl FromStr for GossipStrategy {
+    type Err = crate::Error;
+
+    /// Parses `all`, `tree` or `random:<k>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(GossipStrategy::All),
+            "tree" => Ok(GossipStrategy::SpanningTree),
+            _ => match s.strip_prefix("random:").map(str::parse) {
+                Some(Ok(k)) => Ok(GossipStrategy::RandomSubset(k)),
+                _ => Err(crate::format_err!(
+                    "unknown gossip strategy {s:?}, expected all, tree or random:<k>"
+                )),
+            },
+        }
+    }
+}
+
+/// The neighbors of every node, as Maelstrom sends them in a `topology`
+/// message.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Topology {
+    neighbors: HashMap<String, Vec<String>>,
+}
+
+impl Topology {
+    pub fn from_map(neighbors: HashMap<String, Vec<String>>) -> Self {
+        Topology { neighbors }
+    }
+
+    /// The neighbors of `node`, empty for a node the topology does not list.
+    pub fn neighbors(&self, node: &str) -> &[String] {
+        self.neighbors.get(node).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every node the topology lists neighbors for.
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.neighbors.keys().map(String::as_str)
+    }
+
+    /// The neighbors of `me` in the breadth-first spanning tree rooted at
+    /// `root`: its parent and its children. Broadcasting along them reaches
+    /// every connected node with one message per node. Empty if `me` is not
+    /// reachable from `root`.
+    pub fn spanning_tree_neighbors(&self, root: &str, me: &str) -> Vec<String> {
+        spanning_tree_from(root, &self.neighbors)
+            .remove(me)
+            .unwrap_or_default()
+    }
+}
+
+impl ToJson for Topology {
+    fn to_json(&self) -> Value {
+        self.neighbors.to_json()
+    }
+}
+
+impl FromJson for Topology {
+    fn from_json(value: &Value) -> Result<Self, json::Error> {
+        HashMap::from_json(value).map(Topology::from_map)
+    }
+}
+
+/// Computes a breadth-first spanning tree of `topology` rooted at `root`.
+///
+/// The result maps every reachable node to its tree neighbors (its parent
+/// and its children), so each edge appears in both directions.
+pub fn spanning_tree_from(
+    root: &str,
+    topology: &HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    let mut tree: HashMap<String, Vec<String>> = HashMap::new();
+    let mut visited = HashSet::from([root.to_string()]);
+    let mut queue = VecDeque::from([root.to_string()]);
+    tree.insert(root.to_string(), Vec::new());
+
+    while let Some(node) = queue.pop_front() {
+        let mut neighbors = topology.get(&node).cloned().unwrap_or_default();
+        neighbors.sort();
+        for neighbor in neighbors {
+            if visited.insert(neighbor.clone()) {
+                tree.entry(node.clone()).or_default().push(neighbor.clone());
+                tree.entry(neighbor.clone()).or_default().push(node.clone());
+                queue.push_back(neighbor);
+            }
+        }
+    }
+
+    tree
+}
+
+/// A gossip payload field that is sent gzip-compressed once its JSON form
+/// is larger than [`COMPRESSION_THRESHOLD`], and as plain JSON otherwise.
+///
+/// A compressed field is a string holding `gzip:` and the base64-encoded
+/// data. Only meant for messages between nodes: Maelstrom clients do not
+/// understand it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Compressible<T>(pub T);
+
+impl<T: ToJson> ToJson for Compressible<T> {
+    fn to_json(&self) -> Value {
+        let value = self.0.to_json();
+        let text = value.to_string();
+        if text.len() <= COMPRESSION_THRESHOLD {
+            return value;
+        }
+        let compressed = gzip::compress(text.as_bytes());
+        Value::String(format!("{GZIP_PREFIX}{}", base64::encode(&compressed)))
+    }
+}
+
+impl<T: FromJson> FromJson for Compressible<T> {
+    fn from_json(value: &Value) -> Result<Self, json::Error> {
+        let Some(encoded) = value.as_str().and_then(|s| s.strip_prefix(GZIP_PREFIX)) else {
+            return T::from_json(value).map(Compressible);
+        };
+        let compressed = base64::decode(encoded)
+            .ok_or_else(|| json::Error::new("compressed field is not valid base64"))?;
+        let text = gzip::decompress(&compressed).map_err(|e| json::Error::new(e.to_string()))?;
+        let text = String::from_utf8(text)
+            .map_err(|_| json::Error::new("compressed field is not UTF-8"))?;
+        T::from_json(&json::parse(&text)?).map(Compressible)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `n1 - n2 - n3` with a cycle `n2 - n4 - n5 - n2` hanging off n2.
+    fn topology() -> Topology {
+        let edges = [
+            ("n1", "n2"),
+            ("n2", "n3"),
+            ("n2", "n4"),
+            ("n4", "n5"),
+            ("n5", "n2"),
+        ];
+        let mut neighbors: HashMap<String, Vec<String>> = HashMap::new();
+        for (a, b) in edges {
+            neighbors
+                .entry(a.to_string())
+                .or_default()
+                .push(b.to_string());
+            neighbors
+                .entry(b.to_string())
+                .or_default()
+                .push(a.to_string());
+        }
+        Topology::from_map(neighbors)
+    }
+
+    fn sorted(mut nodes: Vec<String>) -> Vec<String> {
+        nodes.sort();
+        nodes
+    }
+
+    #[test]
+    fn topologies_parse_from_maelstrom_json() {
+        let json = json::parse(r#"{"n1":["n2","n3"],"n2":["n1"],"n3":[]}"#).unwrap();
+        let topology = Topology::from_json(&json).unwrap();
+        assert_eq!(topology.neighbors("n1"), ["n2", "n3"]);
+        assert_eq!(topology.neighbors("n2"), ["n1"]);
+        assert!(topology.neighbors("n3").is_empty());
+        assert_eq!(Topology::from_json(&topology.to_json()).unwrap(), topology);
+
+        for bad in [r#"["n1"]"#, r#"{"n1":"n2"}"#, r#"{"n1":[2]}"#] {
+            assert!(
+                Topology::from_json(&json::parse(bad).unwrap()).is_err(),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_and_unreachable_nodes_have_no_neighbors() {
+        let topology = topology();
+        assert!(topology.neighbors("n9").is_empty());
+        assert!(topology.spanning_tree_neighbors("n1", "n9").is_empty());
+        assert!(topology.spanning_tree_neighbors("n9", "n1").is_empty());
+        assert!(Topology::default().neighbors("n1").is_empty());
+        for strategy in [
+            GossipStrategy::All,
+            GossipStrategy::SpanningTree,
+            GossipStrategy::RandomSubset(3),
+        ] {
+            assert!(strategy.targets("n1", &Topology::default()).is_empty());
+        }
+    }
+
+    #[test]
+    fn all_targets_every_neighbor() {
+        let topology = topology();
+        for node in topology.nodes() {
+            let targets = GossipStrategy::All.targets(node, &topology);
+            assert_eq!(sorted(targets), sorted(topology.neighbors(node).to_vec()));
+        }
+    }
+
+    #[test]
+    fn the_spanning_tree_reaches_every_node_without_a_cycle() {
+        let topology = topology();
+        let mut edges = HashSet::new();
+        for node in topology.nodes() {
+            for target in GossipStrategy::SpanningTree.targets(node, &topology) {
+                assert!(
+                    topology.neighbors(node).contains(&target),
+                    "{node}-{target}"
+                );
+                edges.insert((node.min(&target).to_string(), node.max(&target).to_string()));
+            }
+        }
+        // Connected with one edge fewer than nodes means a tree.
+        let nodes = topology.nodes().count();
+        assert_eq!(edges.len(), nodes - 1, "{edges:?}");
+        let mut reached = HashSet::from(["n1".to_string()]);
+        let mut queue = VecDeque::from(["n1".to_string()]);
+        while let Some(node) = queue.pop_front() {
+            for target in GossipStrategy::SpanningTree.targets(&node, &topology) {
+                if reached.insert(target.clone()) {
+                    queue.push_back(target);
+                }
+            }
+        }
+        assert_eq!(reached.len(), nodes);
+        // Rooted at the lowest id, so n2 is the hub and n4, n5 stay apart.
+        let n2 = GossipStrategy::SpanningTree.targets("n2", &topology);
+        assert_eq!(sorted(n2), ["n1", "n3", "n4", "n5"]);
+    }
+
+    #[test]
+    fn random_subsets_pick_k_distinct_neighbors() {
+        let topology = topology();
+        let neighbors = topology.neighbors("n2");
+        for k in 0..=neighbors.len() + 1 {
+            let targets = GossipStrategy::RandomSubset(k).targets("n2", &topology);
+            assert_eq!(targets.len(), k.min(neighbors.len()), "k = {k}");
+            assert!(targets.iter().all(|target| neighbors.contains(target)));
+            let distinct: HashSet<_> = targets.iter().collect();
+            assert_eq!(distinct.len(), targets.len());
+        }
+    }
+
+    /// A string whose JSON form, quotes included, is `len` bytes long.
+    fn string_of_json_len(len: usize) -> Compressible<String> {
+        Compressible("x".repeat(len - 2))
+    }
+
+    fn round_trip<T: ToJson + FromJson>(value: &Compressible<T>) -> Compressible<T> {
+        Compressible::from_json(&json::parse(&value.to_json().to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fields_up_to_the_threshold_stay_plain() {
+        for len in [COMPRESSION_THRESHOLD - 1, COMPRESSION_THRESHOLD] {
+            let field = string_of_json_len(len);
+            assert_eq!(field.to_json(), Value::String(field.0.clone()));
+            assert_eq!(round_trip(&field), field);
+        }
+    }
+
+    #[test]
+    fn fields_above_the_threshold_are_compressed() {
+        let field = string_of_json_len(COMPRESSION_THRESHOLD + 1);
+        let json = field.to_json();
+        assert!(json.as_str().unwrap().starts_with(GZIP_PREFIX), "{json}");
+        assert_eq!(round_trip(&field), field);
+    }
+
+    #[test]
+    fn repetitive_gossip_gets_smaller() {
+        let field = Compressible((0..2000u64).map(|i| i % 50).collect::<Vec<_>>());
+        let plain = field.0.to_json().to_string();
+        let compressed = field.to_json().to_string();
+        assert!(plain.len() > COMPRESSION_THRESHOLD);
+        assert!(compressed.len() * 4 < plain.len(), "{compressed}");
+        assert_eq!(round_trip(&field), field);
+    }
+
+    #[test]
+    fn invalid_compressed_fields_are_rejected() {
+        for text in ["gzip:not base64!", "gzip:aGVsbG8="] {
+            let value = Value::String(text.to_string());
+            assert!(Compressible::<String>::from_json(&value).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,766 @@
+//! Just enough gzip (RFC 1951 and 1952) to shrink large gossip payloads.
+//!
+//! The compressor emits a single DEFLATE block with Huffman codes built for
+//! the data at hand. The decompressor accepts any valid gzip member.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried per match.
+const MAX_CHAIN: usize = 64;
+/// The longest Huffman code DEFLATE allows, and the longest for the code
+/// length alphabet.
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in, for dynamic blocks.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Input that is not a valid gzip member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidGzip {
+    reason: &'static str,
+}
+
+impl fmt::Display for InvalidGzip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid gzip data: {}", self.reason)
+    }
+}
+
+impl std::error::Error for InvalidGzip {}
+
+fn invalid(reason: &'static str) -> InvalidGzip {
+    InvalidGzip { reason }
+}
+
+/// Compresses `data` into a gzip member.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, CM = deflate, no flags, no mtime, no extra flags, OS unknown.
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    let tokens = find_matches(data);
+    let mut bits = BitWriter::new(&mut out);
+    write_dynamic_block(&mut bits, &tokens);
+    bits.finish();
+
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Decompresses a gzip member produced by [`compress`] or any other
+/// encoder.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, InvalidGzip> {
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err(invalid("missing gzip header"));
+    }
+    if data[2] != 8 {
+        return Err(invalid("unsupported compression method"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    let byte_at = |pos: usize| data.get(pos).copied().ok_or(invalid("truncated header"));
+    if flags & 4 != 0 {
+        let len = byte_at(pos)? as usize | (byte_at(pos + 1)? as usize) << 8;
+        pos += 2 + len;
+    }
+    for flag in [8, 16] {
+        if flags & flag != 0 {
+            while byte_at(pos)? != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    if flags & 2 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() - 8 {
+        return Err(invalid("truncated header"));
+    }
+
+    let trailer = &data[data.len() - 8..];
+    let out = inflate(&data[pos..data.len() - 8])?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || size != out.len() as u32 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+/// A literal byte, or a back-reference of `len` bytes starting `dist` bytes
+/// back.
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { len: usize, dist: usize },
+}
+
+/// LZ77: replaces repeated runs by back-references, using hash chains to
+/// find earlier occurrences.
+fn find_matches(data: &[u8]) -> Vec<Token> {
+    let mut head = vec![usize::MAX; 1 << 15];
+    let mut prev = vec![usize::MAX; data.len()];
+    let hash = |i: usize| {
+        let h = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+        (h.wrapping_mul(2654435761) >> 17) as usize
+    };
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            prev[i] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash(i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW && chain < MAX_CHAIN {
+                let limit = (data.len() - i).min(MAX_MATCH);
+                let len = (0..limit)
+                    .take_while(|&k| data[candidate + k] == data[i + k])
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                    if len == limit {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            tokens.push(Token::Match {
+                len: best_len,
+                dist: best_dist,
+            });
+            for k in i..i + best_len {
+                insert(k, &mut head, &mut prev);
+            }
+            i += best_len;
+        } else {
+            tokens.push(Token::Literal(data[i]));
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    tokens
+}
+
+fn length_symbol(len: usize) -> usize {
+    LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= len)
+        .unwrap()
+}
+
+fn distance_symbol(dist: usize) -> usize {
+    DIST_BASE
+        .iter()
+        .rposition(|&base| base as usize <= dist)
+        .unwrap()
+}
+
+/// Writes `tokens` as one final block with dynamic Huffman codes.
+fn write_dynamic_block(bits: &mut BitWriter, tokens: &[Token]) {
+    let mut literal_freqs = [0u32; 286];
+    let mut distance_freqs = [0u32; 30];
+    literal_freqs[256] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match { len, dist } => {
+                literal_freqs[257 + length_symbol(len)] += 1;
+                distance_freqs[distance_symbol(dist)] += 1;
+            }
+        }
+    }
+    let literal_lengths = code_lengths(&literal_freqs, MAX_CODE_LENGTH);
+    let distance_lengths = code_lengths(&distance_freqs, MAX_CODE_LENGTH);
+    let literal_count = 257.max(last_used(&literal_lengths));
+    let distance_count = 1.max(last_used(&distance_lengths));
+
+    // The code lengths of both codes are sent run-length encoded, themselves
+    // Huffman coded with a third code.
+    let mut all_lengths = literal_lengths[..literal_count].to_vec();
+    all_lengths.extend_from_slice(&distance_lengths[..distance_count]);
+    let runs = run_length_encode(&all_lengths);
+    let mut code_length_freqs = [0u32; 19];
+    for &(symbol, _) in &runs {
+        code_length_freqs[symbol as usize] += 1;
+    }
+    let code_length_lengths = code_lengths(&code_length_freqs, MAX_CODE_LENGTH_CODE_LENGTH);
+    let code_length_count = 4.max(
+        CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| code_length_lengths[symbol] != 0)
+            .map_or(0, |i| i + 1),
+    );
+
+    bits.write(1, 1); // BFINAL
+    bits.write(2, 2); // BTYPE = dynamic Huffman
+    bits.write(literal_count as u32 - 257, 5);
+    bits.write(distance_count as u32 - 1, 5);
+    bits.write(code_length_count as u32 - 4, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        bits.write(code_length_lengths[symbol] as u32, 3);
+    }
+    let code_length_codes = canonical_codes(&code_length_lengths);
+    for &(symbol, extra) in &runs {
+        let symbol = symbol as usize;
+        bits.write_huffman(code_length_codes[symbol], code_length_lengths[symbol]);
+        match symbol {
+            16 => bits.write(extra, 2),
+            17 => bits.write(extra, 3),
+            18 => bits.write(extra, 7),
+            _ => {}
+        }
+    }
+
+    let literal_codes = canonical_codes(&literal_lengths);
+    let distance_codes = canonical_codes(&distance_lengths);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                let byte = byte as usize;
+                bits.write_huffman(literal_codes[byte], literal_lengths[byte]);
+            }
+            Token::Match { len, dist } => {
+                let index = length_symbol(len);
+                let symbol = 257 + index;
+                bits.write_huffman(literal_codes[symbol], literal_lengths[symbol]);
+                bits.write(
+                    (len - LENGTH_BASE[index] as usize) as u32,
+                    LENGTH_EXTRA[index],
+                );
+                let index = distance_symbol(dist);
+                bits.write_huffman(distance_codes[index], distance_lengths[index]);
+                bits.write((dist - DIST_BASE[index] as usize) as u32, DIST_EXTRA[index]);
+            }
+        }
+    }
+    bits.write_huffman(literal_codes[256], literal_lengths[256]);
+}
+
+/// One past the last symbol with a non-zero code length.
+fn last_used(lengths: &[u8]) -> usize {
+    lengths
+        .iter()
+        .rposition(|&len| len != 0)
+        .map_or(0, |i| i + 1)
+}
+
+/// Huffman code lengths for `freqs`, none longer than `limit`.
+///
+/// Symbols with a zero frequency get no code. If fewer than two symbols are
+/// used, the code is padded to two so that decoders always see a complete
+/// code.
+fn code_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    let mut used = freqs.iter().filter(|&&f| f > 0).count();
+    for freq in freqs.iter_mut() {
+        if used >= 2 {
+            break;
+        }
+        if *freq == 0 {
+            *freq = 1;
+            used += 1;
+        }
+    }
+    loop {
+        let lengths = huffman_lengths(&freqs);
+        if lengths.iter().all(|&len| len <= limit) {
+            return lengths;
+        }
+        // Flattening the distribution shortens the longest codes; repeat
+        // until they fit.
+        for freq in freqs.iter_mut().filter(|f| **f > 0) {
+            *freq = (*freq).div_ceil(2);
+        }
+    }
+}
+
+/// Unlimited Huffman code lengths for `freqs`.
+fn huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    // Nodes are leaves (the symbols) followed by internal nodes; `parent`
+    // links each to the node it was merged into.
+    let mut parent = vec![usize::MAX; freqs.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs
+        .iter()
+        .enumerate()
+        .filter(|(_, &f)| f > 0)
+        .map(|(symbol, &f)| Reverse((f as u64, symbol)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a, i)) = heap.pop().unwrap();
+        let Reverse((b, j)) = heap.pop().unwrap();
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[i] = node;
+        parent[j] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    (0..freqs.len())
+        .map(|symbol| {
+            if freqs[symbol] == 0 {
+                return 0;
+            }
+            let mut depth = 0;
+            let mut node = symbol;
+            while parent[node] != usize::MAX {
+                node = parent[node];
+                depth += 1;
+            }
+            depth
+        })
+        .collect()
+}
+
+/// The canonical codes for `lengths`, as RFC 1951 section 3.2.2 assigns
+/// them.
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &len in lengths {
+        counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for len in 1..16 {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+    lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Run-length encodes code lengths into code length symbols and the value
+/// of their extra bits.
+fn run_length_encode(lengths: &[u8]) -> Vec<(u8, u32)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == len).count();
+        if len == 0 && run >= 11 {
+            let run = run.min(138);
+            runs.push((18, run as u32 - 11));
+            i += run;
+        } else if len == 0 && run >= 3 {
+            runs.push((17, run as u32 - 3));
+            i += run;
+        } else if len != 0 && run >= 4 {
+            // The first one is sent as is, the rest as repeats of it.
+            runs.push((len, 0));
+            let run = (run - 1).min(6);
+            runs.push((16, run as u32 - 3));
+            i += 1 + run;
+        } else {
+            runs.push((len, 0));
+            i += 1;
+        }
+    }
+    runs
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Writes the low `count` bits of `value`, least significant first.
+    fn write(&mut self, value: u32, count: u8) {
+        for i in 0..count {
+            self.buffer |= ((value >> i) & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.out.push(self.buffer as u8);
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn write_huffman(&mut self, code: u16, len: u8) {
+        let reversed = (code.reverse_bits() >> (16 - len)) as u32;
+        self.write(reversed, len);
+    }
+
+    fn finish(self) {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u32, InvalidGzip> {
+        let byte = *self
+            .data
+            .get(self.pos)
+            .ok_or(invalid("unexpected end of data"))?;
+        let bit = (byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u32, InvalidGzip> {
+        let mut value = 0;
+        for i in 0..count {
+            value |= self.bit()? << i;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of every length and the
+/// symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&s| lengths[s as usize] != 0)
+            .collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, InvalidGzip> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.bit()? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, InvalidGzip> {
+    let mut bits = BitReader {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bit()? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = data
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or(invalid("truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(invalid("corrupt stored block length"));
+                }
+                let start = bits.pos + 4;
+                let block = data
+                    .get(start..start + len as usize)
+                    .ok_or(invalid("truncated stored block"))?;
+                out.extend_from_slice(block);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut bits, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("reserved block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), InvalidGzip> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or(invalid("repeat with no length"))?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err(invalid("code lengths overflow"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), InvalidGzip> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err(invalid("bad length symbol"));
+                }
+                let len = LENGTH_BASE[index] as usize + bits.bits(LENGTH_EXTRA[index])? as usize;
+                let index = distances.decode(bits)? as usize;
+                if index >= DIST_BASE.len() {
+                    return Err(invalid("bad distance symbol"));
+                }
+                let dist = DIST_BASE[index] as usize + bits.bits(DIST_EXTRA[index])? as usize;
+                if dist > out.len() {
+                    return Err(invalid("distance before start of data"));
+                }
+                let start = out.len() - dist;
+                // Byte by byte, since the match may overlap what it produces.
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Comma separated numbers that repeat often enough to compress but not
+    /// so regularly that a single match covers them.
+    fn numbers(count: u64) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| format!("{},", i * i * 7919 % 1000).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn compressed_data_round_trips() {
+        let inputs = [
+            Vec::new(),
+            b"a".to_vec(),
+            b"hello hello hello".to_vec(),
+            numbers(200),
+            numbers(20_000),
+            vec![b'x'; 100_000],
+        ];
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn compress_writes_one_dynamic_block() {
+        let compressed = compress(&numbers(200));
+        // BFINAL and BTYPE are the first three bits after the 10 byte header.
+        assert_eq!(compressed[10] & 1, 1);
+        assert_eq!((compressed[10] >> 1) & 3, 2);
+    }
+
+    #[test]
+    fn stored_blocks_are_decompressed() {
+        // zlib at level 0.
+        let compressed = unhex(concat!(
+            "1f8b0800000000000403010c00f3ff73746f72656420626c6f636b",
+            "94a3243d0c000000",
+        ));
+        assert_eq!(decompress(&compressed).unwrap(), b"stored block");
+    }
+
+    #[test]
+    fn fixed_huffman_blocks_are_decompressed() {
+        // zlib with its default strategy, which picks fixed codes for short
+        // input.
+        let compressed = unhex(concat!(
+            "1f8b0800000000000203cb48cdc9c957c840900080",
+            "88f9e511000000",
+        ));
+        assert_eq!(decompress(&compressed).unwrap(), b"hello hello hello");
+    }
+
+    #[test]
+    fn dynamic_huffman_blocks_from_zlib_are_decompressed() {
+        let compressed = unhex(concat!(
+        "1f8b08000000000002032d924112042108033fc4611105f9ffc7b6e37098b2704848823f6b6fcb4a5be556bf",
+        "6d5dc7eeb670bb9eb6a3ad7f3ff36e8b48aedd7c6d2bba566e3b0dea241f2cf4ad6aab4ef3e3161b1af57136",
+        "f5e6fef2dfc547bf60c023876e9bbb4a6edb364d97e18e888518bf9fb444224a03c507e587b6e06ceacdfd1d",
+        "074dfb057680c7c77660cd10cc6d8d056776a061a325ce272d91d82ab91e07ce59d4de9af2393db407b0167c",
+        "2c34b40d7d29a9fe925b1aff9264ec9838a8bcca9ba010ffc351135d32db01cd12684e8100e7aca145aa8414",
+        "d47860b6a3c1a565b680c440ea41f2190b1c2a6f2bdee7f3350312f88c812b4eb877e49ba5dc34bb9e06668e",
+        "818d4669adcaa75d6b909353f98c7d5d45b76cf7dbe1c7a65896d835651c34d315e33df96295ba17b3c4223a",
+        "c743eb94a7ceb72559d5d65adb039fe3a2e0d5965ddb9e35e829e57b127b3c041ef46476e57b427f297e76a9",
+        "0b030000",
+        ));
+        assert_eq!((compressed[10] >> 1) & 3, 2);
+        assert_eq!(decompress(&compressed).unwrap(), numbers(200));
+    }
+
+    #[test]
+    fn truncated_streams_are_rejected() {
+        let compressed = compress(&numbers(200));
+        for len in 0..compressed.len() {
+            assert!(decompress(&compressed[..len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn corrupt_streams_are_rejected() {
+        let compressed = compress(&numbers(200));
+
+        let mut bad_magic = compressed.clone();
+        bad_magic[0] = 0;
+        assert!(decompress(&bad_magic).is_err());
+
+        let mut bad_crc = compressed.clone();
+        let crc = compressed.len() - 8;
+        bad_crc[crc] ^= 1;
+        assert!(decompress(&bad_crc).is_err());
+
+        let mut bad_block_type = compressed.clone();
+        bad_block_type[10] |= 0b110;
+        assert!(decompress(&bad_block_type).is_err());
+
+        // The last DEFLATE byte is skipped: it may end in padding bits.
+        for i in 11..compressed.len() - 9 {
+            let mut flipped = compressed.clone();
+            flipped[i] ^= 0x10;
+            // Most flips break the Huffman codes; the rest change the data
+            // and fail the CRC.
+            assert!(decompress(&flipped).is_err(), "byte {i}");
+        }
+    }
+}
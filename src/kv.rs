@@ -0,0 +1,413 @@
+//! A client for Maelstrom's key-value services (`seq-kv`, `lin-kv` and
+//! `lww-kv`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::json::Value;
+use crate::{payload, Context, MaelstromError, Outbox, Result};
+
+/// Maelstrom's error code for reading a key that was never written.
+pub const KEY_DOES_NOT_EXIST: u64 = MaelstromError::KeyDoesNotExist.code();
+
+/// Maelstrom's error code for a `cas` whose `from` did not match.
+pub const PRECONDITION_FAILED: u64 = MaelstromError::PreconditionFailed.code();
+
+/// How many times [`KvClient::lww_write`] retries a `cas` that lost a race.
+const LWW_ATTEMPTS: u32 = 10;
+
+payload! {
+    #[derive(Debug, Clone)]
+    pub enum KvPayload {
+        Read = "read" { key: Value },
+        ReadOk = "read_ok" { value: Value },
+        Write = "write" { key: Value, value: Value },
+        WriteOk = "write_ok",
+        Cas = "cas" { key: Value, from: Value, to: Value, create_if_not_exists: Option<bool> },
+        CasOk = "cas_ok",
+        Error = "error" { code: u64, text: String },
+    }
+}
+
+/// An `error` reply from the KV service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvError {
+    pub code: u64,
+    pub text: String,
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KV error {}: {}", self.code, self.text)
+    }
+}
+
+impl std::error::Error for KvError {}
+
+pub struct KvClient {
+    node_id: String,
+    service: String,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub fn new(node_id: impl Into<String>, service: impl Into<String>) -> Self {
+        KvClient {
+            node_id: node_id.into(),
+            service: service.into(),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub fn seq(node_id: impl Into<String>) -> Self {
+        Self::new(node_id, "seq-kv")
+    }
+
+    pub fn lin(node_id: impl Into<String>) -> Self {
+        Self::new(node_id, "lin-kv")
+    }
+
+    pub fn lww(node_id: impl Into<String>) -> Self {
+        Self::new(node_id, "lww-kv")
+    }
+
+    /// How long to wait for the service to reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Reads `key`, returning `None` if it does not exist.
+    ///
+    /// The value comes back as the JSON it was written as: it is not
+    /// converted, so an array stays an array and `3.0` stays a float.
+    pub fn read(&self, key: &str, output: &mut Outbox) -> Result<Option<Value>> {
+        let mut values = self.read_many(&[key.to_string()], output)?;
+        Ok(values.remove(key).flatten())
+    }
+
+    /// Replaces the value of `key` with `to` if it currently is `from`, and
+    /// returns whether it did. With `create_if_not_exists`, a missing key is
+    /// created with `to`.
+    pub fn cas(
+        &self,
+        key: &str,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+        output: &mut Outbox,
+    ) -> Result<bool> {
+        let reply = self
+            .call(
+                KvPayload::Cas {
+                    key: Value::String(key.to_string()),
+                    from,
+                    to,
+                    create_if_not_exists: Some(create_if_not_exists),
+                },
+                output,
+            )
+            .with_context(|| format!("cas {key:?}"))?;
+        match reply {
+            KvPayload::CasOk => Ok(true),
+            KvPayload::Error { code, .. } if code == PRECONDITION_FAILED => Ok(false),
+            KvPayload::Error { code, text } => {
+                Err(KvError { code, text }).with_context(|| format!("cas {key:?}"))
+            }
+            other => crate::bail!("unexpected reply to cas {key:?}: {other:?}"),
+        }
+    }
+
+    /// Sets `key` to `value`.
+    pub fn write(&self, key: &str, value: Value, output: &mut Outbox) -> Result<()> {
+        let reply = self
+            .call(
+                KvPayload::Write {
+                    key: Value::String(key.to_string()),
+                    value,
+                },
+                output,
+            )
+            .with_context(|| format!("write {key:?}"))?;
+        match reply {
+            KvPayload::WriteOk => Ok(()),
+            KvPayload::Error { code, text } => {
+                Err(KvError { code, text }).with_context(|| format!("write {key:?}"))
+            }
+            other => crate::bail!("unexpected reply to write {key:?}: {other:?}"),
+        }
+    }
+
+    /// Writes a large collection as chunks of at most `chunk_len` items under
+    /// `key#0`, `key#1`, ..., followed by the number of chunks under
+    /// `key#count`. Read it back with [`KvClient::read_chunked`].
+    ///
+    /// The count is written last, so a reader never expects chunks that have
+    /// not been written yet. Concurrent writers of the same key can still
+    /// interleave their chunks. Fails without writing anything if
+    /// `chunk_len` is 0.
+    pub fn write_chunked(
+        &self,
+        key: &str,
+        items: &[Value],
+        chunk_len: usize,
+        output: &mut Outbox,
+    ) -> Result<()> {
+        if chunk_len == 0 {
+            crate::bail!("cannot write {key:?} in chunks of 0 items");
+        }
+        let chunks = items.chunks(chunk_len);
+        let count = chunks.len();
+        for (i, chunk) in chunks.enumerate() {
+            self.write(&chunk_key(key, i), Value::Array(chunk.to_vec()), output)?;
+        }
+        self.write(&format!("{key}#count"), Value::Int(count as i128), output)
+    }
+
+    /// Reads a collection written by [`KvClient::write_chunked`] and
+    /// reassembles it, returning `None` if it was never written. A chunk that
+    /// is missing or not an array is an error.
+    pub fn read_chunked(&self, key: &str, output: &mut Outbox) -> Result<Option<Vec<Value>>> {
+        let count_key = format!("{key}#count");
+        let Some(count) = self.read(&count_key, output)? else {
+            return Ok(None);
+        };
+        let count = count
+            .as_u64()
+            .with_context(|| format!("{count_key} is not a chunk count: {count}"))?;
+        let keys: Vec<String> = (0..count as usize).map(|i| chunk_key(key, i)).collect();
+        let mut chunks = self.read_many(&keys, output)?;
+        let mut items = Vec::new();
+        for chunk_key in &keys {
+            match chunks.remove(chunk_key).flatten() {
+                Some(Value::Array(chunk)) => items.extend(chunk),
+                Some(other) => crate::bail!("chunk {chunk_key} is not an array: {other}"),
+                None => crate::bail!("chunk {chunk_key} of {count} is missing"),
+            }
+        }
+        Ok(Some(items))
+    }
+
+    /// Sets `key` to `value` unless it already holds a value written with a
+    /// later `timestamp`, so the latest timestamp wins whatever order the
+    /// writes arrive in. Returns whether this write was applied.
+    ///
+    /// The value is stored as `{"timestamp": ..., "value": ...}` and replaced
+    /// with a `cas`; read it back with [`KvClient::lww_read`]. On a tie, the
+    /// write that arrived first is kept.
+    pub fn lww_write(
+        &self,
+        key: &str,
+        value: Value,
+        timestamp: u64,
+        output: &mut Outbox,
+    ) -> Result<bool> {
+        let mut entry = crate::json::Map::new();
+        entry.insert("timestamp".to_string(), Value::Int(timestamp.into()));
+        entry.insert("value".to_string(), value);
+        let to = Value::Object(entry);
+        for _ in 0..LWW_ATTEMPTS {
+            let current = self.read(key, output)?;
+            if let Some(current) = &current {
+                let (_, current_timestamp) = lww_entry(key, current)?;
+                if current_timestamp >= timestamp {
+                    return Ok(false);
+                }
+            }
+            let from = current.unwrap_or_default();
+            if self.cas(key, from, to.clone(), true, output)? {
+                return Ok(true);
+            }
+        }
+        crate::bail!("could not write {key:?} in {LWW_ATTEMPTS} attempts")
+    }
+
+    /// Reads a value written by [`KvClient::lww_write`] along with its
+    /// timestamp, returning `None` if it does not exist.
+    pub fn lww_read(&self, key: &str, output: &mut Outbox) -> Result<Option<(Value, u64)>> {
+        let Some(entry) = self.read(key, output)? else {
+            return Ok(None);
+        };
+        let (value, timestamp) = lww_entry(key, &entry)?;
+        Ok(Some((value.clone(), timestamp)))
+    }
+
+    /// Sends one request to the service and waits for the reply.
+    fn call(&self, payload: KvPayload, output: &mut Outbox) -> Result<KvPayload> {
+        let reply = output.request(&self.node_id, &self.service, payload)?;
+        let reply = reply
+            .recv_timeout(self.timeout)
+            .with_context(|| format!("{} did not answer", self.service))?
+            .decode::<KvPayload>()?;
+        Ok(reply.body.payload)
+    }
+
+    /// Reads several keys in one round trip: all reads are sent before any
+    /// reply is awaited.
+    ///
+    /// Keys that do not exist map to `None`; any other error fails the whole
+    /// call.
+    pub fn read_many(
+        &self,
+        keys: &[String],
+        output: &mut Outbox,
+    ) -> Result<HashMap<String, Option<Value>>> {
+        let mut pending = Vec::with_capacity(keys.len());
+        for key in keys {
+            let reply = output.request(
+                &self.node_id,
+                &self.service,
+                KvPayload::Read {
+                    key: Value::String(key.clone()),
+                },
+            )?;
+            pending.push((key, reply));
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut values = HashMap::with_capacity(keys.len());
+        for (key, reply) in pending {
+            let reply = reply
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .with_context(|| format!("{} did not answer read of {key:?}", self.service))?
+                .decode::<KvPayload>()?;
+            let value = match reply.body.payload {
+                KvPayload::ReadOk { value } => Some(value),
+                KvPayload::Error { code, .. } if code == KEY_DOES_NOT_EXIST => None,
+                KvPayload::Error { code, text } => {
+                    return Err(KvError { code, text }).with_context(|| format!("read {key:?}"))
+                }
+                other => crate::bail!("unexpected reply to read {key:?}: {other:?}"),
+            };
+            values.insert(key.clone(), value);
+        }
+        Ok(values)
+    }
+}
+
+/// Splits a stored `lww_write` entry into its value and timestamp.
+fn lww_entry<'a>(key: &str, entry: &'a Value) -> Result<(&'a Value, u64)> {
+    let timestamp = entry.get("timestamp").and_then(Value::as_u64);
+    match (entry.get("value"), timestamp) {
+        (Some(value), Some(timestamp)) => Ok((value, timestamp)),
+        _ => crate::bail!("{key:?} does not hold a timestamped value: {entry}"),
+    }
+}
+
+fn chunk_key(key: &str, index: usize) -> String {
+    format!("{key}#{index}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::json;
+    use crate::test_support::{serving_outbox, KvService, SharedBuffer};
+
+    /// An outbox whose requests `service` answers.
+    fn outbox(service: &KvService) -> Outbox {
+        let services = Arc::new(Mutex::new(vec![service.clone()]));
+        serving_outbox(SharedBuffer::new(), services)
+    }
+
+    #[test]
+    fn values_read_back_with_their_json_type() {
+        let lin_kv = KvService::new("lin-kv");
+        let mut output = outbox(&lin_kv);
+        let kv = KvClient::lin("n1");
+        let value = json::parse(r#"[1,"two",3.0,[4],{"five":null}]"#).unwrap();
+        kv.write("k", value.clone(), &mut output).unwrap();
+        assert_eq!(lin_kv.get("k"), Some(value.clone()), "as stored");
+        let read = kv.read("k", &mut output).unwrap().expect("k was written");
+        // Equal only if every element kept its type, 3.0 included.
+        assert_eq!(read, value);
+        assert_eq!(read.to_string(), r#"[1,"two",3.0,[4],{"five":null}]"#);
+    }
+
+    #[test]
+    fn read_many_marks_missing_keys_absent() {
+        // Answering only once all three reads are in, which they are if
+        // they were sent before any reply was awaited.
+        let lin_kv = KvService::new("lin-kv").answer_in_batches_of(3);
+        lin_kv.insert("a", Value::Int(1));
+        lin_kv.insert("c", Value::String("three".to_string()));
+        let mut output = outbox(&lin_kv);
+        let keys = ["a", "b", "c"].map(str::to_string);
+        let values = KvClient::lin("n1").read_many(&keys, &mut output).unwrap();
+        let expected = HashMap::from([
+            ("a".to_string(), Some(Value::Int(1))),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(Value::String("three".to_string()))),
+        ]);
+        assert_eq!(values, expected);
+        assert_eq!(lin_kv.requests().len(), 3);
+    }
+
+    #[test]
+    fn the_later_lww_write_wins_in_either_order() {
+        let early = (Value::String("early".to_string()), 1);
+        let late = (Value::String("late".to_string()), 2);
+        for order in [[&early, &late], [&late, &early]] {
+            let lww_kv = KvService::new("lww-kv");
+            let mut output = outbox(&lww_kv);
+            let kv = KvClient::lww("n1");
+            let applied: Vec<bool> = order
+                .iter()
+                .map(|(value, timestamp)| {
+                    kv.lww_write("k", value.clone(), *timestamp, &mut output)
+                        .unwrap()
+                })
+                .collect();
+            // Only a write later than what is stored is applied.
+            assert_eq!(applied, [true, order[1].1 == 2]);
+            assert_eq!(kv.lww_read("k", &mut output).unwrap(), Some(late.clone()));
+        }
+    }
+
+    #[test]
+    fn lww_writes_with_the_same_timestamp_keep_the_first() {
+        let lww_kv = KvService::new("lww-kv");
+        let mut output = outbox(&lww_kv);
+        let kv = KvClient::lww("n1");
+        assert_eq!(kv.lww_read("k", &mut output).unwrap(), None);
+        assert!(kv.lww_write("k", Value::Int(1), 5, &mut output).unwrap());
+        assert!(!kv.lww_write("k", Value::Int(2), 5, &mut output).unwrap());
+        assert_eq!(
+            kv.lww_read("k", &mut output).unwrap(),
+            Some((Value::Int(1), 5))
+        );
+    }
+
+    #[test]
+    fn chunked_values_read_back_intact() {
+        let seq_kv = KvService::new("seq-kv");
+        let mut output = outbox(&seq_kv);
+        let kv = KvClient::seq("n1");
+        let items: Vec<Value> = (0..7).map(Value::Int).collect();
+        kv.write_chunked("k", &items, 3, &mut output).unwrap();
+        assert_eq!(seq_kv.get("k#count"), Some(Value::Int(3)));
+        assert_eq!(seq_kv.get("k#2"), Some(Value::Array(vec![Value::Int(6)])));
+        assert_eq!(kv.read_chunked("k", &mut output).unwrap(), Some(items));
+        assert_eq!(kv.read_chunked("other", &mut output).unwrap(), None);
+
+        // A chunk missing in the middle is an error, not a shorter value.
+        seq_kv.insert("gap#0", Value::Array(vec![Value::Int(0)]));
+        seq_kv.insert("gap#2", Value::Array(vec![Value::Int(2)]));
+        seq_kv.insert("gap#count", Value::Int(3));
+        let error = kv.read_chunked("gap", &mut output).unwrap_err();
+        assert!(error.to_string().contains("gap#1"), "{error}");
+    }
+
+    #[test]
+    fn chunks_of_no_items_are_an_error() {
+        let seq_kv = KvService::new("seq-kv");
+        let mut output = outbox(&seq_kv);
+        let items = [Value::Int(1)];
+        let written = KvClient::seq("n1").write_chunked("k", &items, 0, &mut output);
+        assert!(written.is_err());
+        assert!(seq_kv.requests().is_empty(), "something was written");
+    }
+}
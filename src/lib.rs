@@ -0,0 +1,401 @@
+//! A small runtime for writing [Maelstrom](https://github.com/jepsen-io/maelstrom)
+//! nodes for the fly.io distributed systems challenges.
+//!
+//! A node implements [`Node`] and hands itself to [`main_loop`], which performs
+//! the `init` handshake, reads messages from stdin and writes replies to
+//! stdout.
+
+mod base64;
+pub mod crdt;
+mod dedup;
+pub mod error;
+pub mod failover;
+pub mod gossip;
+pub mod gzip;
+pub mod json;
+pub mod kv;
+pub mod partition;
+pub mod persist;
+mod rand;
+mod rpc;
+mod runtime;
+mod sha1;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod time;
+pub mod txn;
+pub mod uuid;
+
+use std::sync::mpsc::Sender;
+
+pub use error::{Context, Error, Result};
+use json::{FromJson, Map, ToJson, Value};
+pub use rpc::PendingReply;
+pub use runtime::{
+    main_loop, main_loop_with_config, main_loop_with_persistence, run, run_with_persistence,
+    spawn_injector, Config, Inbound, LineEnding, MessageTooLarge, OnOversize, Outbox, Overloaded,
+    Retrier, RetryDue, SendHook, Stats, TimedOut, Timer, TimerId, MAX_FORWARDED,
+};
+
+/// A message payload: the contents of a message body besides its ids.
+///
+/// Usually implemented with the [`payload!`] macro.
+pub trait Payload: ToJson + FromJson {
+    /// The Maelstrom message type, i.e. the body's `"type"` field.
+    fn kind(&self) -> &str;
+
+    /// The fields a message of type `kind` has besides `type`, `msg_id` and
+    /// `in_reply_to`, or `None` if any are accepted. Used by
+    /// [`Config::strict_fields`].
+    fn known_fields(kind: &str) -> Option<&'static [&'static str]>
+    where
+        Self: Sized,
+    {
+        let _ = kind;
+        None
+    }
+}
+
+impl Payload for Value {
+    fn kind(&self) -> &str {
+        self.get("type").and_then(Value::as_str).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Message<Payload> {
+    pub src: String,
+    pub dest: String,
+    pub body: Body<Payload>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Body<Payload> {
+    pub id: Option<usize>,
+    pub in_reply_to: Option<usize>,
+    pub payload: Payload,
+}
+
+impl<P> Message<P> {
+    /// Turns a request into a reply addressed back to its sender, without a
+    /// `msg_id` of its own. [`Outbox::reply`] gives replies one from the
+    /// runtime's counter, which no other message's id can collide with.
+    pub fn into_reply(self) -> Self {
+        if self.body.id.is_none() {
+            eprintln!(
+                "warning: replying to a message from {} without a msg_id; \
+                 the reply cannot be correlated",
+                self.src
+            );
+        }
+        Self {
+            src: self.dest,
+            dest: self.src,
+            body: Body {
+                id: None,
+                in_reply_to: self.body.id,
+                payload: self.body.payload,
+            },
+        }
+    }
+}
+
+impl<P: ToJson> Message<P> {
+    /// Queues the message on `output`.
+    ///
+    /// Fails with [`InvalidMessage`] if `src` or `dest` is empty, since
+    /// Maelstrom cannot route such a message.
+    pub fn send(&self, output: &mut Outbox) -> Result<()> {
+        if self.src.is_empty() {
+            return Err(InvalidMessage("empty src").into());
+        }
+        if self.dest.is_empty() {
+            return Err(InvalidMessage("empty dest").into());
+        }
+        if self.body.in_reply_to == Some(0) {
+            // Maelstrom msg_ids are positive, so the request we are replying
+            // to was malformed. Send the reply anyway and let the client sort
+            // it out.
+            eprintln!(
+                "warning: message to {} has in_reply_to 0, msg_ids should be positive",
+                self.dest
+            );
+        }
+        output.push(Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload: self.body.payload.to_json(),
+            },
+        })
+    }
+}
+
+/// A message that cannot be sent as-is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidMessage(pub &'static str);
+
+impl std::fmt::Display for InvalidMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid message: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMessage {}
+
+impl<P: ToJson> ToJson for Message<P> {
+    fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("src".to_string(), self.src.to_json());
+        map.insert("dest".to_string(), self.dest.to_json());
+        map.insert("body".to_string(), self.body.to_json());
+        Value::Object(map)
+    }
+}
+
+impl<P: FromJson> FromJson for Message<P> {
+    fn from_json(value: &Value) -> std::result::Result<Self, json::Error> {
+        let map = value
+            .as_object()
+            .ok_or_else(|| json::Error::new("message is not an object"))?;
+        Ok(Message {
+            src: json::field(map, "src")?,
+            dest: json::field(map, "dest")?,
+            body: json::field(map, "body")?,
+        })
+    }
+}
+
+impl<P: ToJson> ToJson for Body<P> {
+    fn to_json(&self) -> Value {
+        let mut value = self.payload.to_json();
+        if let Some(map) = value.as_object_mut() {
+            json::insert_field(map, "msg_id", &self.id);
+            json::insert_field(map, "in_reply_to", &self.in_reply_to);
+        }
+        value
+    }
+}
+
+impl<P: FromJson> FromJson for Body<P> {
+    fn from_json(value: &Value) -> std::result::Result<Self, json::Error> {
+        let map = value
+            .as_object()
+            .ok_or_else(|| json::Error::new("body is not an object"))?;
+        Ok(Body {
+            id: json::field(map, "msg_id")?,
+            in_reply_to: json::field(map, "in_reply_to")?,
+            payload: P::from_json(value)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Init {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+    /// Any other fields of the `init` body, for extensions Maelstrom (or a
+    /// test harness) may add.
+    pub extra: Map,
+}
+
+/// What a node handles: a message, an event it injected into its own loop,
+/// or the end of the input.
+///
+/// `InjectedPayload` is the node's [`Node::Injected`] type. It can carry
+/// data, e.g. a timer tick numbered by its round; the `heartbeat` example
+/// shows one.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+pub enum Event<Payload, InjectedPayload = ()> {
+    Message(Message<Payload>),
+    /// Sent through the `inject` sender handed to [`Node::from_init`], and
+    /// handled by [`Node::on_injected`].
+    Injected(InjectedPayload),
+    EOF,
+    /// Sent by [`spawn_injector`] when a thread it runs has panicked more
+    /// often than it may be restarted. The runtime stops with an error
+    /// instead of handing it to the node.
+    InjectorFailed(String),
+}
+
+/// Maelstrom's standard error codes, sent with [`Outbox::reply_error`].
+///
+/// Codes below 1000 are Maelstrom's own. Whether an operation may have
+/// happened despite the error depends on the code: [`MaelstromError::Timeout`]
+/// and [`MaelstromError::Crash`] leave it unknown, the others mean it did
+/// not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MaelstromError {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl MaelstromError {
+    const ALL: [MaelstromError; 11] = [
+        MaelstromError::Timeout,
+        MaelstromError::NodeNotFound,
+        MaelstromError::NotSupported,
+        MaelstromError::TemporarilyUnavailable,
+        MaelstromError::MalformedRequest,
+        MaelstromError::Crash,
+        MaelstromError::Abort,
+        MaelstromError::KeyDoesNotExist,
+        MaelstromError::KeyAlreadyExists,
+        MaelstromError::PreconditionFailed,
+        MaelstromError::TxnConflict,
+    ];
+
+    /// The numeric `code` of the error body.
+    pub const fn code(self) -> u64 {
+        match self {
+            MaelstromError::Timeout => 0,
+            MaelstromError::NodeNotFound => 1,
+            MaelstromError::NotSupported => 10,
+            MaelstromError::TemporarilyUnavailable => 11,
+            MaelstromError::MalformedRequest => 12,
+            MaelstromError::Crash => 13,
+            MaelstromError::Abort => 14,
+            MaelstromError::KeyDoesNotExist => 20,
+            MaelstromError::KeyAlreadyExists => 21,
+            MaelstromError::PreconditionFailed => 22,
+            MaelstromError::TxnConflict => 30,
+        }
+    }
+
+    /// The standard error with `code`, if it is one.
+    pub fn from_code(code: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|error| error.code() == code)
+    }
+}
+
+impl std::fmt::Display for MaelstromError {
+    /// Maelstrom's name for the error, e.g. `key-does-not-exist`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MaelstromError::Timeout => "timeout",
+            MaelstromError::NodeNotFound => "node-not-found",
+            MaelstromError::NotSupported => "not-supported",
+            MaelstromError::TemporarilyUnavailable => "temporarily-unavailable",
+            MaelstromError::MalformedRequest => "malformed-request",
+            MaelstromError::Crash => "crash",
+            MaelstromError::Abort => "abort",
+            MaelstromError::KeyDoesNotExist => "key-does-not-exist",
+            MaelstromError::KeyAlreadyExists => "key-already-exists",
+            MaelstromError::PreconditionFailed => "precondition-failed",
+            MaelstromError::TxnConflict => "txn-conflict",
+        })
+    }
+}
+
+impl Message<Value> {
+    /// Interprets the body as a `P` payload.
+    pub fn decode<P: FromJson>(self) -> std::result::Result<Message<P>, json::Error> {
+        Ok(Message {
+            src: self.src,
+            dest: self.dest,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload: P::from_json(&self.body.payload)?,
+            },
+        })
+    }
+}
+
+pub trait Node<S, Payload> {
+    /// Events the node injects into its own loop through the `inject` sender
+    /// it is handed in [`Node::from_init`], e.g. timer ticks.
+    type Injected: Send + 'static;
+
+    fn from_init(
+        state: S,
+        init: Init,
+        inject: Sender<Event<Payload, Self::Injected>>,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Handles messages and EOF. Injected events go to [`Node::on_injected`]
+    /// instead.
+    fn step(&mut self, input: Event<Payload, Self::Injected>, output: &mut Outbox) -> Result<()>;
+
+    fn on_injected(&mut self, payload: Self::Injected, output: &mut Outbox) -> Result<()> {
+        let _ = (payload, output);
+        Ok(())
+    }
+
+    /// Whether an injected event is dropped when another one is already
+    /// queued behind it, so that ticks that piled up while the node was busy
+    /// fire once rather than in a burst. Only suitable when injected events
+    /// are interchangeable, like timer ticks.
+    fn coalesce_injected(&self) -> bool {
+        false
+    }
+
+    /// The node's state, for [`Config::checkpoint`] to write to disk. The
+    /// default, `None`, writes nothing.
+    fn snapshot(&self) -> Option<Value> {
+        None
+    }
+
+    /// Takes back the state an earlier run saved with [`Node::snapshot`].
+    /// Called after `init`, before [`Node::on_init_complete`].
+    fn restore(&mut self, snapshot: Value) -> Result<()> {
+        let _ = snapshot;
+        Ok(())
+    }
+
+    /// The state for [`main_loop_with_persistence`] to save at EOF, which the
+    /// next run gets back in [`Node::from_init`]. The default, `None`, saves
+    /// nothing.
+    fn persistent_state(&self) -> Option<S> {
+        None
+    }
+
+    /// Called once `init_ok` has been written, before any other event is
+    /// handled, for work that must come after the handshake such as
+    /// announcing the node to its peers.
+    fn on_init_complete(&mut self, output: &mut Outbox) -> Result<()> {
+        let _ = output;
+        Ok(())
+    }
+
+    /// Checks the node's own consistency, e.g. that a counter never went
+    /// down. In debug builds the runtime calls it after every event and
+    /// stops with the returned message if it fails.
+    fn check_invariants(&self) -> std::result::Result<(), String> {
+        Ok(())
+    }
+
+    /// Fields to add to the `init_ok` reply, e.g. to report capabilities to a
+    /// harness that understands them. Must be an object.
+    fn init_ok_extra(&self) -> Option<Value> {
+        None
+    }
+
+    /// A hook every message the node sends passes through just before it is
+    /// serialized, e.g. to attach trace metadata: `init_ok`, replies and
+    /// requests whenever they are written out, and the messages the runtime
+    /// sends for the node, such as error replies and forwarded messages.
+    /// Asked for once, right after [`Node::from_init`].
+    ///
+    /// It is a separate function rather than a `&mut self` method because
+    /// the runtime's stdin thread sends some of those messages while the
+    /// node is busy in [`Node::step`], so the hook cannot borrow the node.
+    /// State it needs, like a trace id counter, goes into the closure.
+    fn send_hook(&self) -> Option<SendHook> {
+        None
+    }
+}
@@ -0,0 +1,73 @@
+//! Assigning keys to partitions, and partitions to nodes, the same way on
+//! every node, e.g. for a partitioned `kafka` where each key's log is kept
+//! by one owner.
+
+/// A fixed number of partitions spread over the cluster's nodes.
+///
+/// Partition `i` is owned by the `i`-th node in sorted order, wrapping
+/// around, and a key's partition comes from a hash of the key that does not
+/// depend on the process or the build. So every node that got the same
+/// `node_ids`, in whatever order, agrees on every key's owner without
+/// talking to the others.
+///
+/// ```
+/// use gossip_glomers_rs::partition::Partitions;
+///
+/// let nodes = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+/// let n1 = Partitions::new(&nodes(&["n1", "n2", "n3"]), 12).unwrap();
+/// let n3 = Partitions::new(&nodes(&["n3", "n1", "n2"]), 12).unwrap();
+///
+/// let keys: Vec<String> = (0..1200).map(|i| format!("k{i}")).collect();
+/// let mut per_partition = [0; 12];
+/// for key in &keys {
+///     assert_eq!(n1.partition_owner(key), n3.partition_owner(key));
+///     per_partition[n1.partition(key)] += 1;
+/// }
+/// // 100 keys per partition if spread perfectly.
+/// assert!(per_partition.iter().all(|&keys| (70..=130).contains(&keys)));
+///
+/// assert!(Partitions::new(&[], 12).is_none());
+/// assert!(Partitions::new(&nodes(&["n1"]), 0).is_none());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Partitions {
+    /// The nodes, sorted.
+    nodes: Vec<String>,
+    count: usize,
+}
+
+impl Partitions {
+    /// `count` partitions over `node_ids`, as Maelstrom sent them in `init`,
+    /// or `None` if there are no nodes or no partitions.
+    pub fn new(node_ids: &[String], count: usize) -> Option<Self> {
+        if node_ids.is_empty() || count == 0 {
+            return None;
+        }
+        let mut nodes = node_ids.to_vec();
+        nodes.sort();
+        nodes.dedup();
+        Some(Partitions { nodes, count })
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The partition `key` belongs to, below [`count`](Self::count).
+    pub fn partition(&self, key: &str) -> usize {
+        (fnv1a(key.as_bytes()) % self.count as u64) as usize
+    }
+
+    /// The node that owns `key`'s partition.
+    pub fn partition_owner(&self, key: &str) -> &str {
+        &self.nodes[self.partition(key) % self.nodes.len()]
+    }
+}
+
+/// The 64-bit FNV-1a hash. Unlike `std`'s hashers, it is the same wherever
+/// and whenever it runs.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
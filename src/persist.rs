@@ -0,0 +1,106 @@
+//! Node state that outlives the process, for workloads that restart nodes.
+//!
+//! [`run_with_persistence`](crate::run_with_persistence) loads the state
+//! before [`Node::from_init`](crate::Node::from_init) and saves
+//! [`Node::persistent_state`](crate::Node::persistent_state) at EOF.
+
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::json::{self, FromJson, ToJson};
+use crate::{Context, Result};
+
+/// Where a node's state of type `S` is kept between runs.
+pub trait StatePersistence<S> {
+    fn save(&self, state: &S) -> Result<()>;
+
+    /// The state saved last, or `None` if nothing has been saved yet.
+    fn load(&self) -> Result<Option<S>>;
+}
+
+/// Keeps the state as JSON in a file.
+///
+/// Like the [`UUIDGenerator`](crate::uuid::UUIDGenerator)'s state file, the
+/// state is locked while it is read or written, so processes sharing it
+/// never see a partial write. The lock is held on a `.lock` file next to it,
+/// and the state is written next to the file and then renamed over it, so a
+/// crash mid-write leaves the previous state intact.
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// use gossip_glomers_rs::persist::{FileState, StatePersistence};
+///
+/// let path = std::env::temp_dir().join(format!("state-doc-{}", std::process::id()));
+/// let persistence = FileState::new(&path);
+/// assert_eq!(StatePersistence::<BTreeMap<String, u64>>::load(&persistence)?, None);
+///
+/// let state = BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+/// persistence.save(&state)?;
+/// assert_eq!(persistence.load()?, Some(state));
+/// # std::fs::remove_file(&path).ok();
+/// # std::fs::remove_file(path.with_extension("lock")).ok();
+/// # Ok::<(), gossip_glomers_rs::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileState {
+    path: PathBuf,
+}
+
+impl FileState {
+    /// State kept in the file at `path`, which is created on the first save.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileState { path: path.into() }
+    }
+
+    /// Opens the lock file, creating it if need be.
+    fn lock_file(&self) -> Result<File> {
+        let path = self.sibling("lock");
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("cannot open lock file {}", path.display()))
+    }
+
+    /// The state file's path with `extension` added.
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(extension);
+        path.into()
+    }
+}
+
+impl<S: ToJson + FromJson> StatePersistence<S> for FileState {
+    fn save(&self, state: &S) -> Result<()> {
+        let lock = self.lock_file()?;
+        lock.lock().context("cannot lock state file")?;
+        let tmp = self.sibling("tmp");
+        let written = std::fs::write(&tmp, state.to_json().to_string())
+            .and_then(|()| File::open(&tmp)?.sync_all())
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+        lock.unlock().context("cannot unlock state file")?;
+        written.with_context(|| format!("cannot write state file {}", self.path.display()))
+    }
+
+    fn load(&self) -> Result<Option<S>> {
+        let lock = self.lock_file()?;
+        lock.lock_shared().context("cannot lock state file")?;
+        let read = std::fs::read_to_string(&self.path);
+        lock.unlock().context("cannot unlock state file")?;
+        let text = match read {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("cannot read state file {}", self.path.display()))
+            }
+        };
+        let value = json::parse(&text).context("state file is not JSON")?;
+        let state = S::from_json(&value).context("state file does not hold the node's state")?;
+        Ok(Some(state))
+    }
+}
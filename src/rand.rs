@@ -0,0 +1,20 @@
+//! Just enough randomness for picking gossip peers and similar choices; not
+//! suitable for anything security-sensitive.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a pseudo-random `u64`, seeded differently in every process.
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// Returns a pseudo-random index in `0..len`. `len` must be non-zero.
+pub(crate) fn random_index(len: usize) -> usize {
+    (random_u64() % len as u64) as usize
+}
@@ -0,0 +1,208 @@
+//! Correlating replies with the requests a node sent.
+//!
+//! Requests are registered under their destination and `msg_id`; when a
+//! message with a matching `in_reply_to` arrives, the stdin reader hands it to
+//! the waiting requester instead of to [`Node::step`](crate::Node::step).
+//! Once the requester stops waiting, the registration is dropped, and a
+//! reply that arrives after that goes to `Node::step` like any other message.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+use crate::json::{FromJson, Value};
+use crate::{bail, Message, Result};
+
+/// The task to wake once a reply arrives, set by the last poll.
+pub(crate) type WakerSlot = Arc<Mutex<Option<Waker>>>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: usize,
+    waiting: HashMap<(String, usize), Waiter>,
+}
+
+struct Waiter {
+    tx: Sender<Message<Value>>,
+    waker: WakerSlot,
+}
+
+impl Registry {
+    /// Allocates a `msg_id` that is not used by any request.
+    pub(crate) fn next_id(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        inner.next_id
+    }
+
+    /// Allocates a `msg_id` for a request to `dest` and returns the receiver
+    /// its reply will be delivered to.
+    pub(crate) fn register(&self, dest: &str) -> Reply {
+        let (ticket, rx, _) = self.register_waker(dest);
+        Reply { rx, ticket }
+    }
+
+    /// Like [`Registry::register`], also returning the slot for the waker
+    /// that is woken once the reply has been delivered.
+    pub(crate) fn register_waker(
+        &self,
+        dest: &str,
+    ) -> (Ticket, Receiver<Message<Value>>, WakerSlot) {
+        let (tx, rx) = mpsc::channel();
+        let waker = WakerSlot::default();
+        let ticket = self.register_with(dest, tx, waker.clone());
+        (ticket, rx, waker)
+    }
+
+    /// Allocates a `msg_id` for a request to `dest` whose reply is sent to
+    /// `tx`, so that several requests can share one receiver.
+    pub(crate) fn register_with(
+        &self,
+        dest: &str,
+        tx: Sender<Message<Value>>,
+        waker: WakerSlot,
+    ) -> Ticket {
+        let key = (dest.to_string(), self.next_id());
+        self.inner
+            .lock()
+            .unwrap()
+            .waiting
+            .insert(key.clone(), Waiter { tx, waker });
+        Ticket {
+            registry: self.clone(),
+            key,
+        }
+    }
+
+    /// How many requests are waiting for a reply.
+    #[cfg(test)]
+    pub(crate) fn waiting(&self) -> usize {
+        self.inner.lock().unwrap().waiting.len()
+    }
+
+    /// Delivers `message` to its requester if it answers a pending request,
+    /// otherwise hands it back.
+    pub(crate) fn complete(&self, message: Message<Value>) -> Option<Message<Value>> {
+        let Some(in_reply_to) = message.body.in_reply_to else {
+            return Some(message);
+        };
+        let waiting = self
+            .inner
+            .lock()
+            .unwrap()
+            .waiting
+            .remove(&(message.src.clone(), in_reply_to));
+        match waiting {
+            Some(waiter) => {
+                // The requester may have given up waiting; that is fine.
+                let _ = waiter.tx.send(message);
+                if let Some(waker) = waiter.waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// A request's registration, dropped together with this.
+pub(crate) struct Ticket {
+    registry: Registry,
+    key: (String, usize),
+}
+
+impl Ticket {
+    /// The request's `msg_id`.
+    pub(crate) fn id(&self) -> usize {
+        self.key.1
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        // Already gone if the reply was delivered.
+        if let Ok(mut inner) = self.registry.inner.lock() {
+            inner.waiting.remove(&self.key);
+        }
+    }
+}
+
+/// Where the reply to a request sent with `Outbox::request` is delivered.
+pub(crate) struct Reply {
+    rx: Receiver<Message<Value>>,
+    ticket: Ticket,
+}
+
+impl Reply {
+    /// The request's `msg_id`.
+    pub(crate) fn id(&self) -> usize {
+        self.ticket.id()
+    }
+
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<Message<Value>, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+/// The reply to a request sent with [`Outbox::rpc`](crate::Outbox::rpc).
+///
+/// It is a [`Future`] that resolves once the reply arrives, for nodes that
+/// drive their requests with an executor; [`PendingReply::wait`] blocks for
+/// it instead. Either way, the reply is decoded as a `P`.
+pub struct PendingReply<P> {
+    rx: Receiver<Message<Value>>,
+    waker: WakerSlot,
+    _ticket: Ticket,
+    payload: PhantomData<fn() -> P>,
+}
+
+impl<P: FromJson> PendingReply<P> {
+    pub(crate) fn new(rx: Receiver<Message<Value>>, waker: WakerSlot, ticket: Ticket) -> Self {
+        PendingReply {
+            rx,
+            waker,
+            _ticket: ticket,
+            payload: PhantomData,
+        }
+    }
+
+    /// Blocks until the reply arrives, failing after `timeout`.
+    pub fn wait(self, timeout: Duration) -> Result<Message<P>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply.decode()?),
+            Err(RecvTimeoutError::Timeout) => bail!("no reply within {timeout:?}"),
+            Err(RecvTimeoutError::Disconnected) => bail!("the runtime stopped before the reply"),
+        }
+    }
+}
+
+impl<P: FromJson> Future for PendingReply<P> {
+    type Output = Result<Message<P>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        // The waker is stored before checking for the reply, so a reply
+        // delivered in between still wakes the task.
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.rx.try_recv() {
+            Ok(reply) => Poll::Ready(reply.decode().map_err(Into::into)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(crate::format_err!(
+                "the runtime stopped before the reply"
+            ))),
+        }
+    }
+}
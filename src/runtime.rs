@@ -0,0 +1,3446 @@
+//! The event loop that drives a [`Node`]: the `init` handshake, reading
+//! messages from the input, and writing what the node sends to the output.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::dedup::ReplyCache;
+use crate::json::{self, FromJson, ToJson, Value};
+use crate::persist::StatePersistence;
+use crate::rpc::PendingReply;
+use crate::time::{SystemClock, TimeSource};
+use crate::{
+    bail, payload, rpc, Body, Context, Error, Event, Init, MaelstromError, Message, Node, Payload,
+    Result,
+};
+
+/// How many steps the main loop may leave their output buffered while more
+/// input is waiting, before flushing anyway.
+const MAX_UNFLUSHED_STEPS: usize = 64;
+
+payload! {
+    #[derive(Debug, Clone)]
+    enum InitPayload {
+        Init = "init" { node_id: String, node_ids: Vec<String> },
+        InitOk = "init_ok",
+    }
+}
+
+/// What to do with a message larger than [`Config::max_message_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnOversize {
+    /// Refuse to send it: `send` returns a [`MessageTooLarge`] error.
+    Fail,
+    /// Log a warning and send it anyway.
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MessageSizeLimit {
+    bytes: usize,
+    on_exceed: OnOversize,
+}
+
+/// What [`Config::inbound_filter`] decides for a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inbound {
+    /// Hand the message on as usual.
+    Deliver,
+    /// Discard it, as if it was lost on the network.
+    Drop,
+    /// Hand it on after a while. Messages delayed past the end of the input
+    /// are lost.
+    Delay(Duration),
+}
+
+type FilterFn = dyn Fn(&Message<Value>) -> Inbound + Send + Sync;
+
+/// Rewrites each message a node sends just before it is serialized, see
+/// [`Node::send_hook`].
+pub type SendHook = Arc<dyn Fn(&mut Message<Value>) + Send + Sync>;
+
+#[derive(Clone)]
+struct InboundFilter(Arc<FilterFn>);
+
+impl std::fmt::Debug for InboundFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InboundFilter(..)")
+    }
+}
+
+/// How the lines written to the output are terminated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`, what Maelstrom expects.
+    #[default]
+    Lf,
+    /// `\r\n`, for harnesses that expect Windows line endings.
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// Runtime options for [`main_loop_with_config`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    max_message_size: Option<MessageSizeLimit>,
+    init_timeout: Duration,
+    line_ending: LineEnding,
+    coalesce_writes: bool,
+    log_message_rates: bool,
+    clock: Arc<dyn TimeSource>,
+    inbound_filter: Option<InboundFilter>,
+    json_log: Option<PathBuf>,
+    strict_fields: bool,
+    reply_cache: Option<(usize, Duration)>,
+    checkpoint: Option<(PathBuf, Duration)>,
+    retry_budget: Option<(usize, Duration)>,
+    shed_load: bool,
+    forward_unknown: Option<String>,
+    max_replies_per_sec: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_message_size: None,
+            init_timeout: Duration::from_secs(30),
+            line_ending: LineEnding::default(),
+            coalesce_writes: false,
+            log_message_rates: false,
+            clock: Arc::new(SystemClock),
+            inbound_filter: None,
+            json_log: None,
+            strict_fields: false,
+            reply_cache: None,
+            checkpoint: None,
+            retry_budget: None,
+            shed_load: false,
+            forward_unknown: None,
+            max_replies_per_sec: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait for Maelstrom's `init` message before giving up.
+    /// Defaults to 30 seconds.
+    pub fn init_timeout(mut self, timeout: Duration) -> Self {
+        self.init_timeout = timeout;
+        self
+    }
+
+    /// Guards against runaway payloads: messages whose serialized form is
+    /// longer than `bytes` are handled according to `on_exceed`. Disabled by
+    /// default.
+    pub fn max_message_size(mut self, bytes: usize, on_exceed: OnOversize) -> Self {
+        self.max_message_size = Some(MessageSizeLimit { bytes, on_exceed });
+        self
+    }
+
+    /// How to terminate output lines. Defaults to [`LineEnding::Lf`]; input
+    /// is accepted with either ending regardless.
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Writes all messages flushed together with a single `write_all`
+    /// instead of one per message, saving syscalls when a step sends many.
+    /// Off by default.
+    pub fn coalesce_writes(mut self, enabled: bool) -> Self {
+        self.coalesce_writes = enabled;
+        self
+    }
+
+    /// Logs how many messages per second were received and sent to stderr,
+    /// once a second. Off by default.
+    pub fn log_message_rates(mut self, enabled: bool) -> Self {
+        self.log_message_rates = enabled;
+        self
+    }
+
+    /// The clock timers such as [`Config::log_message_rates`] run on.
+    /// Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn TimeSource>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Runs every message after `init`, replies included, through `filter`
+    /// before it is handled, to simulate loss and latency in tests and chaos
+    /// experiments. Off by default.
+    pub fn inbound_filter(
+        mut self,
+        filter: impl Fn(&Message<Value>) -> Inbound + Send + Sync + 'static,
+    ) -> Self {
+        self.inbound_filter = Some(InboundFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Also appends every received message, dropped message and handler
+    /// error, as well as the `init` and the `init_ok`, to the file at `path`, one JSON object per line with
+    /// `timestamp` (milliseconds since the Unix epoch), `level`, `message`,
+    /// and the `type` and `src` of the message involved. Off by default.
+    pub fn json_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.json_log = Some(path.into());
+        self
+    }
+
+    /// Rejects messages with body fields their payload type does not declare,
+    /// like an input that cannot be decoded, instead of ignoring the extra
+    /// fields. Meant for tests, to catch protocol mismatches. Off by default.
+    pub fn strict_fields(mut self, enabled: bool) -> Self {
+        self.strict_fields = enabled;
+        self
+    }
+
+    /// Remembers the replies to the last `entries` requests, for up to
+    /// `ttl` each, and answers a request that arrives again with the same
+    /// `src` and `msg_id` with the remembered reply instead of handling it
+    /// again. The least recently used entry is evicted first. Its size is
+    /// included in the [`Config::log_message_rates`] line and in
+    /// [`Outbox::stats`]. Off by default.
+    pub fn reply_cache(mut self, entries: usize, ttl: Duration) -> Self {
+        self.reply_cache = Some((entries, ttl));
+        self
+    }
+
+    /// Writes [`Node::snapshot`] to the file at `path` every `interval`, and
+    /// once more at EOF. If the file exists when the node starts, it is
+    /// handed to [`Node::restore`] first, so a restarted node picks up where
+    /// it left off. Off by default.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.checkpoint = Some((path.into(), interval));
+        self
+    }
+
+    /// Limits the resends of [`Retrier`]s across all requests
+    /// to a bucket of `tokens`, refilled by one every `refill_every`. A
+    /// resend takes a token, or waits for one if the bucket is empty, so a
+    /// partition cannot turn every pending request into a retry storm. First
+    /// attempts are never held back. The tokens left are reported in
+    /// [`Outbox::stats`]. Off by default.
+    pub fn retry_budget(mut self, tokens: usize, refill_every: Duration) -> Self {
+        self.retry_budget = Some((tokens, refill_every));
+        self
+    }
+
+    /// Fails a resend that finds the [`Config::retry_budget`] empty with
+    /// [`Overloaded`] instead of waiting for a token, so an overloaded node
+    /// turns requests away rather than stalling on them. Off by default.
+    pub fn shed_load(mut self, enabled: bool) -> Self {
+        self.shed_load = enabled;
+        self
+    }
+
+    /// Forwards messages of a type the node's payload does not handle to
+    /// `peer` instead of failing to decode them, and relays `peer`'s replies
+    /// back to their sender, for nodes that route requests rather than serve
+    /// them. Forwarded messages and relayed replies get new `msg_id`s and
+    /// bypass the node, but still pass through its [`Node::send_hook`].
+    ///
+    /// At most [`MAX_FORWARDED`] forwarded messages await their reply at a
+    /// time; forwarding another forgets the oldest, whose reply is then
+    /// dropped, so a peer that stops answering cannot grow the node without
+    /// bound. Off by default.
+    pub fn forward_unknown(mut self, peer: impl Into<String>) -> Self {
+        self.forward_unknown = Some(peer.into());
+        self
+    }
+
+    /// Writes out at most `limit` replies to clients per second of the
+    /// [`Config::clock`], to see how Maelstrom copes with a slow node.
+    /// Replies over the limit are held back, in order, until a later second
+    /// has room for them, and any still held at EOF are written out then.
+    /// Messages to other nodes and `init_ok` are not held back. Off by
+    /// default.
+    ///
+    /// ```no_run
+    /// use std::io::{self, Write};
+    /// use std::sync::mpsc::Sender;
+    /// use std::sync::{Arc, Mutex};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// use gossip_glomers_rs::time::ManualClock;
+    /// use gossip_glomers_rs::*;
+    ///
+    /// payload! {
+    ///     #[derive(Debug, Clone)]
+    ///     enum EchoPayload {
+    ///         Echo = "echo" { echo: String },
+    ///         EchoOk = "echo_ok" { echo: String },
+    ///     }
+    /// }
+    ///
+    /// struct EchoNode;
+    ///
+    /// impl Node<(), EchoPayload> for EchoNode {
+    ///     type Injected = ();
+    ///
+    ///     fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+    ///         Ok(EchoNode)
+    ///     }
+    ///
+    ///     fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+    ///         let Event::Message(input) = input else {
+    ///             return Ok(());
+    ///         };
+    ///         if let EchoPayload::Echo { echo } = &input.body.payload {
+    ///             let echo = echo.clone();
+    ///             output.reply(&input, EchoPayload::EchoOk { echo })?;
+    ///         }
+    ///         Ok(())
+    ///     }
+    /// }
+    /// # #[derive(Clone, Default)]
+    /// # struct Buffer(Arc<Mutex<Vec<u8>>>);
+    /// # impl Write for Buffer {
+    /// #     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// #         self.0.lock().unwrap().extend_from_slice(buf);
+    /// #         Ok(buf.len())
+    /// #     }
+    /// #     fn flush(&mut self) -> io::Result<()> {
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # impl Buffer {
+    /// #     fn replies(&self) -> usize {
+    /// #         let output = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+    /// #         output.matches("echo_ok").count()
+    /// #     }
+    /// # }
+    ///
+    /// let clock = ManualClock::new();
+    /// let config = Config::new()
+    ///     .clock(Arc::new(clock.clone()))
+    ///     .max_replies_per_sec(2);
+    /// let (input, mut client) = io::pipe()?;
+    /// let output = Buffer::default();
+    /// let node = {
+    ///     let output = output.clone();
+    ///     let input = io::BufReader::new(input);
+    ///     thread::spawn(move || run::<_, EchoNode, _, _, _>(config, (), input, output))
+    /// };
+    /// writeln!(
+    ///     client,
+    ///     r#"{{"src":"c0","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}}}"#
+    /// )?;
+    /// let mut send = |msg_id: u64| {
+    ///     writeln!(
+    ///         client,
+    ///         r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{msg_id},"echo":"hi"}}}}"#
+    ///     )
+    /// };
+    /// let settle = || thread::sleep(Duration::from_millis(100));
+    ///
+    /// for msg_id in 2..=6 {
+    ///     send(msg_id)?;
+    /// }
+    /// settle();
+    /// assert_eq!(output.replies(), 2);
+    ///
+    /// // The next message is handled a simulated second later, and two more
+    /// // of the held replies go out with it, while its own waits its turn.
+    /// clock.advance(Duration::from_secs(1));
+    /// send(7)?;
+    /// settle();
+    /// assert_eq!(output.replies(), 4);
+    ///
+    /// drop(send);
+    /// drop(client);
+    /// node.join().unwrap()?;
+    /// assert_eq!(output.replies(), 6);
+    /// # Ok::<(), gossip_glomers_rs::Error>(())
+    /// ```
+    pub fn max_replies_per_sec(mut self, limit: usize) -> Self {
+        self.max_replies_per_sec = Some(limit);
+        self
+    }
+}
+
+/// Writes log events as JSON lines, see [`Config::json_log`].
+struct JsonLog {
+    file: File,
+}
+
+impl JsonLog {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open JSON log {}", path.display()))?;
+        Ok(JsonLog { file })
+    }
+
+    /// Logs an event, about the message of type `kind` from `src` if given.
+    ///
+    /// Failing to log is reported on stderr but does not stop the node.
+    fn log(&self, level: &str, message: &str, about: Option<(&str, &str)>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        let mut event = json::Map::new();
+        event.insert("timestamp".to_string(), Value::Int(timestamp as i128));
+        event.insert("level".to_string(), level.to_json());
+        event.insert("message".to_string(), message.to_json());
+        if let Some((kind, src)) = about {
+            event.insert("type".to_string(), kind.to_json());
+            event.insert("src".to_string(), src.to_json());
+        }
+        // One write per event, so events logged by the stdin thread and the
+        // main loop do not interleave.
+        let line = format!("{}\n", Value::Object(event));
+        if let Err(e) = (&self.file).write_all(line.as_bytes()) {
+            eprintln!("warning: cannot write JSON log: {e}");
+        }
+    }
+}
+
+/// Saves node snapshots on a timer, see [`Config::checkpoint`].
+struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    clock: Arc<dyn TimeSource>,
+    last: Instant,
+}
+
+impl Checkpointer {
+    fn new(path: PathBuf, interval: Duration, clock: Arc<dyn TimeSource>) -> Self {
+        Checkpointer {
+            path,
+            interval,
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    /// Hands the saved snapshot to `node`, if there is one.
+    fn restore<S, N: Node<S, P>, P>(&self, node: &mut N) -> Result<()> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("cannot read checkpoint"),
+        };
+        let snapshot = json::parse(&text).context("checkpoint is not JSON")?;
+        node.restore(snapshot).context("restoring checkpoint")
+    }
+
+    fn remaining(&self) -> Duration {
+        (self.last + self.interval).saturating_duration_since(self.clock.now())
+    }
+
+    /// Saves a snapshot if the interval is over.
+    fn tick<S, N: Node<S, P>, P>(&mut self, node: &N) {
+        if self.remaining().is_zero() {
+            self.save(node);
+        }
+    }
+
+    /// Saves a snapshot now. It is written next to the checkpoint, synced,
+    /// and then renamed over it, so a crash mid-write leaves the previous one
+    /// intact.
+    /// Failing to save is reported on stderr but does not stop the node.
+    fn save<S, N: Node<S, P>, P>(&mut self, node: &N) {
+        self.last = self.clock.now();
+        let Some(snapshot) = node.snapshot() else {
+            return;
+        };
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let written = std::fs::write(&tmp, snapshot.to_string())
+            .and_then(|()| std::fs::File::open(&tmp)?.sync_all())
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = written {
+            eprintln!(
+                "warning: cannot write checkpoint {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// A token bucket for resends, see [`Config::retry_budget`].
+struct RetryBudget {
+    capacity: usize,
+    refill_every: Duration,
+    clock: Arc<dyn TimeSource>,
+    tokens: usize,
+    /// When the last token was added, or the bucket was last seen full.
+    refilled_at: Instant,
+}
+
+impl RetryBudget {
+    fn new(capacity: usize, refill_every: Duration, clock: Arc<dyn TimeSource>) -> Self {
+        RetryBudget {
+            capacity,
+            refill_every,
+            tokens: capacity,
+            refilled_at: clock.now(),
+            clock,
+        }
+    }
+
+    fn tokens(&mut self) -> usize {
+        self.refill();
+        self.tokens
+    }
+
+    /// Takes a token, or returns how long until the next one if there is
+    /// none.
+    fn take(&mut self) -> std::result::Result<(), Duration> {
+        self.refill();
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            return Ok(());
+        }
+        let next = self.refilled_at + self.refill_every;
+        Err(next.saturating_duration_since(self.clock.now()))
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        if self.tokens >= self.capacity || self.refill_every.is_zero() {
+            self.tokens = self.capacity;
+            self.refilled_at = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let added = (elapsed.as_nanos() / self.refill_every.as_nanos()) as usize;
+        if added == 0 {
+            return;
+        }
+        self.tokens = (self.tokens + added).min(self.capacity);
+        self.refilled_at = if self.tokens == self.capacity {
+            now
+        } else {
+            self.refilled_at + self.refill_every * added as u32
+        };
+    }
+}
+
+/// Counts messages in and out over one-second windows.
+struct RateLog {
+    clock: Arc<dyn TimeSource>,
+    window_start: Instant,
+    messages_in: u64,
+    sent_at_start: u64,
+}
+
+impl RateLog {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(clock: Arc<dyn TimeSource>, outbox: &Outbox) -> Self {
+        RateLog {
+            window_start: clock.now(),
+            clock,
+            messages_in: 0,
+            sent_at_start: outbox.sent,
+        }
+    }
+
+    /// How long until the current window ends.
+    fn remaining(&self) -> Duration {
+        (self.window_start + Self::WINDOW).saturating_duration_since(self.clock.now())
+    }
+
+    /// Returns the rates once the window is over, and starts a new one.
+    fn tick(&mut self, outbox: &Outbox) -> Option<String> {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < Self::WINDOW {
+            return None;
+        }
+        let seconds = elapsed.as_secs_f64();
+        let line = format!(
+            "rate: {:.1} msgs/s in, {:.1} msgs/s out",
+            self.messages_in as f64 / seconds,
+            (outbox.sent - self.sent_at_start) as f64 / seconds
+        );
+        self.window_start = now;
+        self.messages_in = 0;
+        self.sent_at_start = outbox.sent;
+        Some(line)
+    }
+}
+
+/// Holds back replies over [`Config::max_replies_per_sec`].
+struct ReplyPacer {
+    limit: usize,
+    clock: Arc<dyn TimeSource>,
+    window_start: Instant,
+    /// Replies written out in the current window.
+    written: usize,
+    /// Serialized replies waiting for a window with room, oldest first.
+    held: VecDeque<String>,
+}
+
+impl ReplyPacer {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(limit: usize, clock: Arc<dyn TimeSource>) -> Self {
+        ReplyPacer {
+            limit,
+            window_start: clock.now(),
+            clock,
+            written: 0,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// How long until held replies may go out, if there are any.
+    fn remaining(&self) -> Option<Duration> {
+        (!self.held.is_empty())
+            .then(|| (self.window_start + Self::WINDOW).saturating_duration_since(self.clock.now()))
+    }
+
+    /// Returns `line` if it may be written out now, or else holds it back.
+    fn admit(&mut self, line: String) -> Option<String> {
+        if self.held.is_empty() && self.take() {
+            return Some(line);
+        }
+        self.held.push_back(line);
+        None
+    }
+
+    /// Takes the held replies the current window has room for.
+    fn release(&mut self, lines: &mut Vec<String>) {
+        while !self.held.is_empty() && self.take() {
+            lines.extend(self.held.pop_front());
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.window_start) >= Self::WINDOW {
+            self.window_start = now;
+            self.written = 0;
+        }
+        if self.written < self.limit {
+            self.written += 1;
+            return true;
+        }
+        false
+    }
+}
+
+/// Messages sent by a node.
+///
+/// They are buffered and written to stdout once the current step completes,
+/// or earlier through [`Outbox::flush`]. While more input is waiting, the
+/// main loop flushes stdout once for several steps rather than after each.
+pub struct Outbox {
+    writer: Box<dyn Write>,
+    pending: Vec<Message<Value>>,
+    rpc: rpc::Registry,
+    max_message_size: Option<MessageSizeLimit>,
+    encoder: Encoder,
+    coalesce_writes: bool,
+    /// How many messages have been written out so far.
+    sent: u64,
+    /// From `init`, empty until the handshake.
+    node_id: String,
+    node_ids: Vec<String>,
+    retry_budget: Option<RetryBudget>,
+    shed_load: bool,
+    reply_pacer: Option<ReplyPacer>,
+    reply_cache: Option<ReplyCache>,
+    clock: Arc<dyn TimeSource>,
+}
+
+/// A snapshot of a running node's counters, see [`Outbox::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Messages written out so far.
+    pub messages_sent: u64,
+    /// Tokens left in the [`Config::retry_budget`], if there is one.
+    pub retry_tokens: Option<usize>,
+    /// Replies in the [`Config::reply_cache`], if there is one.
+    pub reply_cache_len: Option<usize>,
+}
+
+impl Outbox {
+    pub(crate) fn new(writer: Box<dyn Write>, rpc: rpc::Registry, config: &Config) -> Self {
+        Outbox {
+            writer,
+            pending: Vec::new(),
+            rpc,
+            max_message_size: config.max_message_size,
+            encoder: Encoder::new(config.line_ending),
+            coalesce_writes: config.coalesce_writes,
+            sent: 0,
+            node_id: String::new(),
+            node_ids: Vec::new(),
+            retry_budget: config.retry_budget.map(|(tokens, refill_every)| {
+                RetryBudget::new(tokens, refill_every, Arc::clone(&config.clock))
+            }),
+            shed_load: config.shed_load,
+            reply_pacer: None,
+            reply_cache: config
+                .reply_cache
+                .map(|(entries, ttl)| ReplyCache::new(entries, ttl)),
+            clock: Arc::clone(&config.clock),
+        }
+    }
+
+    /// The node's counters as of now.
+    pub fn stats(&mut self) -> Stats {
+        Stats {
+            messages_sent: self.sent,
+            retry_tokens: self.retry_budget.as_mut().map(RetryBudget::tokens),
+            reply_cache_len: self.reply_cache.as_ref().map(ReplyCache::len),
+        }
+    }
+
+    /// This node's id, as Maelstrom sent it in `init`.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Every node in the cluster, this one included, as Maelstrom sent them
+    /// in `init`.
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Every node in the cluster except this one.
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.node_ids
+            .iter()
+            .map(String::as_str)
+            .filter(|id| *id != self.node_id)
+    }
+
+    /// Buffers a message, enforcing the configured size limit.
+    pub(crate) fn push(&mut self, message: Message<Value>) -> Result<()> {
+        if let Some(limit) = self.max_message_size {
+            let size = message.to_json().to_string().len();
+            if size > limit.bytes {
+                let error = MessageTooLarge {
+                    dest: message.dest.clone(),
+                    size,
+                    limit: limit.bytes,
+                };
+                match limit.on_exceed {
+                    OnOversize::Fail => return Err(error.into()),
+                    OnOversize::Warn => eprintln!("warning: {error}; sending it anyway"),
+                }
+            }
+        }
+        self.pending.push(message);
+        Ok(())
+    }
+
+    /// Writes out all buffered messages and flushes stdout, for replies that
+    /// must not wait for the runtime to batch them with the next steps'.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write()?;
+        self.writer.flush().context("flush STDOUT")
+    }
+
+    /// Like [`Outbox::flush`], but leaves the messages in the writer's
+    /// buffer.
+    ///
+    /// Replies held back by [`Config::max_replies_per_sec`] go out first, as
+    /// far as the limit allows. Replies are remembered in the
+    /// [`Config::reply_cache`] as they were before the send hook.
+    pub(crate) fn write(&mut self) -> Result<()> {
+        let mut lines = Vec::new();
+        if let Some(pacer) = &mut self.reply_pacer {
+            pacer.release(&mut lines);
+        }
+        let now = self.clock.now();
+        for message in std::mem::take(&mut self.pending) {
+            if let Some(cache) = &mut self.reply_cache {
+                cache.insert(&message, now);
+            }
+            let is_client_reply =
+                message.body.in_reply_to.is_some() && !self.node_ids.contains(&message.dest);
+            let line = self.encoder.encode(message);
+            let line = match &mut self.reply_pacer {
+                Some(pacer) if is_client_reply => pacer.admit(line),
+                _ => Some(line),
+            };
+            lines.extend(line);
+        }
+        self.write_lines(lines)
+    }
+
+    /// Writes out the replies still held back, regardless of the limit.
+    fn write_held(&mut self) -> Result<()> {
+        let held = match &mut self.reply_pacer {
+            Some(pacer) => std::mem::take(&mut pacer.held),
+            None => return Ok(()),
+        };
+        self.write_lines(held.into())
+    }
+
+    /// The remembered reply to the request `msg_id` from `src`, see
+    /// [`Config::reply_cache`].
+    fn cached_reply(&mut self, src: &str, msg_id: usize) -> Option<Message<Value>> {
+        let now = self.clock.now();
+        self.reply_cache.as_mut()?.get(src, msg_id, now)
+    }
+
+    /// How long until replies held back by [`Config::max_replies_per_sec`]
+    /// may go out, if there are any.
+    fn held_remaining(&self) -> Option<Duration> {
+        self.reply_pacer.as_ref().and_then(ReplyPacer::remaining)
+    }
+
+    fn write_lines(&mut self, lines: Vec<String>) -> Result<()> {
+        self.sent += lines.len() as u64;
+        if self.coalesce_writes {
+            if !lines.is_empty() {
+                self.writer
+                    .write_all(lines.concat().as_bytes())
+                    .context("write messages to STDOUT")?;
+            }
+            return Ok(());
+        }
+        for line in lines {
+            self.writer
+                .write_all(line.as_bytes())
+                .context("write message to STDOUT")?;
+        }
+        Ok(())
+    }
+
+    /// A fresh `msg_id`, for a request whose reply is to reach
+    /// [`Node::step`] rather than a caller waiting for it. Like the ids of
+    /// replies and RPCs, it is never handed out twice.
+    pub fn next_msg_id(&mut self) -> usize {
+        self.rpc.next_id()
+    }
+
+    /// Sends a request and returns the receiver its reply will be delivered
+    /// to.
+    ///
+    /// The request is written out immediately, so the caller may block on
+    /// the reply within the same step.
+    pub(crate) fn request<P: ToJson>(
+        &mut self,
+        src: &str,
+        dest: &str,
+        payload: P,
+    ) -> Result<rpc::Reply> {
+        let reply = self.rpc.register(dest);
+        self.send_request(src, dest, reply.id(), payload)?;
+        Ok(reply)
+    }
+
+    /// Sends `payload` as a request from `src` to `dest` and returns its
+    /// reply as a [`PendingReply`], which can be awaited or waited on.
+    ///
+    /// Like with [`KvClient`](crate::kv::KvClient), the request is written
+    /// out immediately and the reply is routed to the returned handle rather
+    /// than to [`Node::step`].
+    pub fn rpc<P: Payload>(
+        &mut self,
+        src: &str,
+        dest: &str,
+        payload: P,
+    ) -> Result<PendingReply<P>> {
+        let (ticket, reply, waker) = self.rpc.register_waker(dest);
+        self.send_request(src, dest, ticket.id(), payload)?;
+        Ok(PendingReply::new(reply, waker, ticket))
+    }
+
+    /// Takes a token from the [`Config::retry_budget`] for a resend to
+    /// `dest`, returning how long to wait before trying again if the bucket
+    /// is empty, or failing with [`Overloaded`] when shedding load.
+    pub(crate) fn take_retry_token(&mut self, dest: &str) -> Result<Option<Duration>> {
+        let Some(budget) = &mut self.retry_budget else {
+            return Ok(None);
+        };
+        match budget.take() {
+            Ok(()) => Ok(None),
+            Err(_) if self.shed_load => {
+                Err(Overloaded(format!("retry budget exhausted, not resending to {dest}")).into())
+            }
+            Err(wait) => Ok(Some(wait.max(Duration::from_millis(1)))),
+        }
+    }
+
+    /// Sends a request under `id` and writes it out right away.
+    fn send_request<P: ToJson>(
+        &mut self,
+        src: &str,
+        dest: &str,
+        id: usize,
+        payload: P,
+    ) -> Result<()> {
+        Message {
+            src: src.to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        }
+        .send(self)?;
+        self.flush()
+    }
+
+    /// Answers `original` with `payload`, under a fresh `msg_id` and with
+    /// `in_reply_to` set to the request's `msg_id`.
+    ///
+    /// Ids come from the same counter as requests, so nodes replying this
+    /// way need no counter of their own.
+    pub fn reply<P: Payload>(&mut self, original: &Message<P>, payload: P) -> Result<()> {
+        self.reply_with(original, payload)
+    }
+
+    /// Answers `original` with an `error` body carrying `code` and `text`,
+    /// like [`Outbox::reply`].
+    pub fn reply_error<P>(
+        &mut self,
+        original: &Message<P>,
+        code: MaelstromError,
+        text: impl Into<String>,
+    ) -> Result<()> {
+        let mut payload = json::Map::new();
+        payload.insert("type".to_string(), Value::String("error".to_string()));
+        payload.insert("code".to_string(), Value::Int(code.code().into()));
+        payload.insert("text".to_string(), Value::String(text.into()));
+        self.reply_with(original, Value::Object(payload))
+    }
+
+    fn reply_with<P, Q: ToJson>(&mut self, original: &Message<P>, payload: Q) -> Result<()> {
+        if original.body.id.is_none() {
+            eprintln!(
+                "warning: replying to a message from {} without a msg_id; \
+                 the reply cannot be correlated",
+                original.src
+            );
+        }
+        Message {
+            src: original.dest.clone(),
+            dest: original.src.clone(),
+            body: Body {
+                id: Some(self.rpc.next_id()),
+                in_reply_to: original.body.id,
+                payload,
+            },
+        }
+        .send(self)
+    }
+}
+
+/// A [`Retrier`] request that got no reply to any attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedOut {
+    pub dest: String,
+    pub attempts: usize,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} did not answer {} attempts of {:?} each",
+            self.dest, self.attempts, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// The node has no capacity for a request right now, e.g. because the
+/// [`Config::retry_budget`] is empty under [`Config::shed_load`].
+///
+/// A handler may also return it itself. A request whose handler fails with
+/// it is answered with [`MaelstromError::TemporarilyUnavailable`], so that
+/// the client backs off and tries again later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overloaded(pub String);
+
+impl std::fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "overloaded: {}", self.0)
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// A message whose serialized form exceeds the configured
+/// [`Config::max_message_size`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub dest: String,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "message to {} is {} bytes, over the {} byte limit",
+            self.dest, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+fn parse_message<P: FromJson>(line: &str) -> Result<Message<P>> {
+    let value = json::parse(line)?;
+    Ok(Message::from_json(&value)?)
+}
+
+/// Runs a node: performs the `init` handshake, then feeds every message read
+/// from stdin to [`Node::step`] until stdin is closed.
+///
+/// Errors returned by `step` are logged to stderr together with the message
+/// that caused them, and do not stop the node. Neither do input lines that
+/// are not messages, or whose payload is not a `P`: they are logged and
+/// skipped, and if they are requests, answered with
+/// [`MaelstromError::NotSupported`] for a message type `P` does not have or
+/// [`MaelstromError::MalformedRequest`] for one it does.
+pub fn main_loop<S, N, P>(init_state: S) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+{
+    main_loop_with_config::<S, N, P>(Config::default(), init_state)
+}
+
+/// Like [`main_loop`], with non-default runtime options.
+pub fn main_loop_with_config<S, N, P>(config: Config, init_state: S) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+{
+    run::<S, N, P, _, _>(
+        config,
+        init_state,
+        BufReader::new(std::io::stdin()),
+        BufWriter::new(std::io::stdout()),
+    )
+}
+
+/// Like [`main_loop_with_config`], reading messages from `input` and writing
+/// to `output` instead of stdin and stdout.
+///
+/// ```
+/// use std::io::{self, Read};
+/// use std::sync::mpsc::Sender;
+///
+/// use gossip_glomers_rs::*;
+///
+/// payload! {
+///     #[derive(Debug, Clone)]
+///     enum EchoPayload {
+///         Echo = "echo" { echo: String },
+///         EchoOk = "echo_ok" { echo: String },
+///     }
+/// }
+///
+/// struct EchoNode;
+///
+/// impl Node<(), EchoPayload> for EchoNode {
+///     type Injected = ();
+///
+///     fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+///         Ok(EchoNode)
+///     }
+///
+///     fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+///         let Event::Message(input) = input else {
+///             return Ok(());
+///         };
+///         if let EchoPayload::Echo { echo } = &input.body.payload {
+///             let echo = echo.clone();
+///             output.reply(&input, EchoPayload::EchoOk { echo })?;
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// // A line that is not JSON and a message type the node does not have do
+/// // not stop it from answering the echo after them.
+/// let input = concat!(
+///     r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+///     "\n",
+///     "garbage\n",
+///     r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2}}"#,
+///     "\n",
+///     r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"hi"}}"#,
+///     "\n",
+/// );
+/// let (mut output, writer) = io::pipe()?;
+/// run::<_, EchoNode, _, _, _>(Config::new(), (), input.as_bytes(), writer)?;
+/// let mut written = String::new();
+/// output.read_to_string(&mut written)?;
+/// let replies: Vec<_> = written.lines().collect();
+/// assert_eq!(replies.len(), 3);
+/// assert!(replies[1].contains(r#""type":"error""#) && replies[1].contains(r#""code":10"#));
+/// assert!(replies[2].contains(r#""type":"echo_ok""#));
+/// # Ok::<(), gossip_glomers_rs::Error>(())
+/// ```
+pub fn run<S, N, P, R, W>(config: Config, init_state: S, input: R, output: W) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+    R: BufRead + Send + 'static,
+    W: Write + Send + 'static,
+{
+    run_inner::<S, N, P, R, W>(config, init_state, None, input, output)
+}
+
+/// Like [`main_loop_with_config`], but the node starts from the state
+/// `persistence` holds, if any, instead of `init_state`, and
+/// [`Node::persistent_state`] is saved there at EOF, so a restarted node
+/// picks up where it left off.
+///
+/// Failing to load the state stops the node; failing to save it is reported
+/// on stderr.
+pub fn main_loop_with_persistence<S, N, P>(
+    config: Config,
+    init_state: S,
+    persistence: impl StatePersistence<S>,
+) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+{
+    run_with_persistence::<S, N, P, _, _>(
+        config,
+        init_state,
+        persistence,
+        BufReader::new(std::io::stdin()),
+        BufWriter::new(std::io::stdout()),
+    )
+}
+
+/// Like [`main_loop_with_persistence`], reading messages from `input` and
+/// writing to `output` instead of stdin and stdout.
+///
+/// ```
+/// use std::io::{self, Read};
+/// use std::sync::mpsc::Sender;
+///
+/// use gossip_glomers_rs::persist::FileState;
+/// use gossip_glomers_rs::*;
+///
+/// payload! {
+///     #[derive(Debug, Clone)]
+///     enum CountPayload {
+///         Add = "add" { delta: u64 },
+///         AddOk = "add_ok",
+///         Read = "read",
+///         ReadOk = "read_ok" { value: u64 },
+///     }
+/// }
+///
+/// /// The node's state is its running total.
+/// struct CountNode {
+///     total: u64,
+/// }
+///
+/// impl Node<u64, CountPayload> for CountNode {
+///     type Injected = ();
+///
+///     fn from_init(total: u64, _: Init, _: Sender<Event<CountPayload>>) -> Result<Self> {
+///         Ok(CountNode { total })
+///     }
+///
+///     fn step(&mut self, input: Event<CountPayload>, output: &mut Outbox) -> Result<()> {
+///         let Event::Message(input) = input else {
+///             return Ok(());
+///         };
+///         match input.body.payload {
+///             CountPayload::Add { delta } => {
+///                 self.total += delta;
+///                 output.reply(&input, CountPayload::AddOk)
+///             }
+///             CountPayload::Read => {
+///                 let value = self.total;
+///                 output.reply(&input, CountPayload::ReadOk { value })
+///             }
+///             _ => Ok(()),
+///         }
+///     }
+///
+///     fn persistent_state(&self) -> Option<u64> {
+///         Some(self.total)
+///     }
+/// }
+///
+/// let path = std::env::temp_dir().join(format!("count-doc-{}", std::process::id()));
+/// let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+/// let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":5}}"#;
+/// let read = r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#;
+/// let run_once = |lines: &[&str]| -> Result<String> {
+///     let input = lines.join("\n").into_bytes();
+///     let (mut output, writer) = io::pipe()?;
+///     let persistence = FileState::new(&path);
+///     let input = io::Cursor::new(input);
+///     run_with_persistence::<_, CountNode, _, _, _>(Config::new(), 0, persistence, input, writer)?;
+///     let mut written = String::new();
+///     output.read_to_string(&mut written)?;
+///     Ok(written)
+/// };
+///
+/// run_once(&[init, add])?;
+/// // The second run starts from the total the first one saved at EOF.
+/// assert!(run_once(&[init, add, read])?.contains(r#""value":10"#));
+/// # std::fs::remove_file(&path).ok();
+/// # std::fs::remove_file(path.with_extension("lock")).ok();
+/// # Ok::<(), gossip_glomers_rs::Error>(())
+/// ```
+pub fn run_with_persistence<S, N, P, R, W>(
+    config: Config,
+    init_state: S,
+    persistence: impl StatePersistence<S>,
+    input: R,
+    output: W,
+) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+    R: BufRead + Send + 'static,
+    W: Write + Send + 'static,
+{
+    run_inner::<S, N, P, R, W>(config, init_state, Some(&persistence), input, output)
+}
+
+fn run_inner<S, N, P, R, W>(
+    config: Config,
+    init_state: S,
+    persistence: Option<&dyn StatePersistence<S>>,
+    input: R,
+    output: W,
+) -> Result<()>
+where
+    P: Payload + Send + 'static,
+    N: Node<S, P>,
+    R: BufRead + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let rpc = rpc::Registry::default();
+    let output = SharedWriter(Arc::new(Mutex::new(output)));
+    let mut outbox = Outbox::new(Box::new(output.clone()), rpc.clone(), &config);
+    let forwarder = config.forward_unknown.clone().map(|peer| Forwarder {
+        peer,
+        writer: output.clone(),
+        rpc: rpc.clone(),
+        encoder: outbox.encoder.clone(),
+        pending: Mutex::default(),
+        capacity: MAX_FORWARDED,
+    });
+    let rejector = Rejector {
+        writer: output,
+        rpc: rpc.clone(),
+        encoder: outbox.encoder.clone(),
+    };
+
+    let (init_tx, init_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let reader_tx = tx.clone();
+    let reader_rpc = rpc.clone();
+    let filter = config.inbound_filter.clone();
+    let strict_fields = config.strict_fields;
+    let reader = thread::spawn(move || {
+        let tx = reader_tx;
+        let mut lines = input.lines();
+        match lines.next() {
+            Some(line) => {
+                let _ = init_tx.send(line);
+            }
+            None => return Ok(()),
+        }
+        // Nothing is answered before `init_ok` is out: a rejection or a
+        // forwarded reply written first would be the client's first reply.
+        // If the handshake fails the node stops without reading further.
+        let Ok((node_id, json_log)) = ready_rx.recv() else {
+            return Ok(());
+        };
+        let inlet = Arc::new(Inlet {
+            node_id,
+            rpc: reader_rpc,
+            tx: tx.clone(),
+            forwarder,
+            rejector,
+            json_log,
+            strict_fields,
+        });
+        // Every line read before stdin closed is queued ahead of the EOF, and
+        // the EOF is sent even if reading fails, so the node always sees it
+        // last and the main loop does not wait forever.
+        let result = (|| {
+            for line in lines {
+                let line = line.context("Maelstrom input from STDIN could not be read")?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let input: Message<Value> = match parse_message(&line) {
+                    Ok(input) => input,
+                    Err(e) => {
+                        eprintln!("error: skipping input line that is not a message: {e}: {line}");
+                        continue;
+                    }
+                };
+                match filter
+                    .as_ref()
+                    .map_or(Inbound::Deliver, |filter| filter.0(&input))
+                {
+                    Inbound::Deliver => {}
+                    Inbound::Drop => continue,
+                    Inbound::Delay(delay) => {
+                        let inlet = Arc::clone(&inlet);
+                        thread::spawn(move || {
+                            thread::sleep(delay);
+                            if let Err(e) = inlet.deliver(input) {
+                                eprintln!("error: delayed message: {e}");
+                            }
+                        });
+                        continue;
+                    }
+                }
+                if !inlet.deliver(input)? {
+                    break;
+                }
+            }
+            Ok::<_, Error>(())
+        })();
+        let _ = tx.send(Event::EOF);
+        result
+    });
+
+    let json_log = config
+        .json_log
+        .as_deref()
+        .map(JsonLog::open)
+        .transpose()?
+        .map(Arc::new);
+    let init_line = match init_rx.recv_timeout(config.init_timeout) {
+        Ok(line) => line.context("failed to read init message from STDIN")?,
+        Err(RecvTimeoutError::Timeout) => bail!(
+            "no init message received within {:?}, is the node running under Maelstrom?",
+            config.init_timeout
+        ),
+        Err(RecvTimeoutError::Disconnected) => bail!("no init message received"),
+    };
+    let init_state = match persistence {
+        Some(persistence) => persistence
+            .load()
+            .context("cannot load the saved node state")?
+            .unwrap_or(init_state),
+        None => init_state,
+    };
+    if let (Some(log), Ok(init)) = (&json_log, parse_message::<Value>(&init_line)) {
+        log.log(
+            "info",
+            "received",
+            Some((init.body.payload.kind(), &init.src)),
+        );
+    }
+    let (mut node, node_id): (N, _) = match handshake(init_state, &init_line, tx, &mut outbox) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            if let Some(log) = &json_log {
+                log.log("error", &e.to_string(), None);
+            }
+            return Err(e);
+        }
+    };
+    if let Some(log) = &json_log {
+        log.log("info", "sent", Some(("init_ok", &node_id)));
+    }
+    let _ = ready_tx.send((node_id, json_log.clone()));
+    // Only from here on, so that `init_ok` is never held back.
+    outbox.reply_pacer = config
+        .max_replies_per_sec
+        .map(|limit| ReplyPacer::new(limit, Arc::clone(&config.clock)));
+    let mut checkpoint = config
+        .checkpoint
+        .clone()
+        .map(|(path, interval)| Checkpointer::new(path, interval, Arc::clone(&config.clock)));
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint.restore::<S, N, P>(&mut node)?;
+    }
+    if let Err(e) = node
+        .on_init_complete(&mut outbox)
+        .context("completing init")
+    {
+        eprintln!("error: {e}");
+    }
+    outbox.flush()?;
+
+    let mut rates = config
+        .log_message_rates
+        .then(|| RateLog::new(Arc::clone(&config.clock), &outbox));
+    // Events taken off `rx` to look past a coalesced injected event, or to
+    // see whether more input is waiting.
+    let mut backlog = VecDeque::new();
+    // Steps whose output is still in the writer's buffer.
+    let mut unflushed = 0;
+    loop {
+        // While more input is already waiting, the output of the steps so
+        // far is flushed together with its output, saving a write per step.
+        if unflushed > 0 && backlog.is_empty() {
+            if let Ok(next) = rx.try_recv() {
+                backlog.push_back(next);
+            }
+        }
+        if unflushed > 0 && (backlog.is_empty() || unflushed >= MAX_UNFLUSHED_STEPS) {
+            outbox.flush()?;
+            unflushed = 0;
+        }
+        // Wake up when a timer is due even if nothing arrives.
+        let wait = [
+            rates.as_ref().map(RateLog::remaining),
+            checkpoint.as_ref().map(Checkpointer::remaining),
+            outbox.held_remaining(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let input = match (backlog.pop_front(), wait) {
+            (Some(input), _) => Some(input),
+            (None, Some(wait)) => match rx.recv_timeout(wait) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            (None, None) => match rx.recv() {
+                Ok(input) => Some(input),
+                Err(_) => break,
+            },
+        };
+        if let Some(rates) = &mut rates {
+            if matches!(input, Some(Event::Message(_))) {
+                rates.messages_in += 1;
+            }
+            if let Some(line) = rates.tick(&outbox) {
+                match outbox.stats().reply_cache_len {
+                    Some(cached) => eprintln!("{line}, {cached} cached replies"),
+                    None => eprintln!("{line}"),
+                }
+            }
+        }
+        if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.tick::<S, N, P>(&node);
+        }
+        let Some(input) = input else {
+            // Held replies may be due.
+            outbox.flush()?;
+            continue;
+        };
+        if node.coalesce_injected() && superseded(&input, &rx, &mut backlog) {
+            continue;
+        }
+        let about = match (&json_log, &input) {
+            (Some(_), Event::Message(message)) => {
+                Some((message.body.payload.kind().to_string(), message.src.clone()))
+            }
+            _ => None,
+        };
+        let about = about
+            .as_ref()
+            .map(|(kind, src)| (kind.as_str(), src.as_str()));
+        if let Event::Message(message) = &input {
+            if let Some(log) = &json_log {
+                log.log("info", "received", about);
+            }
+            let cached = message
+                .body
+                .id
+                .and_then(|msg_id| outbox.cached_reply(&message.src, msg_id));
+            if let Some(reply) = cached {
+                // Cached as it was before the send hook, which runs again.
+                outbox.push(reply)?;
+                outbox.flush()?;
+                continue;
+            }
+        }
+        if let Event::InjectorFailed(reason) = input {
+            outbox.write_held()?;
+            outbox.flush()?;
+            bail!("{reason}");
+        }
+        let is_eof = matches!(input, Event::EOF);
+        if let Err(e) = dispatch::<S, N, P>(&mut node, input, &mut outbox) {
+            eprintln!("error: {e}");
+            if let Some(log) = &json_log {
+                log.log("error", &e.to_string(), about);
+            }
+        }
+        outbox.write()?;
+        unflushed += 1;
+        #[cfg(debug_assertions)]
+        check_invariants::<S, N, P>(&node)?;
+        if is_eof {
+            break;
+        }
+    }
+    outbox.write_held()?;
+    outbox.flush()?;
+    if let Some(checkpoint) = &mut checkpoint {
+        checkpoint.save::<S, N, P>(&node);
+    }
+    if let Some(persistence) = persistence {
+        if let Some(state) = node.persistent_state() {
+            if let Err(e) = persistence.save(&state) {
+                eprintln!("warning: cannot save the node state: {e}");
+            }
+        }
+    }
+
+    reader
+        .join()
+        .expect("stdin thread panicked")
+        .context("stdin thread err'd")?;
+
+    Ok(())
+}
+
+/// Where the stdin thread hands the messages it reads, see
+/// [`Inlet::deliver`].
+struct Inlet<P, I> {
+    node_id: String,
+    rpc: rpc::Registry,
+    tx: Sender<Event<P, I>>,
+    forwarder: Option<Forwarder>,
+    rejector: Rejector,
+    json_log: Option<Arc<JsonLog>>,
+    strict_fields: bool,
+}
+
+impl<P: Payload, I> Inlet<P, I> {
+    /// Drops `input` if it is addressed to another node, and otherwise hands
+    /// it to its requester if it is a reply, to the forwarder if it is one to
+    /// relay, or else to the main loop, or to the rejector if it is not a
+    /// `P`. Returns `false` once the main loop has stopped listening.
+    fn deliver(&self, input: Message<Value>) -> Result<bool> {
+        if input.dest != self.node_id {
+            let kind = input.body.payload.kind();
+            let warning = format!(
+                "dropping {kind} from {} addressed to {}, this node is {}",
+                input.src, input.dest, self.node_id
+            );
+            eprintln!("warning: {warning}");
+            if let Some(log) = &self.json_log {
+                log.log("warn", &warning, Some((kind, &input.src)));
+            }
+            return Ok(true);
+        }
+        let Some(input) = self.rpc.complete(input) else {
+            return Ok(true);
+        };
+        let input = match &self.forwarder {
+            Some(forwarder) => match forwarder.route::<P>(input)? {
+                Some(input) => input,
+                None => return Ok(true),
+            },
+            None => input,
+        };
+        let checked = match self.strict_fields {
+            true => check_known_fields::<P>(&input.body.payload),
+            false => Ok(()),
+        };
+        let payload = match checked.and_then(|()| Ok(P::from_json(&input.body.payload)?)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                self.rejector.reject::<P>(&input, &e)?;
+                return Ok(true);
+            }
+        };
+        let input = Message {
+            src: input.src,
+            dest: input.dest,
+            body: Body {
+                id: input.body.id,
+                in_reply_to: input.body.in_reply_to,
+                payload,
+            },
+        };
+        Ok(self.tx.send(Event::Message(input)).is_ok())
+    }
+}
+
+/// Skips messages that are not a node's payload, so that one bad message
+/// does not stop the node.
+struct Rejector {
+    writer: SharedWriter,
+    rpc: rpc::Registry,
+    encoder: Encoder,
+}
+
+impl Rejector {
+    /// Logs that `message` is not a `P`, and answers it with an error if it
+    /// is a request: [`MaelstromError::NotSupported`] if `P` has no such
+    /// message type, or [`MaelstromError::MalformedRequest`] if it does.
+    fn reject<P: Payload>(&self, message: &Message<Value>, error: &Error) -> Result<()> {
+        let kind = message.body.payload.kind();
+        eprintln!(
+            "error: skipping {kind} from {}, it could not be deserialized: {error}",
+            message.src
+        );
+        let (Some(msg_id), None) = (message.body.id, message.body.in_reply_to) else {
+            return Ok(());
+        };
+        let (code, text) = match P::known_fields(kind) {
+            None => (
+                MaelstromError::NotSupported,
+                format!("message type {kind:?} is not supported"),
+            ),
+            Some(_) => (MaelstromError::MalformedRequest, format!("{kind}: {error}")),
+        };
+        let mut payload = json::Map::new();
+        payload.insert("type".to_string(), Value::String("error".to_string()));
+        payload.insert("code".to_string(), Value::Int(code.code().into()));
+        payload.insert("text".to_string(), Value::String(text));
+        let reply = Message {
+            src: message.dest.clone(),
+            dest: message.src.clone(),
+            body: Body {
+                id: Some(self.rpc.next_id()),
+                in_reply_to: Some(msg_id),
+                payload: Value::Object(payload),
+            },
+        };
+        self.writer
+            .send(&self.encoder.encode(reply))
+            .context("write error reply to STDOUT")
+    }
+}
+
+/// How many messages forwarded under [`Config::forward_unknown`] may await
+/// their reply at a time.
+pub const MAX_FORWARDED: usize = 10_000;
+
+/// Relays messages for [`Config::forward_unknown`].
+struct Forwarder {
+    peer: String,
+    writer: SharedWriter,
+    rpc: rpc::Registry,
+    encoder: Encoder,
+    /// Per `msg_id` of a forwarded message, its sender and original `msg_id`.
+    /// Ids only grow, so the first entry is the oldest.
+    pending: Mutex<BTreeMap<usize, (String, usize)>>,
+    /// How many entries `pending` may hold, [`MAX_FORWARDED`] but in tests.
+    capacity: usize,
+}
+
+impl Forwarder {
+    /// Relays `message` if it is a reply from the peer to a forwarded
+    /// message, or forwards it if `P` does not handle its type. Otherwise
+    /// hands it back.
+    fn route<P: Payload>(&self, message: Message<Value>) -> Result<Option<Message<Value>>> {
+        if message.src == self.peer {
+            let client = message
+                .body
+                .in_reply_to
+                .and_then(|id| self.pending.lock().unwrap().remove(&id));
+            if let Some((client, msg_id)) = client {
+                self.write(Message {
+                    src: message.dest,
+                    dest: client,
+                    body: Body {
+                        id: Some(self.rpc.next_id()),
+                        in_reply_to: Some(msg_id),
+                        payload: message.body.payload,
+                    },
+                })?;
+                return Ok(None);
+            }
+        }
+        let kind = message.body.payload.kind();
+        if P::known_fields(kind).is_some() || P::from_json(&message.body.payload).is_ok() {
+            return Ok(Some(message));
+        }
+        if message.src == self.peer && message.body.in_reply_to.is_some() {
+            eprintln!(
+                "warning: dropping a reply from {} to a forwarded message that was forgotten",
+                self.peer
+            );
+            return Ok(None);
+        }
+        // Without a msg_id there is no reply to relay.
+        let id = message.body.id.map(|msg_id| {
+            let id = self.rpc.next_id();
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(id, (message.src.clone(), msg_id));
+            if pending.len() > self.capacity {
+                pending.pop_first();
+            }
+            id
+        });
+        self.write(Message {
+            src: message.dest,
+            dest: self.peer.clone(),
+            body: Body {
+                id,
+                in_reply_to: message.body.in_reply_to,
+                payload: message.body.payload,
+            },
+        })?;
+        Ok(None)
+    }
+
+    fn write(&self, message: Message<Value>) -> Result<()> {
+        self.writer
+            .send(&self.encoder.encode(message))
+            .context("write forwarded message to STDOUT")
+    }
+}
+
+/// Turns messages into output lines, passing each through the node's
+/// [`SendHook`] first. The [`Outbox`], the [`Forwarder`] and the
+/// [`Rejector`] share one, so whichever of them writes a message, it is
+/// hooked and written the same way.
+#[derive(Clone)]
+struct Encoder {
+    /// Set once, right after [`Node::from_init`].
+    hook: Arc<OnceLock<SendHook>>,
+    line_ending: LineEnding,
+}
+
+impl Encoder {
+    fn new(line_ending: LineEnding) -> Self {
+        Encoder {
+            hook: Arc::default(),
+            line_ending,
+        }
+    }
+
+    fn encode(&self, mut message: Message<Value>) -> String {
+        if let Some(hook) = self.hook.get() {
+            hook(&mut message);
+        }
+        format!("{}{}", message.to_json(), self.line_ending.as_str())
+    }
+}
+
+/// The output, shared by the main loop, the [`Forwarder`] and the
+/// [`Rejector`].
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<dyn Write + Send>>);
+
+impl SharedWriter {
+    /// Writes out `line` and flushes, bypassing the [`Outbox`].
+    fn send(&self, line: &str) -> std::io::Result<()> {
+        let mut writer = self.0.lock().unwrap();
+        writer.write_all(line.as_bytes())?;
+        writer.flush()
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    /// Holds the lock for the whole write, so a line is never interleaved
+    /// with one written by the forwarder or the rejector.
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Fails if `body` has a field that its `P` message type does not declare.
+fn check_known_fields<P: Payload>(body: &Value) -> Result<()> {
+    let known = body
+        .get("type")
+        .and_then(Value::as_str)
+        .and_then(P::known_fields);
+    let (Some(known), Some(fields)) = (known, body.as_object()) else {
+        return Ok(());
+    };
+    for field in fields.keys() {
+        if !matches!(field.as_str(), "type" | "msg_id" | "in_reply_to")
+            && !known.contains(&field.as_str())
+        {
+            bail!("unknown field `{field}` in {}", body.kind());
+        }
+    }
+    Ok(())
+}
+
+/// Runs `body` on a background thread, for timers and other sources of
+/// injected events.
+///
+/// Unlike a bare [`thread::spawn`], a panic in `body` does not silently stop
+/// the events: it is logged to stderr, and `body` is started again, up to
+/// `restarts` times. Past that, [`Event::InjectorFailed`] is sent through
+/// `inject`, and [`run`] fails with it. A `body` that returns normally is not
+/// restarted.
+pub fn spawn_injector<P, I, F>(
+    name: &str,
+    restarts: u32,
+    inject: Sender<Event<P, I>>,
+    body: F,
+) -> thread::JoinHandle<()>
+where
+    P: Send + 'static,
+    I: Send + 'static,
+    F: Fn() + Send + 'static,
+{
+    let name = name.to_string();
+    thread::spawn(move || {
+        for attempt in 0..=restarts {
+            let Err(panic) = panic::catch_unwind(AssertUnwindSafe(&body)) else {
+                return;
+            };
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            if attempt < restarts {
+                eprintln!("error: injector thread {name} panicked: {message}; restarting it");
+            } else {
+                eprintln!(
+                    "error: injector thread {name} panicked: {message}; giving up after \
+                     {restarts} restarts"
+                );
+                let _ = inject.send(Event::InjectorFailed(format!(
+                    "injector thread {name} panicked {} times, last with: {message}",
+                    restarts + 1
+                )));
+            }
+        }
+    })
+}
+
+/// Sends injected events after a delay, instead of each node spawning and
+/// sleeping a thread of its own for every timer.
+///
+/// All events scheduled through a `Timer` and its clones share one
+/// background thread. Repeating work, like a gossip interval, schedules its
+/// next event from [`Node::on_injected`].
+///
+/// Once every handle is dropped, which happens at the latest when the node
+/// is dropped at EOF, the thread discards any events still pending and
+/// exits.
+pub struct Timer<I> {
+    commands: Sender<TimerCommand<I>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Names an event scheduled on a [`Timer`], to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+enum TimerCommand<I> {
+    Schedule(Instant, TimerId, I),
+    Cancel(TimerId),
+}
+
+impl<I: Send + 'static> Timer<I> {
+    /// A timer that sends its events through `inject`.
+    pub fn new<P: Send + 'static>(inject: Sender<Event<P, I>>) -> Self {
+        let (commands, received) = mpsc::channel::<TimerCommand<I>>();
+        thread::spawn(move || {
+            let clock = SystemClock;
+            let mut schedule = Schedule::default();
+            loop {
+                while let Some(injected) = schedule.pop_due(clock.now()) {
+                    if inject.send(Event::Injected(injected)).is_err() {
+                        // The main loop is gone.
+                        return;
+                    }
+                }
+                let next = match schedule.next_deadline() {
+                    Some(deadline) => received.recv_timeout(deadline - clock.now()),
+                    None => received.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match next {
+                    Ok(TimerCommand::Schedule(deadline, id, injected)) => {
+                        schedule.insert(deadline, id, injected)
+                    }
+                    Ok(TimerCommand::Cancel(id)) => schedule.cancel(id),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Timer {
+            commands,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sends `injected` to the node once `delay` has passed, unless it is
+    /// cancelled first.
+    ///
+    /// Does nothing if the main loop has already stopped.
+    pub fn schedule_in(&self, delay: Duration, injected: I) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let _ = self
+            .commands
+            .send(TimerCommand::Schedule(Instant::now() + delay, id, injected));
+        id
+    }
+
+    /// Drops the event scheduled as `id`, if it has not been sent yet. To
+    /// re-arm a timeout, cancel it and schedule it again.
+    pub fn cancel(&self, id: TimerId) {
+        let _ = self.commands.send(TimerCommand::Cancel(id));
+    }
+}
+
+impl<I> Clone for Timer<I> {
+    fn clone(&self) -> Self {
+        Timer {
+            commands: self.commands.clone(),
+            next_id: Arc::clone(&self.next_id),
+        }
+    }
+}
+
+/// The events a [`Timer`] thread has yet to send, apart from the thread so
+/// it can be tested on a manual clock.
+struct Schedule<I> {
+    /// Keyed by deadline, and then by id, so events with the same deadline
+    /// are sent in the order they were scheduled.
+    pending: BTreeMap<(Instant, TimerId), I>,
+    deadlines: HashMap<TimerId, Instant>,
+}
+
+impl<I> Default for Schedule<I> {
+    fn default() -> Self {
+        Schedule {
+            pending: BTreeMap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+}
+
+impl<I> Schedule<I> {
+    fn insert(&mut self, deadline: Instant, id: TimerId, injected: I) {
+        self.pending.insert((deadline, id), injected);
+        self.deadlines.insert(id, deadline);
+    }
+
+    fn cancel(&mut self, id: TimerId) {
+        if let Some(deadline) = self.deadlines.remove(&id) {
+            self.pending.remove(&(deadline, id));
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .first_key_value()
+            .map(|(&(deadline, _), _)| deadline)
+    }
+
+    /// Takes the earliest event due by `now`.
+    fn pop_due(&mut self, now: Instant) -> Option<I> {
+        let entry = self.pending.first_entry()?;
+        let &(deadline, id) = entry.key();
+        if deadline > now {
+            return None;
+        }
+        self.deadlines.remove(&id);
+        Some(entry.remove())
+    }
+}
+
+/// Requests that are sent again under a new `msg_id` whenever no reply
+/// arrives within their timeout, without blocking the step that sent them.
+///
+/// The timeouts run on a [`Timer`], which injects a [`RetryDue`] for each;
+/// the node hands those to [`Retrier::on_due`], and the replies it receives
+/// to [`Retrier::on_reply`]. Meanwhile the node keeps handling other
+/// messages as usual.
+pub struct Retrier<P, I> {
+    timer: Timer<I>,
+    pending: HashMap<u64, Retried<P>>,
+    next_key: u64,
+}
+
+/// A request whose reply a [`Retrier`] is waiting for.
+struct Retried<P> {
+    src: String,
+    dest: String,
+    payload: P,
+    timeout: Duration,
+    max_attempts: usize,
+    /// The `msg_id` of every attempt so far; a late reply to an earlier one
+    /// is accepted as well.
+    ids: Vec<usize>,
+    /// Whether the retry budget held back the next attempt before.
+    warned: bool,
+    /// The timeout running for the last attempt.
+    due: Option<TimerId>,
+}
+
+/// Injected by a [`Retrier`] when a request's timeout has passed, for
+/// [`Retrier::on_due`].
+#[derive(Debug)]
+pub struct RetryDue(u64);
+
+impl<P: Payload + Clone, I: From<RetryDue> + Send + 'static> Retrier<P, I> {
+    /// A retrier whose timeouts run on `timer`.
+    pub fn new(timer: Timer<I>) -> Self {
+        Retrier {
+            timer,
+            pending: HashMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// How many requests are still waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sends `payload` as a request from `src` to `dest`, and sends it again
+    /// every `timeout` until it is answered, at most `max_attempts` times
+    /// in all.
+    pub fn send(
+        &mut self,
+        output: &mut Outbox,
+        src: &str,
+        dest: &str,
+        payload: P,
+        timeout: Duration,
+        max_attempts: usize,
+    ) -> Result<()> {
+        let key = self.next_key;
+        self.next_key += 1;
+        let mut request = Retried {
+            src: src.to_string(),
+            dest: dest.to_string(),
+            payload,
+            timeout,
+            max_attempts,
+            ids: Vec::with_capacity(max_attempts),
+            warned: false,
+            due: None,
+        };
+        self.attempt(key, &mut request, output)?;
+        self.pending.insert(key, request);
+        Ok(())
+    }
+
+    /// Whether `reply` answers a pending request, which is then done.
+    ///
+    /// Replies to requests that are already answered or timed out are not,
+    /// so the node can tell duplicates from the reply that counts.
+    pub fn on_reply<Q>(&mut self, reply: &Message<Q>) -> bool {
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            return false;
+        };
+        let answered = self.pending.iter().find_map(|(key, request)| {
+            (request.dest == reply.src && request.ids.contains(&in_reply_to)).then_some(*key)
+        });
+        let Some(request) = answered.and_then(|key| self.pending.remove(&key)) else {
+            return false;
+        };
+        if let Some(due) = request.due {
+            self.timer.cancel(due);
+        }
+        true
+    }
+
+    /// Sends the request `due` is for again, if it is still unanswered.
+    ///
+    /// Fails with [`TimedOut`] once `max_attempts` attempts went unanswered,
+    /// or with [`Overloaded`] if [`Config::shed_load`] is on and the retry
+    /// budget is empty. A resend the budget holds back is tried again once a
+    /// token is due.
+    pub fn on_due(&mut self, due: RetryDue, output: &mut Outbox) -> Result<()> {
+        let RetryDue(key) = due;
+        let Some(mut request) = self.pending.remove(&key) else {
+            return Ok(());
+        };
+        if request.ids.len() >= request.max_attempts {
+            return Err(TimedOut {
+                dest: request.dest,
+                attempts: request.max_attempts,
+                timeout: request.timeout,
+            }
+            .into());
+        }
+        if let Some(wait) = output.take_retry_token(&request.dest)? {
+            if !request.warned {
+                eprintln!(
+                    "warning: retry budget exhausted, holding back resend to {}",
+                    request.dest
+                );
+                request.warned = true;
+            }
+            request.due = Some(self.timer.schedule_in(wait, RetryDue(key).into()));
+        } else {
+            self.attempt(key, &mut request, output)?;
+        }
+        self.pending.insert(key, request);
+        Ok(())
+    }
+
+    fn attempt(&self, key: u64, request: &mut Retried<P>, output: &mut Outbox) -> Result<()> {
+        let id = output.next_msg_id();
+        output.send_request(&request.src, &request.dest, id, request.payload.clone())?;
+        request.ids.push(id);
+        request.due = Some(
+            self.timer
+                .schedule_in(request.timeout, RetryDue(key).into()),
+        );
+        Ok(())
+    }
+}
+
+/// Hands one event to the node: injected events to [`Node::on_injected`],
+/// everything else to [`Node::step`].
+///
+/// Errors are annotated with the event that caused them.
+pub(crate) fn dispatch<S, N, P>(
+    node: &mut N,
+    input: Event<P, N::Injected>,
+    outbox: &mut Outbox,
+) -> Result<()>
+where
+    P: Payload,
+    N: Node<S, P>,
+{
+    let context = match &input {
+        Event::Message(message) => format!(
+            "handling {} from {}",
+            message.body.payload.kind(),
+            message.src
+        ),
+        Event::Injected(_) => "handling injected event".to_string(),
+        Event::EOF => "handling EOF".to_string(),
+        Event::InjectorFailed(reason) => bail!("{reason}"),
+    };
+    // Kept to answer the request if the node turns out to be overloaded.
+    let request = match &input {
+        Event::Message(message) => Some(Message {
+            src: message.src.clone(),
+            dest: message.dest.clone(),
+            body: Body {
+                id: message.body.id,
+                in_reply_to: None,
+                payload: (),
+            },
+        }),
+        _ => None,
+    };
+    let result = match input {
+        Event::Injected(payload) => node.on_injected(payload, outbox),
+        input => node.step(input, outbox),
+    };
+    match (result, request) {
+        (Err(e), Some(request)) if e.downcast_ref::<Overloaded>().is_some() => {
+            eprintln!("warning: {context}: {e}; answering temporarily-unavailable");
+            outbox
+                .reply_error(
+                    &request,
+                    MaelstromError::TemporarilyUnavailable,
+                    e.to_string(),
+                )
+                .context(context)
+        }
+        (result, _) => result.context(context),
+    }
+}
+
+/// Whether a later injected event on `events`, moved to `backlog`, supersedes `event`.
+pub(crate) fn superseded<P, I>(
+    event: &Event<P, I>,
+    events: &Receiver<Event<P, I>>,
+    backlog: &mut VecDeque<Event<P, I>>,
+) -> bool {
+    if !matches!(event, Event::Injected(_)) {
+        return false;
+    }
+    backlog.extend(events.try_iter());
+    backlog
+        .iter()
+        .any(|queued| matches!(queued, Event::Injected(_)))
+}
+
+/// Fails if the node reports that its invariants are broken.
+#[cfg(debug_assertions)]
+pub(crate) fn check_invariants<S, N, P>(node: &N) -> Result<()>
+where
+    N: Node<S, P>,
+{
+    node.check_invariants()
+        .map_err(|violation| crate::format_err!("node invariant violated: {violation}"))
+}
+
+/// Parses the `init` message, constructs the node from it and
+/// writes the `init_ok` reply. Returns the node and its id.
+pub(crate) fn handshake<S, N, P>(
+    init_state: S,
+    init_line: &str,
+    inject: Sender<Event<P, N::Injected>>,
+    outbox: &mut Outbox,
+) -> Result<(N, String)>
+where
+    N: Node<S, P>,
+{
+    let raw: Message<Value> =
+        parse_message(init_line).context("init message could not be deserialized")?;
+    let mut extra = raw.body.payload.as_object().cloned().unwrap_or_default();
+    for known in ["type", "msg_id", "in_reply_to", "node_id", "node_ids"] {
+        extra.remove(known);
+    }
+    let init_msg: Message<InitPayload> = raw
+        .decode()
+        .context("init message could not be deserialized")?;
+    let InitPayload::Init { node_id, node_ids } = init_msg.body.payload.clone() else {
+        bail!("first message should be init");
+    };
+    let invalid = if node_ids.is_empty() {
+        Some(format!(
+            "init has an empty node_ids, which must at least contain this node's id {node_id:?}"
+        ))
+    } else if !node_ids.contains(&node_id) {
+        Some(format!(
+            "init node_ids {node_ids:?} does not contain this node's id {node_id:?}"
+        ))
+    } else {
+        None
+    };
+    if let Some(invalid) = invalid {
+        // Answered, so that Maelstrom reports why the node did not start.
+        outbox.reply_error(&init_msg, MaelstromError::MalformedRequest, invalid.clone())?;
+        outbox.flush()?;
+        bail!("{invalid}");
+    }
+    outbox.node_id = node_id.clone();
+    outbox.node_ids = node_ids.clone();
+    let init = Init {
+        node_id: node_id.clone(),
+        node_ids,
+        extra,
+    };
+    let node: N =
+        Node::from_init(init_state, init, inject).context("node initialization failed")?;
+    if let Some(hook) = node.send_hook() {
+        let _ = outbox.encoder.hook.set(hook);
+    }
+
+    let mut payload = InitPayload::InitOk.to_json();
+    match node.init_ok_extra() {
+        Some(Value::Object(extra)) => {
+            let fields = payload.as_object_mut().expect("payloads are objects");
+            for (key, value) in extra {
+                if key == "type" {
+                    eprintln!("warning: init_ok_extra may not replace the message type");
+                    continue;
+                }
+                fields.insert(key, value);
+            }
+        }
+        Some(other) => eprintln!("warning: ignoring init_ok_extra {other}, it is not an object"),
+        None => {}
+    }
+    let reply = Message {
+        src: init_msg.dest,
+        dest: init_msg.src,
+        body: Body {
+            id: Some(outbox.rpc.next_id()),
+            in_reply_to: init_msg.body.id,
+            payload,
+        },
+    };
+    reply.send(outbox)?;
+    outbox.flush()?;
+    Ok((node, node_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, init_msg};
+
+    payload! {
+        #[derive(Debug, Clone)]
+        enum EchoPayload {
+            Echo = "echo" { echo: String },
+            EchoOk = "echo_ok" { echo: String },
+        }
+    }
+
+    struct EchoNode;
+
+    impl Node<(), EchoPayload> for EchoNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(EchoNode)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            if let EchoPayload::Echo { echo } = &input.body.payload {
+                let echo = echo.clone();
+                output.reply(&input, EchoPayload::EchoOk { echo })?;
+            }
+            Ok(())
+        }
+    }
+
+    /// `init_msg`, with the init's `msg_id` set to `msg_id`.
+    fn init_with_id(node_id: &str, node_ids: &[&str], msg_id: usize) -> String {
+        let mut init = json::parse(&init_msg(node_id, node_ids)).unwrap();
+        let body = init.as_object_mut().unwrap().get_mut("body").unwrap();
+        body.as_object_mut()
+            .unwrap()
+            .insert("msg_id".to_string(), msg_id.to_json());
+        init.to_string()
+    }
+
+    #[test]
+    fn init_ok_answers_the_init_under_a_fresh_msg_id() {
+        let init_ok =
+            test_support::handshake::<_, EchoNode, _>((), &init_with_id("n1", &["n1", "n2"], 7))
+                .unwrap();
+        assert_eq!(init_ok.body.payload.kind(), "init_ok");
+        assert_eq!((init_ok.src.as_str(), init_ok.dest.as_str()), ("n1", "c0"));
+        assert_eq!(init_ok.body.in_reply_to, Some(7));
+        let msg_id = init_ok.body.id.expect("init_ok has a msg_id");
+        assert_ne!(msg_id, 7, "init_ok reused the init's msg_id");
+    }
+
+    /// Reports a capability in its `init_ok`, and tries to rename it.
+    struct CapableNode;
+
+    impl Node<(), EchoPayload> for CapableNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(CapableNode)
+        }
+
+        fn init_ok_extra(&self) -> Option<Value> {
+            let mut extra = json::Map::new();
+            extra.insert("capabilities".to_string(), vec!["echo"].to_json());
+            extra.insert("type".to_string(), "hello".to_json());
+            Some(Value::Object(extra))
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            EchoNode.step(input, output)
+        }
+    }
+
+    #[test]
+    fn init_ok_extra_fields_are_added_to_init_ok() {
+        let node = test_support::TestNode::<_, CapableNode, EchoPayload>::start(
+            (),
+            &init_msg("n1", &["n1"]),
+        )
+        .unwrap();
+        let init_ok = &node.init_ok().body.payload;
+        assert_eq!(init_ok.kind(), "init_ok");
+        let capabilities = init_ok.get("capabilities").and_then(Value::as_array);
+        assert_eq!(capabilities, Some(&vec!["echo".to_json()]));
+    }
+
+    /// Greets its peer n2 as soon as it is initialized.
+    struct GreetingNode;
+
+    impl Node<(), EchoPayload> for GreetingNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(GreetingNode)
+        }
+
+        fn on_init_complete(&mut self, output: &mut Outbox) -> Result<()> {
+            Message {
+                src: "n1".to_string(),
+                dest: "n2".to_string(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: EchoPayload::Echo {
+                        echo: "hello".to_string(),
+                    },
+                },
+            }
+            .send(output)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            EchoNode.step(input, output)
+        }
+    }
+
+    #[test]
+    fn on_init_complete_runs_after_init_ok_is_sent() {
+        let output = test_support::SharedBuffer::new();
+        run::<_, GreetingNode, EchoPayload, _, _>(
+            Config::default(),
+            (),
+            std::io::Cursor::new(init_msg("n1", &["n1", "n2"])),
+            output.clone(),
+        )
+        .unwrap();
+        let messages = output.messages().unwrap();
+        let sent: Vec<_> = messages
+            .iter()
+            .map(|message| (message.dest.as_str(), message.body.payload.kind()))
+            .collect();
+        assert_eq!(sent, [("c0", "init_ok"), ("n2", "echo")]);
+    }
+
+    #[test]
+    fn an_init_without_this_node_is_answered_with_an_error() {
+        for node_ids in [&[][..], &["n2"][..]] {
+            let init = init_msg("n1", node_ids);
+            let output = test_support::SharedBuffer::new();
+            let error = run::<_, EchoNode, EchoPayload, _, _>(
+                Config::default(),
+                (),
+                std::io::Cursor::new(init),
+                output.clone(),
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(error.contains("node_ids"), "{error}");
+
+            let messages = output.messages().unwrap();
+            assert_eq!(messages.len(), 1, "{messages:?}");
+            let reply = &messages[0];
+            assert_eq!(reply.body.payload.kind(), "error");
+            assert_eq!(reply.body.in_reply_to, Some(1));
+            let code = reply.body.payload.get("code").and_then(Value::as_u64);
+            assert_eq!(code, Some(MaelstromError::MalformedRequest.code()));
+        }
+    }
+
+    #[test]
+    fn nothing_is_answered_before_init_ok() {
+        // The reader thread rejects the unknown request itself, so without
+        // waiting for the handshake it could answer before the main thread.
+        let bogus = r#"{"src":"c1","dest":"n1","body":{"type":"bogus","msg_id":1}}"#;
+        let input = format!("{}\n{bogus}\n", init_msg("n1", &["n1"]));
+        for _ in 0..20 {
+            let output = test_support::SharedBuffer::new();
+            run::<_, EchoNode, EchoPayload, _, _>(
+                Config::default(),
+                (),
+                std::io::Cursor::new(input.clone()),
+                output.clone(),
+            )
+            .unwrap();
+            let kinds: Vec<_> = output
+                .messages()
+                .unwrap()
+                .iter()
+                .map(|message| message.body.payload.kind().to_string())
+                .collect();
+            assert_eq!(kinds, ["init_ok", "error"]);
+        }
+    }
+
+    #[test]
+    fn messages_for_other_nodes_are_dropped_before_anything_else() {
+        // Neither a request the node would answer nor one the stdin thread
+        // would reject is answered when it is addressed to another node.
+        let input = [
+            init_msg("n1", &["n1", "n2"]),
+            r#"{"src":"c1","dest":"n2","body":{"type":"bogus","msg_id":1}}"#.to_string(),
+            r#"{"src":"c1","dest":"n2","body":{"type":"echo","echo":"hi","msg_id":2}}"#.to_string(),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":3}}"#.to_string(),
+        ]
+        .join("\n");
+        let output = test_support::SharedBuffer::new();
+        run::<_, EchoNode, EchoPayload, _, _>(
+            Config::default(),
+            (),
+            std::io::Cursor::new(input),
+            output.clone(),
+        )
+        .unwrap();
+        let replies: Vec<_> = output
+            .messages()
+            .unwrap()
+            .into_iter()
+            .map(|message| {
+                (
+                    message.body.payload.kind().to_string(),
+                    message.body.in_reply_to,
+                )
+            })
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                ("init_ok".to_string(), Some(1)),
+                ("echo_ok".to_string(), Some(3))
+            ]
+        );
+    }
+
+    /// Echoes like [`EchoNode`], but first sends the echo on to n2 and
+    /// flushes its reply within the step, and tags everything it sends.
+    struct TracingNode;
+
+    impl Node<(), EchoPayload> for TracingNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(TracingNode)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            if let EchoPayload::Echo { echo } = &input.body.payload {
+                let echo = echo.clone();
+                drop(output.rpc("n1", "n2", EchoPayload::Echo { echo: echo.clone() })?);
+                output.reply(&input, EchoPayload::EchoOk { echo })?;
+                output.flush()?;
+            }
+            Ok(())
+        }
+
+        fn send_hook(&self) -> Option<SendHook> {
+            Some(Arc::new(|message| {
+                let payload = message.body.payload.as_object_mut().unwrap();
+                payload.insert("trace".to_string(), "t1".to_json());
+            }))
+        }
+    }
+
+    /// Runs a [`TracingNode`] on `input` lines after its `init`, returning
+    /// what it wrote as `(type, dest, trace)`.
+    fn traced(config: Config, input: &[&str]) -> Vec<(String, String, Option<String>)> {
+        let mut lines = vec![init_msg("n1", &["n1", "n2"])];
+        lines.extend(input.iter().map(|line| line.to_string()));
+        let output = test_support::SharedBuffer::new();
+        run::<_, TracingNode, EchoPayload, _, _>(
+            config,
+            (),
+            std::io::Cursor::new(lines.join("\n")),
+            output.clone(),
+        )
+        .unwrap();
+        output
+            .messages()
+            .unwrap()
+            .into_iter()
+            .map(|message| {
+                let trace = message.body.payload.get("trace").and_then(Value::as_str);
+                let trace = trace.map(str::to_string);
+                (message.body.payload.kind().to_string(), message.dest, trace)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_message_passes_through_the_send_hook() {
+        let trace = Some("t1".to_string());
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#;
+        let bogus = r#"{"src":"c1","dest":"n1","body":{"type":"bogus","msg_id":2}}"#;
+        // The stdin thread's error reply may come before or after the echo.
+        let mut sent = traced(Config::default(), &[echo, bogus]);
+        sent.sort();
+        let expected = [
+            ("echo", "n2"),
+            ("echo_ok", "c1"),
+            ("error", "c1"),
+            ("init_ok", "c0"),
+        ]
+        .map(|(kind, dest)| (kind.to_string(), dest.to_string(), trace.clone()));
+        assert_eq!(sent, expected);
+
+        let sent = traced(Config::default().forward_unknown("n2"), &[bogus]);
+        assert_eq!(sent[1], ("bogus".to_string(), "n2".to_string(), trace));
+    }
+
+    #[test]
+    fn send_hook_changes_are_written_out() {
+        let input = format!(
+            "{}\n{}\n",
+            init_msg("n1", &["n1", "n2"]),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#
+        );
+        let output = test_support::SharedBuffer::new();
+        run::<_, TracingNode, EchoPayload, _, _>(
+            Config::default(),
+            (),
+            std::io::Cursor::new(input),
+            output.clone(),
+        )
+        .unwrap();
+        let contents = output.contents();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3, "{contents}");
+        for line in lines {
+            assert!(line.contains(r#""trace":"t1""#), "{line}");
+        }
+    }
+
+    #[test]
+    fn rates_are_logged_per_window_of_the_clock() {
+        let clock = crate::time::ManualClock::new();
+        let mut outbox = Outbox::new(
+            Box::new(std::io::sink()),
+            rpc::Registry::default(),
+            &Config::default(),
+        );
+        let mut rates = RateLog::new(Arc::new(clock.clone()), &outbox);
+        let request = test_support::request(
+            "c1",
+            "n1",
+            EchoPayload::Echo {
+                echo: String::new(),
+            },
+            1,
+        );
+        for _ in 0..30 {
+            rates.messages_in += 1;
+            clock.advance(Duration::from_millis(10));
+        }
+        for _ in 0..15 {
+            outbox
+                .reply(
+                    &request,
+                    EchoPayload::EchoOk {
+                        echo: String::new(),
+                    },
+                )
+                .unwrap();
+        }
+        outbox.flush().unwrap();
+        assert_eq!(rates.tick(&outbox), None);
+        assert_eq!(rates.remaining(), Duration::from_millis(700));
+
+        clock.advance(Duration::from_millis(700));
+        assert_eq!(
+            rates.tick(&outbox).as_deref(),
+            Some("rate: 30.0 msgs/s in, 15.0 msgs/s out")
+        );
+        // The next window starts from zero, and spans two seconds here.
+        rates.messages_in += 5;
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(
+            rates.tick(&outbox).as_deref(),
+            Some("rate: 2.5 msgs/s in, 0.0 msgs/s out")
+        );
+    }
+
+    #[test]
+    fn paced_replies_wait_for_the_next_window() {
+        let clock = crate::time::ManualClock::new();
+        let mut pacer = ReplyPacer::new(2, Arc::new(clock.clone()));
+        let admitted: Vec<_> = (1..=5)
+            .filter_map(|reply| pacer.admit(reply.to_string()))
+            .collect();
+        assert_eq!(admitted, ["1", "2"]);
+        assert_eq!(pacer.remaining(), Some(ReplyPacer::WINDOW));
+
+        let mut released = Vec::new();
+        clock.advance(Duration::from_millis(400));
+        pacer.release(&mut released);
+        assert!(released.is_empty());
+        assert_eq!(pacer.remaining(), Some(Duration::from_millis(600)));
+
+        // A new reply queues behind the held ones rather than overtaking.
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(pacer.admit("6".to_string()), None);
+        pacer.release(&mut released);
+        assert_eq!(released, ["3", "4"]);
+
+        clock.advance(ReplyPacer::WINDOW);
+        pacer.release(&mut released);
+        assert_eq!(released, ["3", "4", "5", "6"]);
+        assert_eq!(pacer.remaining(), None);
+    }
+
+    #[test]
+    fn only_the_newest_forwarded_messages_await_a_reply() {
+        let output = test_support::SharedBuffer::new();
+        let forwarder = Forwarder {
+            peer: "n2".to_string(),
+            writer: SharedWriter(Arc::new(Mutex::new(output.clone()))),
+            rpc: rpc::Registry::default(),
+            encoder: Encoder::new(LineEnding::Lf),
+            pending: Mutex::default(),
+            capacity: 2,
+        };
+        for msg_id in 1..=3 {
+            let bogus = test_support::request(
+                "c1",
+                "n1",
+                json::parse(r#"{"type":"bogus"}"#).unwrap(),
+                msg_id,
+            );
+            assert!(forwarder.route::<EchoPayload>(bogus).unwrap().is_none());
+        }
+        assert_eq!(forwarder.pending.lock().unwrap().len(), 2);
+        let forwarded = output.messages().unwrap();
+        assert_eq!(forwarded.len(), 3);
+
+        // Replies to the two newest are relayed, the oldest's is dropped.
+        for message in &forwarded {
+            let mut reply = test_support::request(
+                "n2",
+                "n1",
+                json::parse(r#"{"type":"bogus_ok"}"#).unwrap(),
+                100,
+            );
+            reply.body.in_reply_to = message.body.id;
+            assert!(forwarder.route::<EchoPayload>(reply).unwrap().is_none());
+        }
+        let relayed: Vec<_> = output.messages().unwrap()[3..]
+            .iter()
+            .map(|message| (message.dest.clone(), message.body.in_reply_to))
+            .collect();
+        assert_eq!(
+            relayed,
+            [("c1".to_string(), Some(2)), ("c1".to_string(), Some(3))]
+        );
+        assert!(forwarder.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_json_log_starts_with_the_handshake() {
+        let path = std::env::temp_dir().join(format!("json-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let input = format!(
+            "{}\n{}\n",
+            init_msg("n1", &["n1"]),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#
+        );
+        run::<_, EchoNode, EchoPayload, _, _>(
+            Config::default().json_log(&path),
+            (),
+            std::io::Cursor::new(input),
+            std::io::sink(),
+        )
+        .unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let events: Vec<_> = log
+            .lines()
+            .map(|line| {
+                let event = json::parse(line).unwrap();
+                let field = |name| event.get(name).and_then(Value::as_str).map(str::to_string);
+                assert!(event.get("timestamp").and_then(Value::as_u64).is_some());
+                (field("message"), field("type"), field("src"))
+            })
+            .collect();
+        let event = |message: &str, kind: &str, src: &str| {
+            (
+                Some(message.to_string()),
+                Some(kind.to_string()),
+                Some(src.to_string()),
+            )
+        };
+        assert_eq!(
+            events,
+            [
+                event("received", "init", "c0"),
+                event("sent", "init_ok", "n1"),
+                event("received", "echo", "c1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_messages_fail_or_are_sent_with_a_warning() {
+        let request = test_support::request(
+            "c1",
+            "n1",
+            EchoPayload::Echo {
+                echo: String::new(),
+            },
+            1,
+        );
+        let echo_ok = |len: usize| EchoPayload::EchoOk {
+            echo: "x".repeat(len),
+        };
+        for on_exceed in [OnOversize::Fail, OnOversize::Warn] {
+            let output = test_support::SharedBuffer::new();
+            let config = Config::default().max_message_size(200, on_exceed);
+            let mut outbox =
+                Outbox::new(Box::new(output.clone()), rpc::Registry::default(), &config);
+
+            outbox.reply(&request, echo_ok(10)).unwrap();
+            let oversized = outbox.reply(&request, echo_ok(300));
+            outbox.flush().unwrap();
+            let written = output.messages().unwrap().len();
+            match on_exceed {
+                OnOversize::Fail => {
+                    let error = oversized.unwrap_err();
+                    let too_large = error.downcast_ref::<MessageTooLarge>().unwrap();
+                    assert_eq!((too_large.dest.as_str(), too_large.limit), ("c1", 200));
+                    assert!(too_large.size > 300, "{too_large}");
+                    assert_eq!(written, 1);
+                }
+                OnOversize::Warn => {
+                    oversized.unwrap();
+                    assert_eq!(written, 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn coalesced_messages_go_out_in_one_write() {
+        let request = test_support::request(
+            "c1",
+            "n1",
+            EchoPayload::Echo {
+                echo: String::new(),
+            },
+            1,
+        );
+        for (coalesce, writes) in [(true, 1), (false, 3)] {
+            let output = test_support::SharedBuffer::new();
+            let config = Config::default().coalesce_writes(coalesce);
+            let mut outbox =
+                Outbox::new(Box::new(output.clone()), rpc::Registry::default(), &config);
+            for echo in ["a", "b", "c"] {
+                let echo = echo.to_string();
+                outbox
+                    .reply(&request, EchoPayload::EchoOk { echo })
+                    .unwrap();
+            }
+            outbox.flush().unwrap();
+            assert_eq!(output.writes(), writes, "coalesce_writes({coalesce})");
+            assert_eq!(output.messages().unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn the_inbound_filter_drops_and_delivers() {
+        let echoes = [(2, "lost"), (3, "kept")].map(|(msg_id, echo)| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","echo":"{echo}","msg_id":{msg_id}}}}}"#)
+        });
+        let input = format!("{}\n{}\n", init_msg("n1", &["n1"]), echoes.join("\n"));
+        let config = Config::default().inbound_filter(|message| {
+            match message.body.payload.get("echo").and_then(Value::as_str) {
+                Some("lost") => Inbound::Drop,
+                _ => Inbound::Deliver,
+            }
+        });
+        let output = test_support::SharedBuffer::new();
+        run::<_, EchoNode, EchoPayload, _, _>(
+            config,
+            (),
+            std::io::Cursor::new(input),
+            output.clone(),
+        )
+        .unwrap();
+        let messages = output.messages().unwrap();
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert_eq!(messages[0].body.payload.kind(), "init_ok");
+        assert_eq!(messages[1].body.in_reply_to, Some(3));
+        let echo = messages[1].body.payload.get("echo").and_then(Value::as_str);
+        assert_eq!(echo, Some("kept"));
+    }
+
+    #[test]
+    fn strict_fields_rejects_an_unknown_field() {
+        let echoes = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","extra":1,"msg_id":3}}"#,
+        ];
+        let input = format!("{}\n{}\n", init_msg("n1", &["n1"]), echoes.join("\n"));
+        for strict in [true, false] {
+            let output = test_support::SharedBuffer::new();
+            run::<_, EchoNode, EchoPayload, _, _>(
+                Config::default().strict_fields(strict),
+                (),
+                std::io::Cursor::new(input.clone()),
+                output.clone(),
+            )
+            .unwrap();
+            let messages = output.messages().unwrap();
+            assert_eq!(messages.len(), 3, "{messages:?}");
+            // The rejection is written by the stdin thread, so it may come
+            // before the first echo_ok.
+            let reply_to = |msg_id| {
+                let reply = messages.iter().find(|m| m.body.in_reply_to == Some(msg_id));
+                &reply.expect("every echo is answered").body
+            };
+            assert_eq!(reply_to(2).payload.kind(), "echo_ok");
+            let reply = reply_to(3);
+            if strict {
+                assert_eq!(reply.payload.kind(), "error");
+                let code = reply.payload.get("code").and_then(Value::as_u64);
+                assert_eq!(code, Some(MaelstromError::MalformedRequest.code()));
+                let text = reply.payload.get("text").and_then(Value::as_str).unwrap();
+                assert!(text.contains("unknown field `extra`"), "{text}");
+            } else {
+                assert_eq!(reply.payload.kind(), "echo_ok");
+            }
+        }
+    }
+
+    #[test]
+    fn the_retry_budget_runs_out_and_refills() {
+        let clock = crate::time::ManualClock::new();
+        let mut budget = RetryBudget::new(2, Duration::from_millis(100), Arc::new(clock.clone()));
+        assert_eq!(budget.take(), Ok(()));
+        assert_eq!(budget.take(), Ok(()));
+        assert_eq!(budget.take(), Err(Duration::from_millis(100)));
+
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(budget.take(), Err(Duration::from_millis(40)));
+        clock.advance(Duration::from_millis(40));
+        assert_eq!(budget.take(), Ok(()));
+        assert_eq!(budget.tokens(), 0);
+
+        // However long it sits, the bucket holds no more than its capacity.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(budget.tokens(), 2);
+        assert_eq!(budget.take(), Ok(()));
+        assert_eq!(budget.take(), Ok(()));
+        assert!(budget.take().is_err());
+    }
+
+    /// Has no capacity for anything.
+    struct OverloadedNode;
+
+    impl Node<(), EchoPayload> for OverloadedNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(OverloadedNode)
+        }
+
+        fn step(&mut self, _: Event<EchoPayload>, _: &mut Outbox) -> Result<()> {
+            Err(Overloaded("queue full".to_string()).into())
+        }
+    }
+
+    #[test]
+    fn an_overloaded_request_is_answered_temporarily_unavailable() {
+        let mut node = test_support::TestNode::<_, OverloadedNode, EchoPayload>::start(
+            (),
+            &init_msg("n1", &["n1"]),
+        )
+        .unwrap();
+        let echo = EchoPayload::Echo {
+            echo: "hi".to_string(),
+        };
+        node.step(Event::Message(test_support::request("c1", "n1", echo, 4)))
+            .unwrap();
+        let output = node.take_output().unwrap();
+        assert_eq!(output.len(), 1, "{output:?}");
+        let reply = &output[0];
+        assert_eq!(
+            (reply.dest.as_str(), reply.body.in_reply_to),
+            ("c1", Some(4))
+        );
+        assert_eq!(reply.body.payload.kind(), "error");
+        let code = reply.body.payload.get("code").and_then(Value::as_u64);
+        assert_eq!(code, Some(11));
+        let text = reply.body.payload.get("text").and_then(Value::as_str);
+        assert_eq!(text, Some("overloaded: queue full"));
+
+        // With no request to answer, the error is the caller's.
+        let error = node.step(Event::EOF).unwrap_err();
+        assert!(error.downcast_ref::<Overloaded>().is_some(), "{error}");
+    }
+
+    #[test]
+    fn timer_events_can_be_cancelled_and_re_armed() {
+        let clock = crate::time::ManualClock::new();
+        let at = |millis| clock.now() + Duration::from_millis(millis);
+        let mut schedule = Schedule::default();
+        schedule.insert(at(10), TimerId(0), "retry");
+        schedule.insert(at(20), TimerId(1), "gossip");
+        schedule.insert(at(20), TimerId(2), "gossip again");
+
+        schedule.cancel(TimerId(0));
+        clock.advance(Duration::from_millis(15));
+        assert_eq!(schedule.pop_due(clock.now()), None);
+        // Re-armed under a new id, and due after the events already there.
+        schedule.insert(at(10), TimerId(3), "retry");
+        assert_eq!(schedule.next_deadline(), Some(at(5)));
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(schedule.pop_due(clock.now()), Some("gossip"));
+        assert_eq!(schedule.pop_due(clock.now()), Some("gossip again"));
+        assert_eq!(schedule.pop_due(clock.now()), None);
+        // Cancelling an event already sent does nothing.
+        schedule.cancel(TimerId(1));
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(schedule.pop_due(clock.now()), Some("retry"));
+        assert_eq!(schedule.next_deadline(), None);
+        assert!(schedule.deadlines.is_empty());
+    }
+
+    #[test]
+    fn a_missing_init_times_out() {
+        // Stdin stays open but silent, as when not run by Maelstrom.
+        let (input, silent) = std::io::pipe().unwrap();
+        let started = Instant::now();
+        let result = run::<_, EchoNode, EchoPayload, _, _>(
+            Config::default().init_timeout(Duration::from_millis(50)),
+            (),
+            std::io::BufReader::new(input),
+            std::io::sink(),
+        );
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("no init message received within"), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(silent);
+    }
+
+    /// Answers echoes, and tells n2 once it sees the EOF.
+    struct EofNode;
+
+    impl Node<(), EchoPayload> for EofNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(EofNode)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            match input {
+                Event::Message(input) => EchoNode.step(Event::Message(input), output),
+                Event::EOF => Message {
+                    src: "n1".to_string(),
+                    dest: "n2".to_string(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        payload: EchoPayload::Echo {
+                            echo: "eof".to_string(),
+                        },
+                    },
+                }
+                .send(output),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Starts an injector that panics every time it runs.
+    struct PanickingNode;
+
+    impl Node<(), EchoPayload> for PanickingNode {
+        type Injected = ();
+
+        fn from_init(_: (), _: Init, inject: Sender<Event<EchoPayload>>) -> Result<Self> {
+            spawn_injector("doomed", 2, inject, || panic!("injector bug"));
+            Ok(PanickingNode)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            EchoNode.step(input, output)
+        }
+    }
+
+    #[test]
+    fn a_dead_injector_fails_the_run() {
+        // Stdin stays open, so only the injector can end the run.
+        let (input, mut client) = std::io::pipe().unwrap();
+        writeln!(client, "{}", init_msg("n1", &["n1"])).unwrap();
+        let result = run::<_, PanickingNode, EchoPayload, _, _>(
+            Config::default(),
+            (),
+            std::io::BufReader::new(input),
+            std::io::sink(),
+        );
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.contains("injector thread doomed panicked 3 times"),
+            "{error}"
+        );
+        assert!(error.contains("injector bug"), "{error}");
+        drop(client);
+    }
+
+    /// Runs an [`EofNode`] on three echoes, returning the destinations of
+    /// what it wrote after `init_ok`.
+    fn three_echoes_then_eof(config: Config) -> Vec<String> {
+        let mut lines = vec![init_msg("n1", &["n1", "n2"])];
+        lines.extend((1..=3).map(|msg_id| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","echo":"hi","msg_id":{msg_id}}}}}"#)
+        }));
+        let output = test_support::SharedBuffer::new();
+        run::<_, EofNode, EchoPayload, _, _>(
+            config,
+            (),
+            std::io::Cursor::new(lines.join("\n")),
+            output.clone(),
+        )
+        .unwrap();
+        let messages = output.messages().unwrap();
+        assert_eq!(messages[0].body.payload.kind(), "init_ok");
+        messages[1..]
+            .iter()
+            .map(|message| message.dest.clone())
+            .collect()
+    }
+
+    #[test]
+    fn every_line_is_handled_before_the_eof() {
+        assert_eq!(
+            three_echoes_then_eof(Config::default()),
+            ["c1", "c1", "c1", "n2"]
+        );
+    }
+
+    #[test]
+    fn held_back_replies_are_written_out_at_eof() {
+        // The clock never moves, so only the EOF lets the held replies out.
+        let config = Config::default()
+            .clock(Arc::new(crate::time::ManualClock::new()))
+            .max_replies_per_sec(1);
+        let mut sent = three_echoes_then_eof(config);
+        sent.sort();
+        assert_eq!(sent, ["c1", "c1", "c1", "n2"]);
+    }
+
+    #[test]
+    fn either_line_ending_is_read_and_written() {
+        for line_ending in [LineEnding::Lf, LineEnding::CrLf] {
+            let ending = line_ending.as_str();
+            let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#;
+            // Input framed with the same ending, which the reader accepts.
+            let input = format!("{}{ending}{echo}{ending}", init_msg("n1", &["n1"]));
+            let output = test_support::SharedBuffer::new();
+            run::<_, EchoNode, EchoPayload, _, _>(
+                Config::default().line_ending(line_ending),
+                (),
+                std::io::Cursor::new(input),
+                output.clone(),
+            )
+            .unwrap();
+            let contents = output.contents();
+            assert!(contents.ends_with(ending), "{contents:?}");
+            assert_eq!(contents.matches(ending).count(), 2, "{contents:?}");
+            if line_ending == LineEnding::Lf {
+                assert!(!contents.contains('\r'), "{contents:?}");
+            }
+            // And the output reads back as messages.
+            let kinds: Vec<_> = contents
+                .lines()
+                .map(|line| {
+                    parse_message::<Value>(line)
+                        .unwrap()
+                        .body
+                        .payload
+                        .kind()
+                        .to_string()
+                })
+                .collect();
+            assert_eq!(kinds, ["init_ok", "echo_ok"]);
+        }
+    }
+
+    #[test]
+    fn stats_count_the_cached_replies() {
+        let outbox = |config: Config| {
+            Outbox::new(Box::new(std::io::sink()), rpc::Registry::default(), &config)
+        };
+        assert_eq!(outbox(Config::default()).stats().reply_cache_len, None);
+
+        let mut outbox = outbox(Config::default().reply_cache(2, Duration::from_secs(60)));
+        assert_eq!(outbox.stats().reply_cache_len, Some(0));
+        for msg_id in 1..=3 {
+            let echo = EchoPayload::Echo {
+                echo: "hi".to_string(),
+            };
+            let request = test_support::request("c1", "n1", echo, msg_id);
+            let echo_ok = EchoPayload::EchoOk {
+                echo: "hi".to_string(),
+            };
+            outbox.reply(&request, echo_ok).unwrap();
+            outbox.flush().unwrap();
+        }
+        assert_eq!(outbox.stats().reply_cache_len, Some(2));
+        assert!(outbox.cached_reply("c1", 1).is_none(), "oldest reply kept");
+        assert!(outbox.cached_reply("c1", 3).is_some());
+    }
+
+    #[test]
+    fn requests_nobody_answers_do_not_stay_registered() {
+        let rpc = rpc::Registry::default();
+        let mut outbox = Outbox::new(Box::new(std::io::sink()), rpc.clone(), &Config::default());
+        let echo = || EchoPayload::Echo {
+            echo: "hi".to_string(),
+        };
+        let timeout = Duration::from_millis(1);
+
+        let pending = outbox.rpc("n1", "n2", echo()).unwrap();
+        assert_eq!(rpc.waiting(), 1);
+        assert!(pending.wait(timeout).is_err());
+        assert_eq!(rpc.waiting(), 0, "after PendingReply::wait");
+
+        let failover = crate::failover::Failover::new("n2", "n3").with_timeout(timeout);
+        assert!(failover.call("n1", &echo(), &mut outbox).is_err());
+        assert_eq!(rpc.waiting(), 0, "after Failover::call");
+    }
+
+    /// Answers a client's echo with n2's answer to the same echo, which it
+    /// asks for through a [`Retrier`].
+    struct RelayNode {
+        retrier: Retrier<EchoPayload, RetryDue>,
+        client: Option<Message<EchoPayload>>,
+        timed_out: usize,
+    }
+
+    const RELAY_TIMEOUT: Duration = Duration::from_millis(20);
+
+    impl Node<(), EchoPayload> for RelayNode {
+        type Injected = RetryDue;
+
+        fn from_init(_: (), _: Init, inject: Sender<Event<EchoPayload, RetryDue>>) -> Result<Self> {
+            Ok(RelayNode {
+                retrier: Retrier::new(Timer::new(inject)),
+                client: None,
+                timed_out: 0,
+            })
+        }
+
+        fn on_injected(&mut self, due: RetryDue, output: &mut Outbox) -> Result<()> {
+            match self.retrier.on_due(due, output) {
+                Err(e) if e.downcast_ref::<TimedOut>().is_some() => {
+                    self.timed_out += 1;
+                    Ok(())
+                }
+                other => other,
+            }
+        }
+
+        fn step(&mut self, input: Event<EchoPayload, RetryDue>, output: &mut Outbox) -> Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            match &input.body.payload {
+                EchoPayload::Echo { echo } if input.src == "c1" => {
+                    let echo = EchoPayload::Echo { echo: echo.clone() };
+                    self.retrier
+                        .send(output, "n1", "n2", echo, RELAY_TIMEOUT, 3)?;
+                    self.client = Some(input);
+                }
+                EchoPayload::Echo { echo } => {
+                    let echo = echo.clone();
+                    output.reply(&input, EchoPayload::EchoOk { echo })?;
+                }
+                EchoPayload::EchoOk { echo } => {
+                    if self.retrier.on_reply(&input) {
+                        let client = self.client.take().expect("a client waiting");
+                        let echo = echo.clone();
+                        output.reply(&client, EchoPayload::EchoOk { echo })?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    type Relay = test_support::TestNode<(), RelayNode, EchoPayload>;
+
+    fn echo(echo: &str) -> EchoPayload {
+        EchoPayload::Echo {
+            echo: echo.to_string(),
+        }
+    }
+
+    /// Handles injected events until one arrives, or fails after a second.
+    fn step_until_injected(node: &mut Relay) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while node.step_pending().unwrap() == 0 {
+            assert!(Instant::now() < deadline, "nothing was injected");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn a_retried_request_succeeds_on_the_second_attempt() {
+        let mut node = Relay::start((), &init_msg("n1", &["n1", "n2"])).unwrap();
+        node.step(Event::Message(test_support::request(
+            "c1",
+            "n1",
+            echo("hi"),
+            1,
+        )))
+        .unwrap();
+        let first = node.take_output().unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].dest, "n2");
+
+        // The first attempt is lost; another request is answered meanwhile.
+        node.step(Event::Message(test_support::request(
+            "n3",
+            "n1",
+            echo("ho"),
+            2,
+        )))
+        .unwrap();
+        let answered = node.take_output().unwrap();
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].dest, "n3");
+
+        step_until_injected(&mut node);
+        let second = node.take_output().unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].dest, "n2");
+        assert_ne!(second[0].body.id, first[0].body.id);
+
+        let mut reply = test_support::request(
+            "n2",
+            "n1",
+            EchoPayload::EchoOk {
+                echo: "hi".to_string(),
+            },
+            10,
+        );
+        reply.body.in_reply_to = second[0].body.id;
+        node.step(Event::Message(reply.clone())).unwrap();
+        let relayed = node.take_output().unwrap();
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].dest, "c1");
+        assert_eq!(relayed[0].body.in_reply_to, Some(1));
+        assert_eq!(node.node().retrier.pending(), 0);
+
+        // A duplicate reply, or a late one to the first attempt, is ignored.
+        node.step(Event::Message(reply)).unwrap();
+        assert!(node.take_output().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_retried_request_times_out_after_its_last_attempt() {
+        let mut node = Relay::start((), &init_msg("n1", &["n1", "n2"])).unwrap();
+        node.step(Event::Message(test_support::request(
+            "c1",
+            "n1",
+            echo("hi"),
+            1,
+        )))
+        .unwrap();
+        let mut attempts = node.take_output().unwrap().len();
+        while node.node().timed_out == 0 {
+            step_until_injected(&mut node);
+            attempts += node.take_output().unwrap().len();
+        }
+        assert_eq!(attempts, 3);
+        assert_eq!(node.node().retrier.pending(), 0);
+    }
+
+    /// Counts the echoes it answered, across runs, and echoes the count.
+    struct CountingNode {
+        count: u64,
+    }
+
+    impl Node<u64, EchoPayload> for CountingNode {
+        type Injected = ();
+
+        fn from_init(count: u64, _: Init, _: Sender<Event<EchoPayload>>) -> Result<Self> {
+            Ok(CountingNode { count })
+        }
+
+        fn step(&mut self, input: Event<EchoPayload>, output: &mut Outbox) -> Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            if let EchoPayload::Echo { .. } = &input.body.payload {
+                self.count += 1;
+                let echo = self.count.to_string();
+                output.reply(&input, EchoPayload::EchoOk { echo })?;
+            }
+            Ok(())
+        }
+
+        fn persistent_state(&self) -> Option<u64> {
+            Some(self.count)
+        }
+    }
+
+    #[test]
+    fn state_saved_at_eof_is_loaded_on_the_next_start() {
+        let path = std::env::temp_dir().join(format!("persist-test-{}", std::process::id()));
+        let echo = |msg_id: usize| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","echo":"","msg_id":{msg_id}}}}}"#
+            )
+        };
+        let run_once = |echoes: usize| {
+            let mut lines = vec![init_msg("n1", &["n1"])];
+            lines.extend((1..=echoes).map(|msg_id| echo(msg_id + 1)));
+            let output = test_support::SharedBuffer::new();
+            run_with_persistence::<_, CountingNode, EchoPayload, _, _>(
+                Config::default(),
+                0,
+                crate::persist::FileState::new(&path),
+                std::io::Cursor::new(lines.join("\n")),
+                output.clone(),
+            )
+            .unwrap();
+            let messages = output.messages().unwrap();
+            let last = messages.last().unwrap().body.payload.get("echo").cloned();
+            last.and_then(|echo| echo.as_str().map(str::to_string))
+        };
+
+        assert_eq!(run_once(2).as_deref(), Some("2"));
+        let saved: Option<u64> =
+            StatePersistence::load(&crate::persist::FileState::new(&path)).unwrap();
+        assert_eq!(saved, Some(2));
+        // The second run counts on from the first.
+        assert_eq!(run_once(1).as_deref(), Some("3"));
+        std::fs::remove_file(&path).ok();
+        let mut lock = path.into_os_string();
+        lock.push(".lock");
+        std::fs::remove_file(lock).ok();
+    }
+
+    #[test]
+    fn deeply_nested_lines_are_skipped() {
+        let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":2}}"#;
+        let input = [
+            init_msg("n1", &["n1"]),
+            "[".repeat(1_000_000),
+            echo.to_string(),
+        ]
+        .join("\n");
+        let output = test_support::SharedBuffer::new();
+        run::<_, EchoNode, EchoPayload, _, _>(
+            Config::default(),
+            (),
+            std::io::Cursor::new(input),
+            output.clone(),
+        )
+        .unwrap();
+        let kinds: Vec<_> = output
+            .messages()
+            .unwrap()
+            .iter()
+            .map(|message| message.body.payload.kind().to_string())
+            .collect();
+        assert_eq!(kinds, ["init_ok", "echo_ok"]);
+    }
+}
@@ -0,0 +1,767 @@
+//! Helpers for driving nodes from tests, without a Maelstrom process on the
+//! other end. Enabled by the `test-support` feature.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::json::{FromJson, Map, ToJson, Value};
+use crate::kv::KvPayload;
+use crate::runtime::{self, Config};
+use crate::{rpc, Body, Context, Event, MaelstromError, Message, Node, Outbox, Payload, Result};
+
+/// A writer that appends to a buffer shared with its clones, so a test can
+/// inspect what a node wrote after handing the writer to the runtime.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>, Arc<AtomicUsize>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything written so far, as text.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+
+    /// How many writes the buffer took so far, however many lines each held.
+    pub fn writes(&self) -> usize {
+        self.1.load(Ordering::SeqCst)
+    }
+
+    /// Everything written so far, parsed as one message per line.
+    pub fn messages(&self) -> Result<Vec<Message<Value>>> {
+        self.contents()
+            .lines()
+            .map(|line| {
+                let value = crate::json::parse(line)?;
+                Ok(crate::json::FromJson::from_json(&value)?)
+            })
+            .collect()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        self.1.fetch_add(1, Ordering::SeqCst);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `init` message Maelstrom sends `node_id`, as the JSON line
+/// [`TestNode::start`] and [`handshake`] take. `node_ids` is the whole
+/// cluster, `node_id` included.
+pub fn init_msg(node_id: &str, node_ids: &[&str]) -> String {
+    let mut payload = Map::new();
+    payload.insert("type".to_string(), Value::String("init".to_string()));
+    payload.insert("node_id".to_string(), Value::String(node_id.to_string()));
+    payload.insert(
+        "node_ids".to_string(),
+        node_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .to_json(),
+    );
+    request("c0", node_id, Value::Object(payload), 1)
+        .to_json()
+        .to_string()
+}
+
+/// A request from `src` to `dest` with `msg_id` `id`.
+///
+/// ```
+/// use gossip_glomers_rs::json::Value;
+/// use gossip_glomers_rs::test_support::{init_msg, request};
+///
+/// let init = init_msg("n1", &["n1", "n2"]);
+/// assert!(init.contains(r#""node_ids":["n1","n2"]"#));
+///
+/// let message = request("c1", "n1", Value::Null, 7);
+/// assert_eq!((message.src.as_str(), message.dest.as_str()), ("c1", "n1"));
+/// assert_eq!((message.body.id, message.body.in_reply_to), (Some(7), None));
+/// ```
+pub fn request<P>(src: &str, dest: &str, payload: P, id: usize) -> Message<P> {
+    Message {
+        src: src.to_string(),
+        dest: dest.to_string(),
+        body: Body {
+            id: Some(id),
+            in_reply_to: None,
+            payload,
+        },
+    }
+}
+
+/// Runs only the `init` exchange for node type `N`: feeds it `init` (one
+/// JSON line) and returns the `init_ok` it replied with.
+pub fn handshake<S, N, P>(init_state: S, init: &str) -> Result<Message<Value>>
+where
+    N: Node<S, P>,
+    P: Payload,
+{
+    let buffer = SharedBuffer::new();
+    let mut outbox = Outbox::new(
+        Box::new(buffer.clone()),
+        rpc::Registry::default(),
+        &Config::default(),
+    );
+    let (inject, _events) = mpsc::channel();
+    runtime::handshake::<S, N, P>(init_state, init, inject, &mut outbox)?;
+    buffer
+        .messages()?
+        .into_iter()
+        .next()
+        .context("node did not reply to init")
+}
+
+/// A node driven by hand instead of by the stdin loop.
+///
+/// The test decides when events are handled: it can step the node with an
+/// event directly, or push events through [`TestNode::inject`] (as the node's
+/// own background threads do) and handle them with
+/// [`TestNode::step_pending`].
+pub struct TestNode<S, N, P>
+where
+    N: Node<S, P>,
+{
+    node: N,
+    node_id: String,
+    init_ok: Message<Value>,
+    outbox: Outbox,
+    buffer: SharedBuffer,
+    services: Services,
+    inject: Sender<Event<P, N::Injected>>,
+    events: Receiver<Event<P, N::Injected>>,
+    backlog: VecDeque<Event<P, N::Injected>>,
+    _state: PhantomData<fn(S)>,
+}
+
+impl<S, N, P> TestNode<S, N, P>
+where
+    N: Node<S, P>,
+    P: Payload,
+{
+    /// Initializes the node from `init` (one JSON line). The `init_ok` reply
+    /// is kept apart, for [`TestNode::init_ok`]; what the node sends from
+    /// [`Node::on_init_complete`] is part of the output.
+    pub fn start(init_state: S, init: &str) -> Result<Self> {
+        let buffer = SharedBuffer::new();
+        let services = Services::default();
+        let mut outbox = serving_outbox(buffer.clone(), services.clone());
+        let (inject, events) = mpsc::channel();
+        let (mut node, node_id) =
+            runtime::handshake::<S, N, P>(init_state, init, inject.clone(), &mut outbox)?;
+        let init_ok = buffer
+            .messages()?
+            .into_iter()
+            .next()
+            .context("node did not reply to init")?;
+        buffer.0.lock().unwrap().clear();
+        node.on_init_complete(&mut outbox)
+            .context("completing init")?;
+        outbox.flush()?;
+        Ok(TestNode {
+            node,
+            node_id,
+            init_ok,
+            outbox,
+            buffer,
+            services,
+            inject,
+            events,
+            backlog: VecDeque::new(),
+            _state: PhantomData,
+        })
+    }
+
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    /// The node's reply to `init`.
+    pub fn init_ok(&self) -> &Message<Value> {
+        &self.init_ok
+    }
+
+    /// Answers the requests the node sends to `service` from now on with
+    /// it, as they are written out. They are not part of
+    /// [`TestNode::take_output`].
+    pub fn serve(&mut self, service: KvService) {
+        self.services.lock().unwrap().push(service);
+    }
+
+    /// A sender for the node's event queue, like the one `from_init` got.
+    pub fn inject(&self) -> Sender<Event<P, N::Injected>> {
+        self.inject.clone()
+    }
+
+    /// Handles one event and flushes what the node sent. In debug builds it
+    /// then checks the node's invariants, as the runtime does.
+    pub fn step(&mut self, event: Event<P, N::Injected>) -> Result<()> {
+        let result = runtime::dispatch::<S, N, P>(&mut self.node, event, &mut self.outbox);
+        self.outbox.flush()?;
+        #[cfg(debug_assertions)]
+        runtime::check_invariants::<S, N, P>(&self.node)?;
+        result
+    }
+
+    /// Handles every event queued so far, returning how many were handled.
+    /// Injected events the node coalesces are dropped as in the runtime.
+    pub fn step_pending(&mut self) -> Result<usize> {
+        let mut handled = 0;
+        while let Some(event) = self
+            .backlog
+            .pop_front()
+            .or_else(|| self.events.try_recv().ok())
+        {
+            if self.node.coalesce_injected()
+                && runtime::superseded(&event, &self.events, &mut self.backlog)
+            {
+                continue;
+            }
+            self.step(event)?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Feeds recorded output of other nodes, as returned by
+    /// [`TestNode::take_output`], to this node. Messages addressed to other
+    /// nodes are skipped, so a whole recording can be replayed to each peer.
+    /// Returns how many messages were handled.
+    pub fn replay(&mut self, recording: &[Message<Value>]) -> Result<usize> {
+        let mut handled = 0;
+        for message in recording {
+            if message.dest != self.node_id {
+                continue;
+            }
+            let message = message
+                .clone()
+                .decode()
+                .with_context(|| format!("decoding recorded message from {}", message.src))?;
+            self.step(Event::Message(message))?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// The messages sent since the last call.
+    pub fn take_output(&mut self) -> Result<Vec<Message<Value>>> {
+        let messages = self.buffer.messages()?;
+        self.buffer.0.lock().unwrap().clear();
+        Ok(messages)
+    }
+}
+
+/// Several [`TestNode`]s wired together: messages between them are delivered
+/// in the order they were sent, and everything else (replies to clients,
+/// requests to services) is collected for the test.
+pub struct Cluster<S, N, P>
+where
+    N: Node<S, P>,
+{
+    nodes: BTreeMap<String, TestNode<S, N, P>>,
+    in_flight: VecDeque<Message<Value>>,
+    outside: Vec<Message<Value>>,
+    sent: usize,
+    /// Messages delivered so far, the simulated clock.
+    delivered: usize,
+}
+
+impl<S, N, P> Cluster<S, N, P>
+where
+    N: Node<S, P>,
+    P: Payload,
+{
+    /// Initializes a node for each of `node_ids`, each with its own state
+    /// from `init_state`.
+    pub fn start(node_ids: &[&str], mut init_state: impl FnMut() -> S) -> Result<Self> {
+        let mut cluster = Cluster {
+            nodes: BTreeMap::new(),
+            in_flight: VecDeque::new(),
+            outside: Vec::new(),
+            sent: 0,
+            delivered: 0,
+        };
+        for id in node_ids {
+            let mut node = TestNode::start(init_state(), &init_msg(id, node_ids))
+                .with_context(|| format!("starting {id}"))?;
+            let output = node.take_output()?;
+            cluster.nodes.insert(id.to_string(), node);
+            cluster.route(output);
+        }
+        Ok(cluster)
+    }
+
+    pub fn node(&self, id: &str) -> Option<&TestNode<S, N, P>> {
+        self.nodes.get(id)
+    }
+
+    /// Answers the requests every node sends to `service`, see
+    /// [`TestNode::serve`]. The nodes share its state.
+    pub fn serve(&mut self, service: KvService) {
+        for node in self.nodes.values_mut() {
+            node.serve(service.clone());
+        }
+    }
+
+    /// Queues `message`, e.g. a client request, for delivery.
+    pub fn send(&mut self, message: Message<Value>) {
+        self.in_flight.push_back(message);
+    }
+
+    /// Delivers messages and handles injected events until no node has
+    /// anything left to do.
+    pub fn run_until_quiet(&mut self) -> Result<()> {
+        while self.advance()? {}
+        Ok(())
+    }
+
+    /// Like [`Cluster::run_until_quiet`], also measuring how long each value
+    /// took to reach every node. `values` lists the values a node holds;
+    /// it is looked at after every delivery.
+    pub fn run_measuring_convergence<K: Ord + Clone>(
+        &mut self,
+        values: impl Fn(&N) -> Vec<K>,
+    ) -> Result<Convergence<K>> {
+        let mut first_seen = BTreeMap::new();
+        let mut per_value = BTreeMap::new();
+        loop {
+            let mut holders: BTreeMap<K, usize> = BTreeMap::new();
+            for node in self.nodes.values() {
+                for value in values(node.node()) {
+                    *holders.entry(value).or_default() += 1;
+                }
+            }
+            for (value, count) in holders {
+                let since = *first_seen.entry(value.clone()).or_insert(self.delivered);
+                let converged = per_value.entry(value).or_insert(None);
+                if count == self.nodes.len() && converged.is_none() {
+                    *converged = Some(self.delivered - since);
+                }
+            }
+            if !self.advance()? {
+                return Ok(Convergence { per_value });
+            }
+        }
+    }
+
+    /// Handles the injected events queued at every node, then delivers one
+    /// message. Returns whether there was anything to do.
+    fn advance(&mut self) -> Result<bool> {
+        let mut handled = 0;
+        let mut output = Vec::new();
+        for node in self.nodes.values_mut() {
+            handled += node.step_pending()?;
+            output.extend(node.take_output()?);
+        }
+        self.route(output);
+        let Some(message) = self.in_flight.pop_front() else {
+            return Ok(handled > 0);
+        };
+        self.delivered += 1;
+        let Some(node) = self.nodes.get_mut(&message.dest) else {
+            self.outside.push(message);
+            return Ok(true);
+        };
+        node.replay(std::slice::from_ref(&message))?;
+        let output = node.take_output()?;
+        self.route(output);
+        Ok(true)
+    }
+
+    /// The messages sent to anyone outside the cluster since the last call.
+    pub fn take_outside(&mut self) -> Vec<Message<Value>> {
+        std::mem::take(&mut self.outside)
+    }
+
+    /// How many messages all nodes sent in total, to each other and to the
+    /// outside, including the ones sent while starting.
+    pub fn messages_sent(&self) -> usize {
+        self.sent
+    }
+
+    fn route(&mut self, messages: Vec<Message<Value>>) {
+        self.sent += messages.len();
+        self.in_flight.extend(messages);
+    }
+}
+
+/// How quickly values spread through a [`Cluster`], as measured by
+/// [`Cluster::run_measuring_convergence`].
+///
+/// The simulator has no clock of its own, so times are counted in messages
+/// delivered: from when a value was first seen on any node to when every
+/// node had it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Convergence<K> {
+    /// Per value, its time to converge, or `None` if some node never got it.
+    pub per_value: BTreeMap<K, Option<usize>>,
+}
+
+impl<K> Convergence<K> {
+    /// The slowest value's time to converge, or `None` if some value never
+    /// reached every node.
+    pub fn max(&self) -> Option<usize> {
+        self.per_value
+            .values()
+            .try_fold(0, |max, time| Some(max.max((*time)?)))
+    }
+}
+
+/// The [`KvService`]s a [`TestNode`] serves.
+pub(crate) type Services = Arc<Mutex<Vec<KvService>>>;
+
+/// An outbox like a [`TestNode`]'s: requests to one of `services` are
+/// answered by it as they are written out, and everything else is written
+/// to `buffer`.
+pub(crate) fn serving_outbox(buffer: SharedBuffer, services: Services) -> Outbox {
+    let rpc = rpc::Registry::default();
+    let output = ServingOutput {
+        buffer,
+        services,
+        rpc: rpc.clone(),
+        partial: Vec::new(),
+    };
+    Outbox::new(Box::new(output), rpc, &Config::default())
+}
+
+struct ServingOutput {
+    buffer: SharedBuffer,
+    services: Services,
+    rpc: rpc::Registry,
+    /// The start of a line not written out in full yet.
+    partial: Vec<u8>,
+}
+
+impl ServingOutput {
+    fn route(&mut self, line: &[u8]) -> io::Result<()> {
+        let request = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| crate::json::parse(line).ok())
+            .and_then(|value| Message::<Value>::from_json(&value).ok());
+        let service = request.and_then(|request| {
+            let services = self.services.lock().unwrap();
+            let service = services.iter().find(|service| service.name == request.dest);
+            Some((service?.clone(), request))
+        });
+        let Some((service, request)) = service else {
+            return self.buffer.write_all(line);
+        };
+        for reply in service.answer(request) {
+            // Unclaimed if the requester has stopped waiting.
+            let _ = self.rpc.complete(reply);
+        }
+        Ok(())
+    }
+}
+
+impl Write for ServingOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.route(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A Maelstrom key-value service such as `lin-kv`, kept in memory, for
+/// [`TestNode::serve`]. It answers `read`, `write` and `cas` like Maelstrom
+/// does, so that [`KvClient`](crate::kv::KvClient) calls made in a step
+/// return without a Maelstrom process. Clones share the same store.
+#[derive(Clone)]
+pub struct KvService {
+    name: String,
+    state: Arc<Mutex<KvState>>,
+}
+
+#[derive(Default)]
+struct KvState {
+    /// Per key, as JSON, its value.
+    values: BTreeMap<String, Value>,
+    /// Per key, its value before the latest change, for stale reads.
+    previous: BTreeMap<String, Value>,
+    stale_reads: usize,
+    partitioned: bool,
+    requests: Vec<Message<Value>>,
+    batch: usize,
+    held: Vec<Message<Value>>,
+}
+
+impl KvService {
+    /// An empty service that answers requests addressed to `name`.
+    pub fn new(name: impl Into<String>) -> Self {
+        KvService {
+            name: name.into(),
+            state: Arc::new(Mutex::new(KvState {
+                batch: 1,
+                ..KvState::default()
+            })),
+        }
+    }
+
+    /// Holds its replies back until `requests` requests are waiting for
+    /// one and then answers them all, to check that a node sends its
+    /// requests before it waits for the replies.
+    pub fn answer_in_batches_of(self, requests: usize) -> Self {
+        self.state.lock().unwrap().batch = requests.max(1);
+        self
+    }
+
+    /// Answers the next `reads` reads of a key that has changed with its
+    /// value before the change, like a lagging `seq-kv` replica would.
+    pub fn stale_reads(self, reads: usize) -> Self {
+        self.state.lock().unwrap().stale_reads = reads;
+        self
+    }
+
+    /// Stops answering requests, as if a partition separated the service
+    /// from its clients. They are still recorded in
+    /// [`KvService::requests`].
+    pub fn partition(&self) {
+        self.state.lock().unwrap().partitioned = true;
+    }
+
+    /// The value of the string key `key`.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state.values.get(&key.to_json().to_string()).cloned()
+    }
+
+    /// Sets the string key `key` to `value`, as if some node had written it.
+    pub fn insert(&self, key: &str, value: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .set(key.to_json().to_string(), value);
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<Message<Value>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Handles `request`, returning the replies that are due now.
+    fn answer(&self, request: Message<Value>) -> Vec<Message<Value>> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request.clone());
+        if state.partitioned {
+            return Vec::new();
+        }
+        let payload = match KvPayload::from_json(&request.body.payload) {
+            Ok(payload) => state.apply(payload),
+            Err(e) => KvPayload::Error {
+                code: MaelstromError::MalformedRequest.code(),
+                text: e.to_string(),
+            },
+        };
+        state.held.push(Message {
+            src: request.dest,
+            dest: request.src,
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: payload.to_json(),
+            },
+        });
+        if state.held.len() < state.batch {
+            return Vec::new();
+        }
+        std::mem::take(&mut state.held)
+    }
+}
+
+impl KvState {
+    fn set(&mut self, key: String, value: Value) {
+        if let Some(previous) = self.values.insert(key.clone(), value) {
+            self.previous.insert(key, previous);
+        }
+    }
+
+    fn apply(&mut self, request: KvPayload) -> KvPayload {
+        let missing = |key: &Value| KvPayload::Error {
+            code: MaelstromError::KeyDoesNotExist.code(),
+            text: format!("key {key} does not exist"),
+        };
+        match request {
+            KvPayload::Read { key } => match self.values.get(&key.to_string()) {
+                Some(_) if self.stale_reads > 0 && self.previous.contains_key(&key.to_string()) => {
+                    self.stale_reads -= 1;
+                    KvPayload::ReadOk {
+                        value: self.previous[&key.to_string()].clone(),
+                    }
+                }
+                Some(value) => KvPayload::ReadOk {
+                    value: value.clone(),
+                },
+                None => missing(&key),
+            },
+            KvPayload::Write { key, value } => {
+                self.set(key.to_string(), value);
+                KvPayload::WriteOk
+            }
+            KvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.values.get(&key.to_string()) {
+                Some(current) if *current != from => KvPayload::Error {
+                    code: MaelstromError::PreconditionFailed.code(),
+                    text: format!("expected {from}, but {key} is {current}"),
+                },
+                None if create_if_not_exists != Some(true) => missing(&key),
+                _ => {
+                    self.set(key.to_string(), to);
+                    KvPayload::CasOk
+                }
+            },
+            other => KvPayload::Error {
+                code: MaelstromError::NotSupported.code(),
+                text: format!("{} is not a request", other.kind()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Sender;
+
+    use super::*;
+    use crate::{payload, Init};
+
+    payload! {
+        #[derive(Debug, Clone)]
+        enum EchoPayload {
+            Echo = "echo" { echo: String },
+            EchoOk = "echo_ok" { echo: String },
+        }
+    }
+
+    /// Answers echoes, and echoes every injected number to `c1`. Its state
+    /// is whether it coalesces injected events.
+    struct EchoNode {
+        coalesce: bool,
+    }
+
+    impl Node<bool, EchoPayload> for EchoNode {
+        type Injected = u64;
+
+        fn from_init(coalesce: bool, _: Init, _: Sender<Event<EchoPayload, u64>>) -> Result<Self> {
+            Ok(EchoNode { coalesce })
+        }
+
+        fn coalesce_injected(&self) -> bool {
+            self.coalesce
+        }
+
+        fn on_injected(&mut self, number: u64, output: &mut Outbox) -> Result<()> {
+            Message {
+                src: output.node_id().to_string(),
+                dest: "c1".to_string(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: EchoPayload::Echo {
+                        echo: number.to_string(),
+                    },
+                },
+            }
+            .send(output)
+        }
+
+        fn step(&mut self, input: Event<EchoPayload, u64>, output: &mut Outbox) -> Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            if let EchoPayload::Echo { echo } = &input.body.payload {
+                let echo = echo.clone();
+                output.reply(&input, EchoPayload::EchoOk { echo })?;
+            }
+            Ok(())
+        }
+    }
+
+    /// The `echo` of each message.
+    fn echoes(messages: &[Message<Value>]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.body.payload.get("echo").unwrap().as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn injected_events_reach_on_injected() {
+        let mut node =
+            TestNode::<_, EchoNode, EchoPayload>::start(false, &init_msg("n1", &["n1"])).unwrap();
+        let inject = node.inject();
+        inject.send(Event::Injected(1)).unwrap();
+        inject.send(Event::Injected(2)).unwrap();
+        assert_eq!(node.step_pending().unwrap(), 2);
+        let output = node.take_output().unwrap();
+        assert_eq!(echoes(&output), ["1", "2"]);
+        assert!(output.iter().all(|message| message.dest == "c1"));
+        assert_eq!(node.step_pending().unwrap(), 0);
+    }
+
+    #[test]
+    fn coalesced_injected_events_fire_once() {
+        let mut node =
+            TestNode::<_, EchoNode, EchoPayload>::start(true, &init_msg("n1", &["n1"])).unwrap();
+        for number in 1..=3 {
+            node.inject().send(Event::Injected(number)).unwrap();
+        }
+        assert_eq!(node.step_pending().unwrap(), 1);
+        assert_eq!(echoes(&node.take_output().unwrap()), ["3"]);
+    }
+
+    #[test]
+    fn built_inits_are_accepted() {
+        let init_ok =
+            handshake::<_, EchoNode, EchoPayload>(false, &init_msg("n2", &["n1", "n2"])).unwrap();
+        assert_eq!(init_ok.body.payload.kind(), "init_ok");
+        assert_eq!((init_ok.src.as_str(), init_ok.dest.as_str()), ("n2", "c0"));
+        assert_eq!(init_ok.body.in_reply_to, Some(1));
+    }
+
+    #[test]
+    fn test_nodes_answer_built_requests() {
+        let mut node = TestNode::<_, EchoNode, _>::start(false, &init_msg("n1", &["n1"])).unwrap();
+        assert!(
+            node.take_output().unwrap().is_empty(),
+            "init_ok is not kept"
+        );
+        let echo = EchoPayload::Echo {
+            echo: "hi".to_string(),
+        };
+        node.step(Event::Message(request("c1", "n1", echo, 3)))
+            .unwrap();
+        let output = node.take_output().unwrap();
+        assert_eq!(output.len(), 1);
+        assert_eq!(
+            (output[0].src.as_str(), output[0].dest.as_str()),
+            ("n1", "c1")
+        );
+        assert_eq!(output[0].body.in_reply_to, Some(3));
+        assert_eq!(output[0].body.payload.kind(), "echo_ok");
+        assert_eq!(echoes(&output), ["hi"]);
+        assert!(node.take_output().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+//! Transactions over `lin-kv` for the `txn-rw-register` workload.
+//!
+//! The whole database lives under a single KV key, together with a version
+//! number. A transaction reads it, applies its operations locally, and
+//! installs the result with a `cas` from the version it read. If another
+//! node committed in between, the `cas` fails and the transaction starts
+//! over, so either all of a transaction's writes become visible or none do.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::json::{self, FromJson, Map, ToJson, Value};
+use crate::kv::KvClient;
+use crate::{MaelstromError, Outbox, Result};
+
+/// The KV key holding the database.
+const ROOT_KEY: &str = "txn-root";
+
+/// One micro-operation of a transaction, `["r", k, v]` or `["w", k, v]` on
+/// the wire. Payloads take a transaction as a `Vec<Op>` field.
+///
+/// ```
+/// use gossip_glomers_rs::json::{self, FromJson, ToJson};
+/// use gossip_glomers_rs::txn::Op;
+///
+/// let wire = r#"[["r",1,null],["w",1,6],["r",1,6]]"#;
+/// let txn = Vec::<Op>::from_json(&json::parse(wire)?)?;
+/// assert_eq!(
+///     txn,
+///     [
+///         Op::Read { key: 1, value: None },
+///         Op::Write { key: 1, value: 6 },
+///         Op::Read { key: 1, value: Some(6) },
+///     ]
+/// );
+/// assert_eq!(txn.to_json().to_string(), wire);
+/// # Ok::<(), json::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// `value` is `None` in requests and filled in by [`TxnStore::apply`].
+    Read {
+        key: u64,
+        value: Option<u64>,
+    },
+    Write {
+        key: u64,
+        value: u64,
+    },
+}
+
+impl ToJson for Op {
+    fn to_json(&self) -> Value {
+        let (kind, key, value) = match self {
+            Op::Read { key, value } => ("r", key, value.to_json()),
+            Op::Write { key, value } => ("w", key, value.to_json()),
+        };
+        Value::Array(vec![kind.to_json(), key.to_json(), value])
+    }
+}
+
+impl FromJson for Op {
+    fn from_json(value: &Value) -> Result<Self, json::Error> {
+        let [kind, key, value] = value
+            .as_array()
+            .map(Vec::as_slice)
+            .ok_or_else(|| json::Error::new("txn operation is not an array"))?
+        else {
+            return Err(json::Error::new("txn operation must have three elements"));
+        };
+        let key = u64::from_json(key)?;
+        match kind.as_str() {
+            Some("r") => Ok(Op::Read {
+                key,
+                value: Option::from_json(value)?,
+            }),
+            Some("w") => Ok(Op::Write {
+                key,
+                value: u64::from_json(value)?,
+            }),
+            _ => Err(json::Error::new(format!(
+                "unknown txn operation {kind}, expected \"r\" or \"w\""
+            ))),
+        }
+    }
+}
+
+/// A transaction gave up after conflicting with other writers on every
+/// attempt. Answered with [`MaelstromError::TxnConflict`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxnConflict {
+    pub attempts: u32,
+}
+
+impl TxnConflict {
+    pub fn code(&self) -> MaelstromError {
+        MaelstromError::TxnConflict
+    }
+}
+
+impl fmt::Display for TxnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "txn-conflict: transaction aborted after {} conflicting attempts",
+            self.attempts
+        )
+    }
+}
+
+impl std::error::Error for TxnConflict {}
+
+/// The database state stored under [`ROOT_KEY`].
+#[derive(Debug, Clone, Default)]
+struct Root {
+    version: u64,
+    data: BTreeMap<String, u64>,
+}
+
+impl ToJson for Root {
+    fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        json::insert_field(&mut map, "version", &self.version);
+        json::insert_field(&mut map, "data", &self.data);
+        Value::Object(map)
+    }
+}
+
+impl FromJson for Root {
+    fn from_json(value: &Value) -> Result<Self, json::Error> {
+        let map = value
+            .as_object()
+            .ok_or_else(|| json::Error::new("txn root is not an object"))?;
+        Ok(Root {
+            version: json::field(map, "version")?,
+            data: json::field(map, "data")?,
+        })
+    }
+}
+
+pub struct TxnStore {
+    kv: KvClient,
+    attempts: u32,
+}
+
+impl TxnStore {
+    /// A store kept in the service `kv` talks to, which should be `lin-kv`.
+    pub fn new(kv: KvClient) -> Self {
+        TxnStore { kv, attempts: 5 }
+    }
+
+    /// How many times a conflicting transaction is attempted before it
+    /// fails with [`TxnConflict`].
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Runs `txn` atomically and returns it with the values of its reads
+    /// filled in.
+    ///
+    /// However many operations the transaction has, each attempt does all
+    /// its reads with one KV read of the root, applies every operation
+    /// locally, and then installs all writes with one `cas`. So no operation
+    /// waits on the KV service by itself, and the completed transaction is
+    /// only returned once all of them are done.
+    pub fn apply(&self, txn: &[Op], output: &mut Outbox) -> Result<Vec<Op>> {
+        for _ in 0..self.attempts {
+            let current = self.kv.read(ROOT_KEY, output)?;
+            let root = match &current {
+                Some(value) => Root::from_json(value)?,
+                None => Root::default(),
+            };
+            let (result, mut next) = Self::run(txn, root);
+            if !txn.iter().any(|op| matches!(op, Op::Write { .. })) {
+                // A read-only transaction saw a consistent snapshot and has
+                // nothing to install.
+                return Ok(result);
+            }
+            next.version += 1;
+            let from = current.unwrap_or_else(|| Root::default().to_json());
+            if self.kv.cas(ROOT_KEY, from, next.to_json(), true, output)? {
+                return Ok(result);
+            }
+        }
+        Err(TxnConflict {
+            attempts: self.attempts,
+        }
+        .into())
+    }
+
+    /// Applies `txn` to `root`, returning the completed operations and the
+    /// resulting state.
+    fn run(txn: &[Op], mut root: Root) -> (Vec<Op>, Root) {
+        let result = txn
+            .iter()
+            .map(|op| match *op {
+                Op::Read { key, .. } => Op::Read {
+                    key,
+                    value: root.data.get(&key.to_string()).copied(),
+                },
+                Op::Write { key, value } => {
+                    root.data.insert(key.to_string(), value);
+                    Op::Write { key, value }
+                }
+            })
+            .collect();
+        (result, root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::test_support::{serving_outbox, KvService, SharedBuffer};
+    use crate::Payload;
+
+    /// An outbox whose requests `lin_kv` answers.
+    fn outbox(lin_kv: &KvService) -> Outbox {
+        serving_outbox(
+            SharedBuffer::new(),
+            Arc::new(Mutex::new(vec![lin_kv.clone()])),
+        )
+    }
+
+    #[test]
+    fn all_reads_of_a_txn_take_one_kv_read() {
+        let lin_kv = KvService::new("lin-kv");
+        let data = (1..=5).map(|key| (key.to_string(), key * 10)).collect();
+        lin_kv.insert(ROOT_KEY, Root { version: 3, data }.to_json());
+        let reads: Vec<_> = (1..=5).map(|key| Op::Read { key, value: None }).collect();
+
+        let txn = TxnStore::new(KvClient::lin("n1"))
+            .apply(&reads, &mut outbox(&lin_kv))
+            .unwrap();
+        let expected: Vec<_> = (1..=5)
+            .map(|key| Op::Read {
+                key,
+                value: Some(key * 10),
+            })
+            .collect();
+        assert_eq!(txn, expected);
+        assert_eq!(requests(&lin_kv), ["read"]);
+    }
+
+    #[test]
+    fn all_writes_of_a_txn_take_one_cas() {
+        let lin_kv = KvService::new("lin-kv");
+        let txn = [
+            Op::Write { key: 1, value: 6 },
+            Op::Read {
+                key: 1,
+                value: None,
+            },
+            Op::Write { key: 2, value: 7 },
+        ];
+
+        let txn = TxnStore::new(KvClient::lin("n1"))
+            .apply(&txn, &mut outbox(&lin_kv))
+            .unwrap();
+        assert_eq!(
+            txn[1],
+            Op::Read {
+                key: 1,
+                value: Some(6)
+            }
+        );
+        assert_eq!(requests(&lin_kv), ["read", "cas"]);
+        let root = Root::from_json(&lin_kv.get(ROOT_KEY).unwrap()).unwrap();
+        assert_eq!(root.version, 1);
+        let data = BTreeMap::from([("1".to_string(), 6), ("2".to_string(), 7)]);
+        assert_eq!(root.data, data);
+    }
+
+    #[test]
+    fn ops_round_trip_through_maelstroms_json() {
+        let wire = r#"[["r",1,null],["w",1,6],["r",1,6]]"#;
+        let txn = Vec::<Op>::from_json(&json::parse(wire).unwrap()).unwrap();
+        assert_eq!(
+            txn,
+            [
+                Op::Read {
+                    key: 1,
+                    value: None
+                },
+                Op::Write { key: 1, value: 6 },
+                Op::Read {
+                    key: 1,
+                    value: Some(6)
+                },
+            ]
+        );
+        assert_eq!(txn.to_json().to_string(), wire);
+
+        for bad in [
+            r#"["r",1]"#,
+            r#"["x",1,null]"#,
+            r#"["w",1,null]"#,
+            r#"{"r":1}"#,
+        ] {
+            let op = Op::from_json(&json::parse(bad).unwrap());
+            assert!(op.is_err(), "{bad} decoded");
+        }
+    }
+
+    /// The types of the requests `service` received.
+    fn requests(service: &KvService) -> Vec<String> {
+        service
+            .requests()
+            .iter()
+            .map(|request| request.body.payload.kind().to_string())
+            .collect()
+    }
+}
@@ -0,0 +1,1562 @@
+//! Globally-unique ID generation for the `unique-ids` workload.
+//!
+//! IDs follow the RFC 9562 version 6 layout: a 60-bit timestamp counted in
+//! 100ns intervals since the Gregorian epoch, most significant bits first, a
+//! clock sequence, and the 48-bit node id of the machine. Because the
+//! timestamp leads, IDs from one node sort in the order they were issued. The
+//! last timestamp and sequence handed out are kept in a state file so that a
+//! restarted node never reissues an ID.
+//!
+//! The state file starts with a `v<N>` header giving its layout version.
+//! Files written before the header existed are version 1 and are upgraded
+//! when the generator opens them.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+#[cfg(not(feature = "no-mac"))]
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{bail, Context, Result};
+
+/// Offset between the Gregorian epoch (1582-10-15) and the Unix epoch, in
+/// 100ns intervals.
+const GREGORIAN_OFFSET: u64 = 0x01B2_1DD2_1381_4000;
+
+/// Environment variable that overrides the MAC-derived node id.
+pub const NODE_ID_ENV: &str = "GGRS_NODE_ID";
+
+const NODE_ID_MASK: u64 = 0xFFFF_FFFF_FFFF;
+
+/// The least significant bit of the first octet of a 48-bit node id.
+const MULTICAST_BIT: u64 = 1 << 40;
+
+const VERSION: u128 = 6;
+const VARIANT: u128 = 0b10;
+const SEQUENCE_MASK: u16 = 0x3FFF;
+
+/// The largest clock sequence: it has 14 bits.
+pub const MAX_SEQUENCE: u16 = SEQUENCE_MASK;
+const V7_COUNTER_MASK: u16 = 0x0FFF;
+
+const URN_PREFIX: &str = "urn:uuid:";
+
+/// Layout version of the state file written by this build.
+const STATE_VERSION: u32 = 2;
+
+/// How many times committing the state is retried on a transient IO error.
+const COMMIT_RETRIES: u32 = 5;
+
+/// Wait before the first retry of a commit; doubled after each attempt.
+const COMMIT_BACKOFF: Duration = Duration::from_millis(1);
+
+/// How many clock readings [`State::advance`] takes, waiting for the clock to
+/// tick over once the sequence is used up, before it gives up on a clock
+/// that does not move. A working clock ticks within a few readings.
+const MAX_STUCK_READINGS: u32 = 1 << 24;
+
+/// How far the clock may fall behind the last ID before it is reported, see
+/// [`UUIDGenerator::with_max_backward_drift`].
+const DEFAULT_MAX_BACKWARD_DRIFT: Duration = Duration::from_millis(100);
+
+#[allow(clippy::upper_case_acronyms)]
+/// Ordered the same way as [`UUID::to_bytes`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UUID {
+    id: u128,
+}
+
+impl UUID {
+    /// The RFC 4122 namespace for fully-qualified domain names.
+    pub const NAMESPACE_DNS: UUID = UUID::from_u128(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8);
+    /// The RFC 4122 namespace for URLs.
+    pub const NAMESPACE_URL: UUID = UUID::from_u128(0x6ba7b811_9dad_11d1_80b4_00c04fd430c8);
+    /// The RFC 4122 namespace for ISO OIDs.
+    pub const NAMESPACE_OID: UUID = UUID::from_u128(0x6ba7b812_9dad_11d1_80b4_00c04fd430c8);
+    /// The RFC 4122 namespace for X.500 distinguished names.
+    pub const NAMESPACE_X500: UUID = UUID::from_u128(0x6ba7b814_9dad_11d1_80b4_00c04fd430c8);
+
+    const fn from_u128(value: u128) -> Self {
+        UUID { id: value }
+    }
+
+    /// The 60-bit timestamp of a time-based ID, in 100ns intervals since the
+    /// Gregorian epoch, reassembled from its three fields.
+    pub fn timestamp(&self) -> u64 {
+        let high = (self.id >> 96) as u64 & 0xFFFF_FFFF;
+        let mid = (self.id >> 80) as u64 & 0xFFFF;
+        let low = (self.id >> 64) as u64 & 0x0FFF;
+        (high << 28) | (mid << 12) | low
+    }
+
+    /// The 16 bytes of the ID in network byte order.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.id.to_be_bytes()
+    }
+
+    /// Parses the canonical hyphenated form, or the same 32 hex digits
+    /// without hyphens, in either case, and checks that the result is an
+    /// RFC 9562 UUID: a defined version (1 to 8) and the `10` variant.
+    ///
+    /// Generators here only issue versions 5, 6 and 7, but the namespaces
+    /// [`generate_v5`](UUIDGenerator::generate_v5) takes are version 1, and
+    /// IDs from other implementations are valid UUIDs too, so every defined
+    /// version is accepted. Stricter than [`UUID::from_str`], which takes any
+    /// 128 bits.
+    pub fn parse(s: &str) -> Result<UUID, ParseUuidError> {
+        let hyphens: &[usize] = match s.len() {
+            36 => &[8, 13, 18, 23],
+            32 => &[],
+            len => return Err(ParseUuidError::InvalidLength { len }),
+        };
+        let mut id = 0u128;
+        for (index, character) in s.char_indices() {
+            if hyphens.contains(&index) && character == '-' {
+                continue;
+            }
+            let digit = character
+                .to_digit(16)
+                .filter(|_| !hyphens.contains(&index))
+                .ok_or(ParseUuidError::InvalidCharacter { character, index })?;
+            id = (id << 4) | digit as u128;
+        }
+        UUID::decode(id)
+    }
+
+    /// Interprets 128 bits as a UUID, checking like [`UUID::parse`] that
+    /// they are an RFC 9562 UUID: a defined version (1 to 8) and the `10`
+    /// variant. The nil and max UUIDs are rejected as well.
+    ///
+    /// ```
+    /// use gossip_glomers_rs::uuid::{ParseUuidError, UUID};
+    ///
+    /// // The RFC 9562 variant, but version 0.
+    /// let malformed = 0x6ba7b810_9dad_01d1_80b4_00c04fd430c8;
+    /// assert_eq!(
+    ///     UUID::decode(malformed),
+    ///     Err(ParseUuidError::InvalidVersion { version: 0 })
+    /// );
+    /// assert!(UUID::decode(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8).is_ok());
+    /// ```
+    pub fn decode(value: u128) -> Result<UUID, ParseUuidError> {
+        let version = ((value >> 76) & 0xF) as u8;
+        if !(1..=8).contains(&version) {
+            return Err(ParseUuidError::InvalidVersion { version });
+        }
+        if (value >> 62) & 0b11 != VARIANT {
+            return Err(ParseUuidError::InvalidVariant);
+        }
+        Ok(UUID::from_u128(value))
+    }
+
+    /// The ID as a single integer; its decimal form is what [`UUID`] used
+    /// to display as.
+    pub fn as_u128(&self) -> u128 {
+        self.id
+    }
+
+    /// The canonical form, `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` in
+    /// lowercase hex. This is also what `Display` writes.
+    pub fn to_hyphenated(&self) -> String {
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            self.id >> 96,
+            (self.id >> 80) & 0xFFFF,
+            (self.id >> 64) & 0xFFFF,
+            (self.id >> 48) & 0xFFFF,
+            self.id & 0xFFFF_FFFF_FFFF
+        )
+    }
+
+    /// The RFC 4122 URN form, `urn:uuid:xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub fn to_urn(&self) -> String {
+        format!("{URN_PREFIX}{}", self.to_hyphenated())
+    }
+}
+
+impl fmt::Display for UUID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hyphenated())
+    }
+}
+
+/// Why a string, or a value passed to [`UUID::decode`], is not a UUID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseUuidError {
+    /// Not in any form accepted by [`UUID::from_str`].
+    InvalidFormat { input: String },
+    /// Neither the 36-character hyphenated nor the 32-character plain form.
+    InvalidLength { len: usize },
+    /// A character that is not a hex digit, or a hyphen out of place.
+    InvalidCharacter { character: char, index: usize },
+    /// The version nibble is not one defined by RFC 9562.
+    InvalidVersion { version: u8 },
+    /// The variant bits are not the RFC 9562 `10` variant.
+    InvalidVariant,
+}
+
+impl fmt::Display for ParseUuidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseUuidError::InvalidFormat { input } => write!(f, "invalid UUID {input:?}"),
+            ParseUuidError::InvalidLength { len } => {
+                write!(f, "invalid UUID length {len}, expected 36 or 32 characters")
+            }
+            ParseUuidError::InvalidCharacter { character, index } => {
+                write!(
+                    f,
+                    "invalid character {character:?} at index {index} of UUID"
+                )
+            }
+            ParseUuidError::InvalidVersion { version } => {
+                write!(f, "unknown UUID version {version}, expected 1 to 8")
+            }
+            ParseUuidError::InvalidVariant => f.write_str("UUID is not of the RFC 9562 variant"),
+        }
+    }
+}
+
+impl std::error::Error for ParseUuidError {}
+
+impl FromStr for UUID {
+    type Err = ParseUuidError;
+
+    /// Parses the hyphenated hex form that is displayed, the same with a
+    /// `urn:uuid:` prefix, or the decimal form of [`UUID::as_u128`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseUuidError::InvalidFormat {
+            input: s.to_string(),
+        };
+        let hyphenated = s.strip_prefix(URN_PREFIX).unwrap_or(s);
+        let groups: Vec<&str> = hyphenated.split('-').collect();
+        if let [a, b, c, d, e] = groups[..] {
+            let lengths_match = [a, b, c, d, e]
+                .iter()
+                .zip([8, 4, 4, 4, 12])
+                .all(|(group, len)| group.len() == len);
+            let hex = [a, b, c, d, e].concat();
+            if !lengths_match || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(error());
+            }
+            return u128::from_str_radix(&hex, 16)
+                .map(UUID::from_u128)
+                .map_err(|_| error());
+        }
+        if hyphenated.len() != s.len() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(error());
+        }
+        s.parse().map(UUID::from_u128).map_err(|_| error())
+    }
+}
+
+/// Where a generator reads the time its IDs are stamped with.
+pub trait Clock: Send {
+    /// The current time in 100ns intervals since the Gregorian epoch.
+    fn now_100ns(&self) -> Result<u64>;
+}
+
+/// The system's wall clock, which generators use unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now_100ns(&self) -> Result<u64> {
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("system clock is set before the Unix epoch")?;
+        Ok((since_unix.as_nanos() / 100) as u64 + GREGORIAN_OFFSET)
+    }
+}
+
+/// Any function returning the time is a clock, e.g. one reading a counter a
+/// test moves by hand.
+impl<F: Fn() -> Result<u64> + Send> Clock for F {
+    fn now_100ns(&self) -> Result<u64> {
+        self()
+    }
+}
+
+/// The timestamp and clock sequence of the last ID a generator issued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct State {
+    pub last_timestamp: u64,
+    pub last_sequence_id: u16,
+}
+
+impl State {
+    /// Moves the state past the last issued ID, reading the current time
+    /// from `now`.
+    ///
+    /// If the clock has not moved on, the sequence is bumped instead. Once the
+    /// sequence runs out of bits within one interval, this spins until the
+    /// clock ticks over, and fails if it has not after
+    /// [`MAX_STUCK_READINGS`] readings. If instead the clock is behind the last ID (it was
+    /// set back), the timestamp is moved one interval ahead rather than
+    /// waiting for the clock to catch up, so IDs never go backwards.
+    fn advance(&mut self, now: impl Fn() -> Result<u64>) -> Result<()> {
+        let mut current_timestamp = now()?;
+        if self.last_timestamp < current_timestamp {
+            self.last_timestamp = current_timestamp;
+        } else if self.last_sequence_id < SEQUENCE_MASK {
+            self.last_sequence_id += 1;
+        } else if self.last_timestamp == current_timestamp {
+            let mut readings = 0;
+            while current_timestamp == self.last_timestamp {
+                if readings == MAX_STUCK_READINGS {
+                    bail!(
+                        "clock stuck at {current_timestamp} for {readings} readings with the \
+                         UUID clock sequence used up"
+                    );
+                }
+                std::hint::spin_loop();
+                current_timestamp = now()?;
+                readings += 1;
+            }
+            self.last_timestamp = current_timestamp.max(self.last_timestamp + 1);
+            self.last_sequence_id = 0;
+        } else {
+            self.last_timestamp += 1;
+            self.last_sequence_id = 0;
+        }
+        Ok(())
+    }
+
+    /// Packs the state into an ID. The state is copied once, so all time
+    /// fields come from the same timestamp.
+    fn pack(self, node_id: u64) -> u128 {
+        let State {
+            last_timestamp: timestamp,
+            last_sequence_id: sequence,
+        } = self;
+        let clock_seq = (VARIANT << 14) | (sequence & SEQUENCE_MASK) as u128;
+        (Generator::get_time_high(timestamp) << 96)
+            | (Generator::get_time_mid(timestamp) << 80)
+            | (Generator::get_time_low_and_version(timestamp) << 64)
+            | (clock_seq << 48)
+            | (node_id & NODE_ID_MASK) as u128
+    }
+}
+
+/// What a generator keeps its state in: the state file, or a stand-in for
+/// it in tests.
+trait StateFile: Read + Write + Seek + Send {
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn lock(&self) -> io::Result<()>;
+    fn lock_shared(&self) -> io::Result<()>;
+    fn unlock(&self) -> io::Result<()>;
+}
+
+impl StateFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn lock(&self) -> io::Result<()> {
+        File::lock(self)
+    }
+
+    fn lock_shared(&self) -> io::Result<()> {
+        File::lock_shared(self)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        File::unlock(self)
+    }
+}
+
+/// Where the generator keeps its state between calls.
+enum Storage {
+    /// The state file, locked while an ID is being generated.
+    File(Box<dyn StateFile>),
+    /// The state file could not be opened; state only lives as long as the
+    /// process.
+    Memory,
+}
+
+/// The state behind a [`UUIDGenerator`], guarded by its mutex.
+struct Generator {
+    storage: Storage,
+    state: State,
+    node_id: u64,
+    clock: Box<dyn Clock>,
+    /// The Unix millisecond and counter of the last version 7 ID.
+    last_v7: (u64, u16),
+    /// IDs already committed to the state file by [`UUIDGenerator::warm_up`]
+    /// and not handed out yet.
+    reserved: VecDeque<UUID>,
+    /// Total time spent waiting for the state file lock.
+    lock_wait: Duration,
+    /// See [`UUIDGenerator::with_deferred_commit`].
+    commit_every: Option<usize>,
+    /// IDs issued since the state was last committed.
+    uncommitted: usize,
+    /// Whether `state` was loaded from the file, for deferred commits.
+    loaded: bool,
+    max_backward_drift: Duration,
+    /// How many times the clock fell behind by more than
+    /// `max_backward_drift`.
+    backward_jumps: u64,
+    /// Whether the clock is still behind since the last jump was reported.
+    drifting: bool,
+}
+
+impl Generator {
+    fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let storage = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+        {
+            Ok(mut file) => {
+                Self::migrate_state_file(&mut file)
+                    .with_context(|| format!("UUID state file {}", path.display()))?;
+                Storage::File(Box::new(file))
+            }
+            Err(e) => {
+                eprintln!(
+                    "warning: cannot open UUID state file {}: {e}; falling back to in-memory state, \
+                     IDs are no longer guaranteed unique across restarts",
+                    path.display()
+                );
+                Storage::Memory
+            }
+        };
+        Ok(Self::with_storage(storage))
+    }
+
+    fn with_storage(storage: Storage) -> Self {
+        Generator {
+            storage,
+            state: State::default(),
+            node_id: Self::get_node_id(),
+            clock: Box::new(WallClock),
+            last_v7: (0, 0),
+            reserved: VecDeque::new(),
+            lock_wait: Duration::ZERO,
+            commit_every: None,
+            uncommitted: 0,
+            loaded: false,
+            max_backward_drift: DEFAULT_MAX_BACKWARD_DRIFT,
+            backward_jumps: 0,
+            drifting: false,
+        }
+    }
+
+    fn with_deferred_commit(mut self, ids: usize) -> Self {
+        self.commit_every = Some(ids.max(1));
+        self
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.uncommitted == 0 {
+            return Ok(());
+        }
+        self.lock_state_file()?;
+        self.commit_state_and_release_lock()?;
+        self.uncommitted = 0;
+        Ok(())
+    }
+
+    fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    fn with_initial_sequence(mut self, sequence: u16) -> Self {
+        self.state.last_sequence_id = sequence & SEQUENCE_MASK;
+        self
+    }
+
+    fn is_in_memory(&self) -> bool {
+        matches!(self.storage, Storage::Memory)
+    }
+
+    fn lock_wait(&self) -> Duration {
+        self.lock_wait
+    }
+
+    fn with_max_backward_drift(mut self, drift: Duration) -> Self {
+        self.max_backward_drift = drift;
+        self
+    }
+
+    fn backward_jumps(&self) -> u64 {
+        self.backward_jumps
+    }
+
+    fn peek_state(&mut self) -> Result<State> {
+        match &mut self.storage {
+            Storage::File(file) => {
+                file.lock_shared().context("cannot lock UUID state file")?;
+                let read = Self::read_state(file.as_mut());
+                file.unlock().context("cannot unlock UUID state file")?;
+                let (_, state) = read?;
+                Ok(state.unwrap_or(self.state))
+            }
+            Storage::Memory => Ok(self.state),
+        }
+    }
+
+    fn generate_v7(&mut self) -> Result<UUID> {
+        let millis = self.clock.now_100ns()?.saturating_sub(GREGORIAN_OFFSET) / 10_000;
+        let (last_millis, counter) = self.last_v7;
+        self.last_v7 = if millis > last_millis {
+            (millis, 0)
+        } else if counter < V7_COUNTER_MASK {
+            (last_millis, counter + 1)
+        } else {
+            (last_millis + 1, 0)
+        };
+        let (millis, counter) = self.last_v7;
+        let random = crate::rand::random_u64() as u128 & ((1 << 62) - 1);
+        Ok(UUID::from_u128(
+            ((millis as u128 & 0xFFFF_FFFF_FFFF) << 80)
+                | (7 << 76)
+                | ((counter as u128) << 64)
+                | (VARIANT << 62)
+                | random,
+        ))
+    }
+
+    fn warm_up(&mut self, count: usize) -> Result<()> {
+        let batch = self.generate_batch(count)?;
+        self.reserved.extend(batch);
+        Ok(())
+    }
+
+    fn generate(&mut self) -> Result<UUID> {
+        if let Some(uuid) = self.reserved.pop_front() {
+            return Ok(uuid);
+        }
+        match self.commit_every {
+            Some(every) => self.generate_deferred(every),
+            None => self.generate_batch(1).map(|mut uuids| uuids.remove(0)),
+        }
+    }
+
+    fn generate_deferred(&mut self, commit_every: usize) -> Result<UUID> {
+        if !self.loaded {
+            self.get_global_state_from_stable_storage()?;
+            self.release_lock()?;
+            self.loaded = true;
+        }
+        self.advance_state()?;
+        let uuid = UUID::from_u128(self.pack());
+        self.uncommitted += 1;
+        if self.uncommitted >= commit_every {
+            self.flush()?;
+        }
+        Ok(uuid)
+    }
+
+    fn generate_batch(&mut self, count: usize) -> Result<Vec<UUID>> {
+        // Loading the state from the file would forget uncommitted IDs.
+        self.flush()?;
+        self.get_global_state_from_stable_storage()?;
+        let uuids = (0..count)
+            .map(|_| {
+                self.advance_state()?;
+                Ok(UUID::from_u128(self.pack()))
+            })
+            .collect::<Result<Vec<_>>>();
+        let committed = self.commit_state_and_release_lock();
+        let uuids = uuids?;
+        committed?;
+        Ok(uuids)
+    }
+
+    /// Rewrites a state file left by an older build in the current layout.
+    ///
+    /// Fails if the file was written by a newer build, since its state
+    /// cannot be read and ignoring it could reissue IDs.
+    fn migrate_state_file(file: &mut impl StateFile) -> Result<()> {
+        file.lock().context("cannot lock")?;
+        let migrated = (|| {
+            let (version, state) = Self::read_state(file)?;
+            if version > STATE_VERSION {
+                bail!(
+                    "file has version {version}, but this build only understands versions up \
+                     to {STATE_VERSION}"
+                );
+            }
+            if version < STATE_VERSION {
+                if let Some(state) = state {
+                    Self::write_state(file, state)?;
+                }
+            }
+            Ok(())
+        })();
+        file.unlock().context("cannot unlock")?;
+        migrated
+    }
+
+    /// Reads the layout version and the last issued state, if any, from the
+    /// state file.
+    fn read_state(file: &mut (impl StateFile + ?Sized)) -> Result<(u32, Option<State>)> {
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_string(&mut contents))
+            .context("cannot read UUID state file")?;
+        let (version, record) = match contents.split_once('\n') {
+            Some((header, record)) if header.starts_with('v') => {
+                let version = header[1..]
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid UUID state file header {header:?}"))?;
+                (version, record)
+            }
+            // Version 1 files hold just the record.
+            _ => (1, contents.as_str()),
+        };
+        let state = match record.trim().split_once(',') {
+            Some((timestamp, sequence)) => {
+                let invalid = || format!("invalid UUID state record {:?}", record.trim());
+                Some(State {
+                    last_timestamp: timestamp.parse().with_context(invalid)?,
+                    last_sequence_id: sequence.parse().with_context(invalid)?,
+                })
+            }
+            None => None,
+        };
+        Ok((version, state))
+    }
+
+    /// Replaces the contents of the state file with `state`, in the current
+    /// layout.
+    ///
+    /// The whole rewrite is retried on transient errors, so a write that was
+    /// interrupted halfway is never left behind.
+    fn write_state(file: &mut (impl StateFile + ?Sized), state: State) -> Result<()> {
+        retry_transient(|| {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_fmt(format_args!(
+                "v{STATE_VERSION}\n{},{}",
+                state.last_timestamp, state.last_sequence_id
+            ))?;
+            file.flush()
+        })
+        .context("cannot write UUID state file")
+    }
+
+    /// Locks the state file (if any) and loads the last issued state from it.
+    ///
+    /// On error the lock is not held.
+    fn get_global_state_from_stable_storage(&mut self) -> Result<()> {
+        self.lock_state_file()?;
+        if let Storage::File(file) = &mut self.storage {
+            let state = Self::read_state(file.as_mut()).and_then(|(version, state)| {
+                if version > STATE_VERSION {
+                    bail!("UUID state file was rewritten by a newer build (version {version})");
+                }
+                Ok(state)
+            });
+            match state {
+                Ok(Some(state)) => self.state = state,
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = file.unlock();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn advance_state(&mut self) -> Result<()> {
+        let clock = &self.clock;
+        let last = self.state.last_timestamp;
+        let first_reading = Cell::new(None);
+        self.state.advance(|| {
+            let now = clock.now_100ns()?;
+            first_reading.set(first_reading.get().or(Some(now)));
+            Ok(now)
+        })?;
+        let behind = first_reading
+            .get()
+            .map_or(0, |now| last.saturating_sub(now));
+        let tolerance = (self.max_backward_drift.as_nanos() / 100) as u64;
+        if behind == 0 {
+            self.drifting = false;
+        } else if behind > tolerance && !self.drifting {
+            eprintln!(
+                "warning: clock is {:?} behind the last UUID, more than the {:?} tolerated; \
+                 keeping IDs ahead of it until it catches up",
+                Duration::from_nanos(behind.saturating_mul(100)),
+                self.max_backward_drift
+            );
+            self.backward_jumps += 1;
+            self.drifting = true;
+        }
+        Ok(())
+    }
+
+    /// Takes the state file lock, counting the time spent waiting for it.
+    fn lock_state_file(&mut self) -> Result<()> {
+        if let Storage::File(file) = &self.storage {
+            let started = Instant::now();
+            file.lock().context("cannot lock UUID state file")?;
+            self.lock_wait += started.elapsed();
+        }
+        Ok(())
+    }
+
+    /// Releases the state file lock without writing the state.
+    fn release_lock(&mut self) -> Result<()> {
+        if let Storage::File(file) = &self.storage {
+            retry_transient(|| file.unlock()).context("cannot unlock UUID state file")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the state back and releases the lock. The lock is released
+    /// even if the write fails.
+    fn commit_state_and_release_lock(&mut self) -> Result<()> {
+        if let Storage::File(file) = &mut self.storage {
+            let written = Self::write_state(file.as_mut(), self.state);
+            retry_transient(|| file.unlock()).context("cannot unlock UUID state file")?;
+            written?;
+        }
+        Ok(())
+    }
+
+    fn pack(&self) -> u128 {
+        self.state.pack(self.node_id)
+    }
+
+    fn get_time_high(timestamp: u64) -> u128 {
+        ((timestamp >> 28) & 0xFFFF_FFFF) as u128
+    }
+
+    fn get_time_mid(timestamp: u64) -> u128 {
+        ((timestamp >> 12) & 0xFFFF) as u128
+    }
+
+    fn get_time_low_and_version(timestamp: u64) -> u128 {
+        ((timestamp & 0x0FFF) as u128) | (VERSION << 12)
+    }
+
+    /// Returns the node id from `GGRS_NODE_ID` if set, otherwise the MAC
+    /// address of the first non-loopback network interface that has one.
+    /// Without such an interface the node id is random.
+    ///
+    /// With the `no-mac` feature the network interfaces are never looked at,
+    /// and a node id without the variable is always random.
+    fn get_node_id() -> u64 {
+        if let Ok(value) = std::env::var(NODE_ID_ENV) {
+            match Self::parse_node_id(&value) {
+                Some(node_id) => return node_id,
+                None => eprintln!(
+                    "warning: ignoring {NODE_ID_ENV}={value:?}: not a 48-bit decimal or hex integer"
+                ),
+            }
+        }
+        #[cfg(not(feature = "no-mac"))]
+        if let Some(mac) = Self::read_mac() {
+            return mac;
+        }
+        Self::random_node_id()
+    }
+
+    #[cfg(not(feature = "no-mac"))]
+    fn read_mac() -> Option<u64> {
+        let ifaces: Vec<String> = fs::read_dir("/sys/class/net")
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self::select_mac(ifaces, |iface| {
+            fs::read_to_string(format!("/sys/class/net/{iface}/address")).ok()
+        })
+    }
+
+    /// Picks the MAC address of the first interface, by name, that is not
+    /// the loopback and has a non-zero address. `read_address` returns the
+    /// address of an interface in `aa:bb:cc:dd:ee:ff` form.
+    #[cfg(not(feature = "no-mac"))]
+    fn select_mac(
+        mut ifaces: Vec<String>,
+        read_address: impl Fn(&str) -> Option<String>,
+    ) -> Option<u64> {
+        // Directory order is arbitrary; sorting keeps the choice stable.
+        ifaces.sort();
+        ifaces
+            .iter()
+            .filter(|iface| *iface != "lo")
+            .filter_map(|iface| read_address(iface))
+            .filter_map(|mac| u64::from_str_radix(&mac.trim().replace(':', ""), 16).ok())
+            .find(|&mac| mac != 0 && mac <= NODE_ID_MASK)
+    }
+
+    /// A random node id with the multicast bit set, which RFC 9562 section
+    /// 6.10 reserves for node ids that are not a real MAC address.
+    fn random_node_id() -> u64 {
+        (crate::rand::random_u64() & NODE_ID_MASK) | MULTICAST_BIT
+    }
+
+    /// Parses a node id given either in decimal or as `0x`-prefixed hex.
+    fn parse_node_id(value: &str) -> Option<u64> {
+        let value = value.trim();
+        let node_id = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+            None => value.parse().ok()?,
+        };
+        (node_id <= NODE_ID_MASK).then_some(node_id)
+    }
+}
+
+impl Drop for Generator {
+    /// Commits any IDs generated with deferred commits.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("error: {e}");
+        }
+    }
+}
+
+/// Issues version 6 IDs, keeping their state in a file shared by all nodes
+/// on the machine.
+///
+/// The generator locks its state internally, so it can be shared between
+/// threads behind an [`Arc`](std::sync::Arc) and every method takes `&self`.
+/// Concurrent calls are serialized: each ID is generated and committed under
+/// the lock, so no two threads are handed the same one. If a thread panics
+/// while generating, later calls fail rather than risk reissuing an ID.
+///
+/// ```
+/// use std::collections::HashSet;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use gossip_glomers_rs::uuid::UUIDGenerator;
+///
+/// let path = std::env::temp_dir().join(format!("uuid-doc-{}", std::process::id()));
+/// let generator = Arc::new(UUIDGenerator::new(&path)?);
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let generator = Arc::clone(&generator);
+///         thread::spawn(move || (0..100).map(|_| generator.generate()).collect::<Vec<_>>())
+///     })
+///     .collect();
+/// let mut ids = HashSet::new();
+/// for thread in threads {
+///     for id in thread.join().unwrap() {
+///         assert!(ids.insert(id?), "duplicate ID");
+///     }
+/// }
+/// assert_eq!(ids.len(), 400);
+/// # drop(generator);
+/// # std::fs::remove_file(&path).ok();
+/// # Ok::<(), gossip_glomers_rs::Error>(())
+/// ```
+pub struct UUIDGenerator {
+    inner: Mutex<Generator>,
+}
+
+impl UUIDGenerator {
+    /// Creates a generator backed by the state file at `path`.
+    ///
+    /// If the file cannot be opened (read-only filesystem, missing
+    /// permissions, ...) the generator falls back to keeping its state in
+    /// memory. IDs are still unique for the lifetime of the process, but not
+    /// across restarts. A file that opens but cannot be locked or read is an
+    /// error.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(UUIDGenerator {
+            inner: Mutex::new(Generator::new(path)?),
+        })
+    }
+
+    /// Generates IDs in memory, without touching the state file, and only
+    /// commits the state once every `ids` IDs and on [`flush`](Self::flush)
+    /// (or drop), instead of locking, reading and writing the file for each.
+    ///
+    /// This trades durability and sharing for throughput. Between commits
+    /// the file lags behind the IDs handed out, so if the process dies
+    /// before a commit, a restart can reissue those IDs unless the clock has
+    /// moved past them. And the file is not locked while generating, so no
+    /// other process may use the same state file.
+    pub fn with_deferred_commit(self, ids: usize) -> Self {
+        self.map(|generator| generator.with_deferred_commit(ids))
+    }
+
+    /// Stamps IDs with the time from `clock` instead of the [`WallClock`],
+    /// e.g. to make the timestamps in tests predictable. The state file still
+    /// applies, so a clock behind its last timestamp is treated like one that
+    /// was set back.
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        self.map(|generator| generator.with_clock(clock))
+    }
+
+    /// Starts the clock sequence at `sequence` instead of 0, so that runs
+    /// from a fresh state file are reproducible. State loaded from the file
+    /// takes precedence. Only the low 14 bits are kept, see
+    /// [`MAX_SEQUENCE`].
+    pub fn with_initial_sequence(self, sequence: u16) -> Self {
+        self.map(|generator| generator.with_initial_sequence(sequence))
+    }
+
+    /// How far the clock may fall behind the last issued ID, e.g. by an NTP
+    /// correction, before that is reported as a jump back. Defaults to
+    /// 100ms.
+    ///
+    /// Either way IDs stay monotonic: the clock sequence is bumped, or the
+    /// timestamp moved ahead, until the clock catches up. A jump past the
+    /// tolerance is logged to stderr once and counted in
+    /// [`backward_jumps`](Self::backward_jumps); smaller ones are treated as
+    /// transient and pass silently.
+    pub fn with_max_backward_drift(self, drift: Duration) -> Self {
+        self.map(|generator| generator.with_max_backward_drift(drift))
+    }
+
+    /// Commits the IDs issued since the last commit to the state file. Only
+    /// needed with [`with_deferred_commit`](Self::with_deferred_commit);
+    /// otherwise every ID is committed as it is issued.
+    pub fn flush(&self) -> Result<()> {
+        self.lock()?.flush()
+    }
+
+    /// Whether the generator is running without stable storage.
+    pub fn is_in_memory(&self) -> bool {
+        self.lock_unchecked().is_in_memory()
+    }
+
+    /// How long this generator has spent in total waiting for other
+    /// processes to release the state file, a measure of contention. Time
+    /// spent waiting for other threads sharing the generator is not counted.
+    pub fn lock_wait(&self) -> Duration {
+        self.lock_unchecked().lock_wait()
+    }
+
+    /// How many times the clock has jumped back further than
+    /// [`with_max_backward_drift`](Self::with_max_backward_drift) allows.
+    pub fn backward_jumps(&self) -> u64 {
+        self.lock_unchecked().backward_jumps()
+    }
+
+    /// The state of the last issued ID, read without advancing it.
+    ///
+    /// With a state file this reads the file under a shared lock, so it also
+    /// reflects IDs issued by other processes.
+    pub fn peek_state(&self) -> Result<State> {
+        self.lock()?.peek_state()
+    }
+
+    /// The name-based (version 5) UUID for `name` in `namespace`, per
+    /// RFC 4122 section 4.3. It only depends on its inputs, not on any
+    /// generator state.
+    pub fn generate_v5(namespace: &UUID, name: &[u8]) -> UUID {
+        let mut input = namespace.to_bytes().to_vec();
+        input.extend_from_slice(name);
+        let digest = crate::sha1::sha1(&input);
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        bytes[6] = (bytes[6] & 0x0F) | 0x50;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        UUID::from_u128(u128::from_be_bytes(bytes))
+    }
+
+    /// A time-ordered (version 7) UUID per RFC 9562: the Unix time in
+    /// milliseconds in the top 48 bits, then a 12-bit counter, then 62
+    /// random bits.
+    ///
+    /// The counter orders IDs issued within the same millisecond, and once
+    /// it runs out the millisecond is moved ahead. A clock that goes back is
+    /// ignored until it passes the last millisecond used. IDs from one
+    /// generator therefore sort in the order they were issued.
+    /// Unlike [`generate`](Self::generate) this does not use the state file:
+    /// the random bits keep IDs from different nodes and runs apart.
+    pub fn generate_v7(&self) -> Result<UUID> {
+        self.lock()?.generate_v7()
+    }
+
+    /// Reserves the next `count` IDs under a single lock, so that the next
+    /// `count` calls to [`generate`](Self::generate) are answered without
+    /// touching the state file.
+    ///
+    /// Reserved IDs are committed as issued, so if they are never handed out
+    /// they are skipped rather than reissued. Other processes sharing the
+    /// file may issue later IDs before the reserved ones are used up, so IDs
+    /// from different processes no longer sort in the order they were handed
+    /// out.
+    pub fn warm_up(&self, count: usize) -> Result<()> {
+        self.lock()?.warm_up(count)
+    }
+
+    pub fn generate(&self) -> Result<UUID> {
+        self.lock()?.generate()
+    }
+
+    /// Generates `count` IDs while holding the state file lock once, instead
+    /// of locking, reading and writing the file for every ID.
+    ///
+    /// If generating fails partway, the lock is still released and no ID is
+    /// returned.
+    pub fn generate_batch(&self, count: usize) -> Result<Vec<UUID>> {
+        self.lock()?.generate_batch(count)
+    }
+
+    /// A lazy stream of IDs. Like [`generate_batch`](Self::generate_batch)
+    /// the state file stays locked for as long as the iterator lives, so
+    /// other processes sharing the file, and other threads sharing the
+    /// generator, wait until it is dropped; dropping it commits the state of
+    /// the last ID it yielded and releases the lock.
+    ///
+    /// Errors after the lock was taken cannot be returned from the iterator;
+    /// they are logged to stderr, and end the stream if generating fails.
+    pub fn iter(&self) -> Result<Ids<'_>> {
+        let mut generator = self.lock()?;
+        generator.flush()?;
+        generator.get_global_state_from_stable_storage()?;
+        Ok(Ids { generator })
+    }
+
+    /// Applies a builder method to the state. Nothing else can hold the lock
+    /// while the generator is owned, so poisoning is not a concern.
+    fn map(self, f: impl FnOnce(Generator) -> Generator) -> Self {
+        let generator = self
+            .inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        UUIDGenerator {
+            inner: Mutex::new(f(generator)),
+        }
+    }
+
+    /// Locks the state, failing if a thread panicked while holding it: the
+    /// state may then be halfway through issuing an ID.
+    fn lock(&self) -> Result<MutexGuard<'_, Generator>> {
+        self.inner
+            .lock()
+            .map_err(|_| crate::format_err!("UUID generator poisoned by a panic while generating"))
+    }
+
+    /// Locks the state for reads that are meaningful even if it is poisoned.
+    fn lock_unchecked(&self) -> MutexGuard<'_, Generator> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Time-ordered IDs for nodes that do not need them to stay unique across
+/// restarts.
+///
+/// Unlike [`UUIDGenerator`] there is no state file and no MAC address: the
+/// node id is random for every generator and the sequence only lives in
+/// memory. IDs from one generator sort in the order they were issued.
+#[derive(Debug)]
+pub struct EphemeralGenerator {
+    state: State,
+    node_id: u64,
+}
+
+impl Default for EphemeralGenerator {
+    fn default() -> Self {
+        EphemeralGenerator {
+            state: State::default(),
+            node_id: Generator::random_node_id(),
+        }
+    }
+}
+
+impl EphemeralGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn generate(&mut self) -> Result<UUID> {
+        self.state.advance(|| WallClock.now_100ns())?;
+        Ok(UUID::from_u128(self.state.pack(self.node_id)))
+    }
+}
+
+/// The iterator returned by [`UUIDGenerator::iter`].
+pub struct Ids<'a> {
+    generator: MutexGuard<'a, Generator>,
+}
+
+impl Iterator for Ids<'_> {
+    type Item = UUID;
+
+    fn next(&mut self) -> Option<UUID> {
+        match self.generator.advance_state() {
+            Ok(()) => Some(UUID::from_u128(self.generator.pack())),
+            Err(e) => {
+                eprintln!("error: cannot generate UUID: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Ids<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.generator.commit_state_and_release_lock() {
+            eprintln!("error: {e}");
+        }
+    }
+}
+
+/// Runs `op`, retrying it with exponential backoff while it fails with an
+/// error that may go away by itself (`EINTR`, `EAGAIN`).
+fn retry_transient<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = COMMIT_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(e)
+                if retries < COMMIT_RETRIES
+                    && matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) =>
+            {
+                retries += 1;
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A state file in memory. It counts the commits written to it, and
+    /// fails the first `failures` writes with `EAGAIN`. (`write_all` already
+    /// retries `EINTR` by itself.)
+    #[derive(Clone, Default)]
+    struct FakeFile(Arc<Mutex<FakeFileInner>>);
+
+    #[derive(Default)]
+    struct FakeFileInner {
+        contents: Cursor<Vec<u8>>,
+        commits: usize,
+        failures: usize,
+    }
+
+    impl FakeFile {
+        fn failing(failures: usize) -> Self {
+            let file = FakeFile::default();
+            file.0.lock().unwrap().failures = failures;
+            file
+        }
+
+        fn commits(&self) -> usize {
+            self.0.lock().unwrap().commits
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().contents.get_ref().clone()).unwrap()
+        }
+
+        /// A generator keeping its state in the file.
+        fn generator(&self) -> Generator {
+            Generator::with_storage(Storage::File(Box::new(self.clone())))
+        }
+    }
+
+    impl Read for FakeFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().contents.read(buf)
+        }
+    }
+
+    impl Write for FakeFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut inner = self.0.lock().unwrap();
+            if inner.failures > 0 {
+                inner.failures -= 1;
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            inner.contents.write(buf)
+        }
+
+        /// Ends every write of the state.
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().commits += 1;
+            Ok(())
+        }
+    }
+
+    impl Seek for FakeFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.lock().unwrap().contents.seek(pos)
+        }
+    }
+
+    impl StateFile for FakeFile {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            let len = usize::try_from(len).unwrap();
+            self.0.lock().unwrap().contents.get_mut().resize(len, 0);
+            Ok(())
+        }
+
+        fn lock(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn lock_shared(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn unlock(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A clock that reads `readings` in turn and then ticks on by one
+    /// interval per reading.
+    fn fake_clock(readings: Vec<u64>) -> impl Fn() -> Result<u64> {
+        let next = Cell::new(0);
+        let last = *readings.last().unwrap();
+        move || {
+            let index = next.get();
+            next.set(index + 1);
+            Ok(readings
+                .get(index)
+                .copied()
+                .unwrap_or_else(|| last + (index - readings.len()) as u64 + 1))
+        }
+    }
+
+    /// Packs the IDs for `count` advances of a fresh state on `clock`.
+    fn ids(count: usize, clock: impl Fn() -> Result<u64>) -> Vec<UUID> {
+        let mut state = State::default();
+        (0..count)
+            .map(|_| {
+                state.advance(&clock).unwrap();
+                UUID::from_u128(state.pack(0x0000_5e00_5301))
+            })
+            .collect()
+    }
+
+    fn assert_strictly_increasing(ids: &[UUID]) {
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{} then {}", pair[0], pair[1]);
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+    }
+
+    #[test]
+    fn ids_increase_on_a_ticking_clock() {
+        let ids = ids(1000, fake_clock((1000..2000).step_by(3).collect()));
+        assert_strictly_increasing(&ids);
+    }
+
+    #[test]
+    fn ids_increase_on_a_stuck_clock() {
+        // More IDs than the sequence has values, so the clock must tick.
+        let count = usize::from(SEQUENCE_MASK) + 100;
+        let ids = ids(count, fake_clock(vec![1000; count + 10]));
+        assert_strictly_increasing(&ids);
+    }
+
+    #[test]
+    fn ids_increase_on_a_clock_going_backwards() {
+        let readings = (0..usize::from(SEQUENCE_MASK) * 2)
+            .map(|i| 1_000_000 - i as u64 / 3)
+            .collect();
+        let ids = ids(usize::from(SEQUENCE_MASK) * 2, fake_clock(readings));
+        assert_strictly_increasing(&ids);
+    }
+
+    #[test]
+    fn a_clock_that_never_ticks_fails_once_the_sequence_is_used_up() {
+        let mut state = State::default();
+        let clock = || Ok(1000);
+        for _ in 0..=SEQUENCE_MASK {
+            state.advance(clock).unwrap();
+        }
+        assert_eq!(state.last_sequence_id, SEQUENCE_MASK);
+        let error = state.advance(clock).unwrap_err().to_string();
+        assert!(error.contains("clock stuck at 1000"), "{error}");
+        // Nothing was issued for the failed advance.
+        assert_eq!(
+            (state.last_timestamp, state.last_sequence_id),
+            (1000, SEQUENCE_MASK)
+        );
+    }
+
+    #[test]
+    fn a_backward_jump_past_the_tolerance_is_counted_once() {
+        let now = Arc::new(AtomicU64::new(GREGORIAN_OFFSET + 100_000_000));
+        let mut generator = FakeFile::default()
+            .generator()
+            .with_clock({
+                let now = Arc::clone(&now);
+                move || Ok(now.load(Ordering::SeqCst))
+            })
+            .with_max_backward_drift(Duration::from_millis(100));
+        let mut ids = vec![generator.generate().unwrap()];
+        let mut step_back = |by: Duration| {
+            now.fetch_sub((by.as_nanos() / 100) as u64, Ordering::SeqCst);
+            ids.extend((0..10).map(|_| generator.generate().unwrap()));
+            generator.backward_jumps()
+        };
+        // Within the tolerance, the jump is taken as transient.
+        assert_eq!(step_back(Duration::from_millis(50)), 0);
+        // A second back, as after an NTP correction, is reported, but only
+        // once while the clock stays behind.
+        assert_eq!(step_back(Duration::from_secs(1)), 1);
+        assert_eq!(step_back(Duration::ZERO), 1);
+        assert_strictly_increasing(&ids);
+    }
+
+    #[test]
+    fn v7_ids_increase_on_a_stuck_clock() {
+        let path = temp_path("v7");
+        let generator = UUIDGenerator::new(&path)
+            .unwrap()
+            .with_clock(|| Ok(GREGORIAN_OFFSET + 10_000));
+        let ids: Vec<_> = (0..usize::from(V7_COUNTER_MASK) + 100)
+            .map(|_| generator.generate_v7().unwrap())
+            .collect();
+        assert_strictly_increasing(&ids);
+        drop(generator);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn commits_are_retried_on_transient_errors() {
+        let file = FakeFile::failing(COMMIT_RETRIES as usize);
+        let mut generator = file.generator().with_clock(|| Ok(1000));
+        let id = generator.generate().unwrap();
+        assert_eq!(file.contents(), format!("v{STATE_VERSION}\n1000,0"));
+        assert_eq!(id.timestamp(), 1000);
+    }
+
+    #[test]
+    fn commits_fail_once_the_retries_run_out() {
+        let file = FakeFile::failing(COMMIT_RETRIES as usize + 1);
+        let mut generator = file.generator().with_clock(|| Ok(1000));
+        assert!(generator.generate().is_err());
+        assert_eq!(file.commits(), 0);
+    }
+
+    #[test]
+    fn warmed_up_generates_do_not_touch_the_file() {
+        let file = FakeFile::default();
+        let mut generator = file.generator();
+        generator.warm_up(64).unwrap();
+        assert_eq!(file.commits(), 1, "the batch is one commit");
+        let ids: Vec<_> = (0..64).map(|_| generator.generate().unwrap()).collect();
+        assert_eq!(file.commits(), 1, "reserved IDs were committed");
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        generator.generate().unwrap();
+        assert_eq!(file.commits(), 2, "the reservation is used up");
+    }
+
+    /// `select_mac` over the interfaces and addresses in `listing`.
+    #[cfg(not(feature = "no-mac"))]
+    fn select_mac(listing: &[(&str, &str)]) -> Option<u64> {
+        let ifaces = listing.iter().map(|(iface, _)| iface.to_string()).collect();
+        Generator::select_mac(ifaces, |iface| {
+            listing
+                .iter()
+                .find(|(name, _)| *name == iface)
+                .map(|(_, address)| format!("{address}\n"))
+        })
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-mac"))]
+    fn the_first_real_interface_by_name_gives_the_mac() {
+        let listing = [
+            ("wlan0", "02:00:00:00:00:03"),
+            ("lo", "00:00:00:00:00:00"),
+            ("eth1", "02:00:00:00:00:02"),
+            ("eth0", "02:00:00:00:00:01"),
+        ];
+        assert_eq!(select_mac(&listing), Some(0x0200_0000_0001));
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-mac"))]
+    fn zero_and_malformed_addresses_are_skipped() {
+        let listing = [
+            ("dummy0", "00:00:00:00:00:00"),
+            ("eth0", "not a mac"),
+            ("eth1", "02:00:00:00:00:02"),
+        ];
+        assert_eq!(select_mac(&listing), Some(0x0200_0000_0002));
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-mac"))]
+    fn only_a_loopback_gives_no_mac() {
+        assert_eq!(select_mac(&[("lo", "00:00:00:00:00:00")]), None);
+        assert_eq!(select_mac(&[]), None);
+    }
+
+    #[test]
+    fn urns_round_trip() {
+        let uuid = UUID::NAMESPACE_URL;
+        let urn = uuid.to_urn();
+        assert_eq!(urn, "urn:uuid:6ba7b811-9dad-11d1-80b4-00c04fd430c8");
+        assert_eq!(urn.parse::<UUID>(), Ok(uuid.clone()));
+        assert_eq!(uuid.to_hyphenated().parse::<UUID>(), Ok(uuid.clone()));
+        assert_eq!(uuid.as_u128().to_string().parse::<UUID>(), Ok(uuid));
+        for bad in [
+            "urn:uuid:",
+            "urn:uuid:6ba7b811",
+            "urn:uuid:123",
+            "uuid:6ba7b811-9dad-11d1-80b4-00c04fd430c8",
+        ] {
+            assert!(bad.parse::<UUID>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parse_takes_either_form_in_either_case() {
+        let uuid = UUID::NAMESPACE_URL;
+        for form in [
+            "6ba7b811-9dad-11d1-80b4-00c04fd430c8",
+            "6BA7B811-9DAD-11D1-80B4-00C04FD430C8",
+            "6ba7b8119dad11d180b400c04fd430c8",
+            "6Ba7B8119dAd11D180b400C04fD430C8",
+        ] {
+            assert_eq!(UUID::parse(form), Ok(uuid.clone()), "{form}");
+        }
+        // A random (version 4) ID from elsewhere is accepted too.
+        let v4 = UUID::parse("f81d4fae-7dec-41d0-a765-00a0c91e6bf6").unwrap();
+        assert_eq!(v4.to_string(), "f81d4fae-7dec-41d0-a765-00a0c91e6bf6");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_ids() {
+        let cases = [
+            ("", ParseUuidError::InvalidLength { len: 0 }),
+            (
+                "6ba7b811-9dad-11d1-80b4-00c04fd430c",
+                ParseUuidError::InvalidLength { len: 35 },
+            ),
+            (
+                "6ba7b811-9dad-11d1-80b4-00c04fd430c8a",
+                ParseUuidError::InvalidLength { len: 37 },
+            ),
+            (
+                "6ba7b811-9dad-11d1-80b4-00c04fd430cg",
+                ParseUuidError::InvalidCharacter {
+                    character: 'g',
+                    index: 35,
+                },
+            ),
+            (
+                "6ba7b81-19dad-11d1-80b4-00c04fd430c8",
+                ParseUuidError::InvalidCharacter {
+                    character: '-',
+                    index: 7,
+                },
+            ),
+            (
+                "6ba7b811-9dad-11d1-80b400c04fd430c8-",
+                ParseUuidError::InvalidCharacter {
+                    character: '0',
+                    index: 23,
+                },
+            ),
+            (
+                "6ba7b811-9dad-01d1-80b4-00c04fd430c8",
+                ParseUuidError::InvalidVersion { version: 0 },
+            ),
+            (
+                "6ba7b811-9dad-11d1-c0b4-00c04fd430c8",
+                ParseUuidError::InvalidVariant,
+            ),
+        ];
+        for (input, error) in cases {
+            assert_eq!(UUID::parse(input), Err(error), "{input}");
+        }
+    }
+
+    #[test]
+    fn v5_matches_the_rfc_example() {
+        // RFC 9562 appendix A.4.
+        let id = UUIDGenerator::generate_v5(&UUID::NAMESPACE_DNS, b"www.example.com");
+        assert_eq!(id.to_string(), "2ed6657d-e927-568b-95e1-2665a8aea6a2");
+        assert_eq!(UUID::parse(&id.to_string()), Ok(id));
+    }
+
+    /// A path for a state file in the temp directory, unique to the test.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("uuid-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn an_unwritable_path_still_gives_unique_ids() {
+        let path = temp_path("missing").join("state.db");
+        let generator = UUIDGenerator::new(&path).unwrap();
+        assert!(generator.is_in_memory());
+        let ids: HashSet<_> = (0..1000).map(|_| generator.generate().unwrap()).collect();
+        assert_eq!(ids.len(), 1000);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_headerless_file_migrates_to_the_current_version() {
+        let path = temp_path("v1");
+        std::fs::write(&path, "1234,5").unwrap();
+        let generator = UUIDGenerator::new(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("v{STATE_VERSION}\n1234,5")
+        );
+        let state = generator.peek_state().unwrap();
+        assert_eq!(
+            state,
+            State {
+                last_timestamp: 1234,
+                last_sequence_id: 5
+            }
+        );
+        drop(generator);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn a_file_from_a_newer_build_is_rejected() {
+        let path = temp_path("future");
+        let contents = format!("v{}\n1234,5", STATE_VERSION + 1);
+        std::fs::write(&path, &contents).unwrap();
+        assert!(UUIDGenerator::new(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    #[cfg(feature = "no-mac")]
+    fn without_mac_access_node_ids_are_random() {
+        // `read_mac`, the only code that lists /sys/class/net, is not even
+        // compiled in; so the node ids are random, with the multicast bit.
+        let mut generators = [(); 2].map(|()| Generator::with_storage(Storage::Memory));
+        let [first, second] = generators.each_ref().map(|generator| generator.node_id);
+        assert_ne!(first, second);
+        assert!(first & MULTICAST_BIT != 0 && second & MULTICAST_BIT != 0);
+        let ids: HashSet<_> = generators
+            .iter_mut()
+            .flat_map(|generator| generator.generate_batch(500).unwrap())
+            .collect();
+        assert_eq!(ids.len(), 1000);
+    }
+}
@@ -0,0 +1,54 @@
+//! Runs a node binary the way Maelstrom does, over stdin and stdout.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use gossip_glomers_rs::json::{self, Value};
+use gossip_glomers_rs::test_support::init_msg;
+
+/// Feeds `lines` to the echo binary, closes its stdin and returns the
+/// messages it wrote.
+fn run_echo(lines: &[&str]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("cannot start the echo binary");
+    let mut stdin = child.stdin.take().unwrap();
+    for line in lines {
+        writeln!(stdin, "{line}").unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output.status);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| json::parse(line).unwrap())
+        .collect()
+}
+
+fn body(message: &Value) -> &Value {
+    message.get("body").unwrap()
+}
+
+#[test]
+fn init_is_answered_with_init_ok() {
+    let init = init_msg("n1", &["n1", "n2"]);
+    let echo = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#;
+    let output = run_echo(&[&init, echo]);
+    assert_eq!(output.len(), 2, "{output:?}");
+
+    let init_ok = &output[0];
+    assert_eq!(init_ok.get("src").and_then(Value::as_str), Some("n1"));
+    assert_eq!(init_ok.get("dest").and_then(Value::as_str), Some("c0"));
+    let init_ok = body(init_ok);
+    assert_eq!(init_ok.get("type").and_then(Value::as_str), Some("init_ok"));
+    assert_eq!(init_ok.get("in_reply_to").and_then(Value::as_u64), Some(1));
+
+    let echo_ok = body(&output[1]);
+    assert_eq!(echo_ok.get("type").and_then(Value::as_str), Some("echo_ok"));
+    assert_eq!(echo_ok.get("in_reply_to").and_then(Value::as_u64), Some(2));
+    assert_eq!(echo_ok.get("echo").and_then(Value::as_str), Some("hi"));
+}